@@ -0,0 +1,15 @@
+//! Hardware-independent heater logic, kept apart from the firmware so that it can be tested on
+//! the host:
+//!
+//! ```sh
+//! cargo +stable test -p heater-core --target host-tuple
+//! ```
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod pattern;
+pub mod select;
+pub mod ssr;
+pub mod state;
+pub mod time;
@@ -0,0 +1,169 @@
+//! Firing patterns for the SSR: which of a cycle's steps are on, for a given duty.
+//!
+//! Pure functions, with no dependency on the SSR task, so that the firing algorithm can be
+//! reasoned about and tested on its own.
+use crate::ssr::Duty;
+use alloc::string::String;
+
+/// The number of steps in a pattern. A duty of one percent turns on one step.
+pub const TOTAL_STEPS: usize = 100;
+
+/// On/off steps of equal duration, repeated to fire the SSR.
+pub type Pattern = [bool; TOTAL_STEPS];
+
+/// The duty a pattern actually fires at, as the share of its steps that are on.
+///
+/// This is the duty in effect, after the duty curve, derating, and any lock or fault.
+pub fn pattern_duty(pattern: &Pattern) -> Duty {
+    Duty::saturating(pattern.iter().filter(|&&on| on).count() as u8)
+}
+
+/// Renders a pattern as a line of `o` (on) and `·` (off) steps.
+pub fn format_pattern(pattern: &Pattern) -> String {
+    pattern
+        .iter()
+        .map(|&on| if on { 'o' } else { '·' })
+        .collect()
+}
+
+/// Turns a duty cycle percentage into a pattern of on/off steps of equal duration.
+///
+/// These steps are evenly distributed, maximizing the number of transitions.
+///
+/// Example output:
+///   0%: ····································································································
+///   1%: ·················································o··················································
+///   2%: ························o·················································o·························
+///   3%: ················o································o·································o················
+///   4%: ············o························o························o························o············
+///   5%: ·········o···················o···················o···················o···················o··········
+///   ..
+///  50%: o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·
+///   ..
+///  96%: oooooooooooo·oooooooooooooooooooooooo·oooooooooooooooooooooooo·oooooooooooooooooooooooo·oooooooooooo
+///  97%: oooooooooooooooo·ooooooooooooooooooooooooooooooooo·oooooooooooooooooooooooooooooooo·oooooooooooooooo
+///  98%: ooooooooooooooooooooooooo·ooooooooooooooooooooooooooooooooooooooooooooooooo·oooooooooooooooooooooooo
+///  99%: oooooooooooooooooooooooooooooooooooooooooooooooooo·ooooooooooooooooooooooooooooooooooooooooooooooooo
+/// 100%: oooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooo
+pub fn generate_evenly_distributed_steps(duty: Duty) -> Pattern {
+    const TOTAL_STEPS_I32: i32 = TOTAL_STEPS as i32;
+
+    // The target number of ON steps.
+    let num_on_steps_target = duty.percent() as i32;
+
+    // Initialize the output array with all steps OFF (false).
+    let mut steps_array: Pattern = [false; TOTAL_STEPS];
+
+    // Initialize the accumulator.
+    // Starting at `TOTAL_STEPS/2` centers the distribution of ON pulses.
+    let mut accumulator: i32 = TOTAL_STEPS_I32 / 2;
+
+    // Loop through each of the 100 steps to decide if it's ON or OFF.
+    for step in steps_array.iter_mut() {
+        // Add the "target density" of ON states to the accumulator.
+        accumulator += num_on_steps_target;
+
+        // Check if the accumulator has reached the threshold.
+        if accumulator >= TOTAL_STEPS_I32 {
+            *step = true; // This step is ON.
+            // "Spend" the credit for one ON pulse by subtracting TOTAL_STEPS
+            // from the accumulator.
+            accumulator -= TOTAL_STEPS_I32;
+        }
+        // Else, the step remains OFF (false), which is its initialized state.
+    }
+
+    steps_array
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn duties() -> impl Iterator<Item = Duty> {
+        (0..=100).map(|percent| Duty::new(percent).unwrap())
+    }
+
+    // The number of on steps in each prefix of the pattern.
+    fn running_counts(pattern: &Pattern) -> [usize; TOTAL_STEPS] {
+        let mut counts = [0; TOTAL_STEPS];
+        let mut count = 0;
+        for (step, &on) in pattern.iter().enumerate() {
+            count += on as usize;
+            counts[step] = count;
+        }
+        counts
+    }
+
+    #[test]
+    fn on_steps_match_the_duty() {
+        for duty in duties() {
+            let pattern = generate_evenly_distributed_steps(duty);
+            let on_steps = pattern.iter().filter(|&&on| on).count();
+            assert_eq!(on_steps, duty.percent() as usize, "duty {duty}");
+            assert_eq!(pattern_duty(&pattern), duty);
+        }
+    }
+
+    #[test]
+    fn zero_and_full_duty() {
+        assert_eq!(
+            generate_evenly_distributed_steps(Duty::OFF),
+            [false; TOTAL_STEPS]
+        );
+        assert_eq!(
+            generate_evenly_distributed_steps(Duty::FULL),
+            [true; TOTAL_STEPS]
+        );
+    }
+
+    #[test]
+    fn on_steps_are_evenly_spaced() {
+        for duty in duties().filter(|duty| *duty != Duty::OFF) {
+            let pattern = generate_evenly_distributed_steps(duty);
+            let on_steps: Vec<usize> = (0..TOTAL_STEPS).filter(|&step| pattern[step]).collect();
+
+            // Gaps between on steps, wrapping around as the pattern repeats.
+            let gaps: Vec<usize> = on_steps
+                .iter()
+                .zip(on_steps.iter().cycle().skip(1))
+                .map(|(&from, &to)| (to + TOTAL_STEPS - from - 1) % TOTAL_STEPS + 1)
+                .collect();
+            let (shortest, longest) = (gaps.iter().min().unwrap(), gaps.iter().max().unwrap());
+            assert!(longest - shortest <= 1, "duty {duty}: gaps {gaps:?}");
+        }
+    }
+
+    #[test]
+    fn every_prefix_tracks_the_duty() {
+        for duty in duties() {
+            let counts = running_counts(&generate_evenly_distributed_steps(duty));
+            for (step, &count) in counts.iter().enumerate() {
+                let ideal = (step + 1) as f32 * duty.percent() as f32 / TOTAL_STEPS as f32;
+                assert!(
+                    (count as f32 - ideal).abs() <= 0.5,
+                    "duty {duty}: {count} on steps by step {step}, ideally {ideal}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn adjacent_duties_differ_by_at_most_one_step_so_far() {
+        for percent in 0..100 {
+            let lower = running_counts(&generate_evenly_distributed_steps(Duty::saturating(
+                percent,
+            )));
+            let higher = running_counts(&generate_evenly_distributed_steps(Duty::saturating(
+                percent + 1,
+            )));
+            for step in 0..TOTAL_STEPS {
+                let extra = higher[step] - lower[step];
+                assert!(
+                    extra <= 1,
+                    "duty {percent}: {extra} extra on steps by step {step}"
+                );
+            }
+        }
+    }
+}
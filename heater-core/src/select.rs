@@ -0,0 +1,869 @@
+#![allow(clippy::too_many_arguments)]
+
+//! Wait for the first of several futures to complete.
+//!
+//! Once one future completes, the others are dropped. A future created anew for each select must
+//! be cancel-safe: dropping it before it completes must not lose anything.
+//!
+//! A future that keeps state across selects, such as a timer, is created once and passed by
+//! reference: as `&mut fut` if it is `Unpin`, or pinned with `core::pin::pin!` and passed as
+//! `fut.as_mut()` otherwise. `&mut F` and `Pin<&mut F>` are futures themselves, so dropping them
+//! leaves the held future untouched, and it resumes where it was on the next select.
+//!
+//! Futures are polled in order, so the earlier ones win when several are ready.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+// ====================================================================
+
+/// Result for [`select5`].
+#[derive(Debug, Clone)]
+pub enum Either5<A, B, C, D, E> {
+    /// First future finished first.
+    First(A),
+    /// Second future finished first.
+    Second(B),
+    /// Third future finished first.
+    Third(C),
+    /// Fourth future finished first.
+    Fourth(D),
+    /// Fifth future finished first.
+    Fifth(E),
+}
+
+/// Same as [`select`], but with more futures.
+pub fn select5<A, B, C, D, E>(a: A, b: B, c: C, d: D, e: E) -> Select5<A, B, C, D, E>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+{
+    Select5 { a, b, c, d, e }
+}
+
+/// Future for the [`select5`] function.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Select5<A, B, C, D, E> {
+    a: A,
+    b: B,
+    c: C,
+    d: D,
+    e: E,
+}
+
+impl<A, B, C, D, E> Future for Select5<A, B, C, D, E>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+{
+    type Output = Either5<A::Output, B::Output, C::Output, D::Output, E::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let b = unsafe { Pin::new_unchecked(&mut this.b) };
+        let c = unsafe { Pin::new_unchecked(&mut this.c) };
+        let d = unsafe { Pin::new_unchecked(&mut this.d) };
+        let e = unsafe { Pin::new_unchecked(&mut this.e) };
+        if let Poll::Ready(x) = a.poll(cx) {
+            return Poll::Ready(Either5::First(x));
+        }
+        if let Poll::Ready(x) = b.poll(cx) {
+            return Poll::Ready(Either5::Second(x));
+        }
+        if let Poll::Ready(x) = c.poll(cx) {
+            return Poll::Ready(Either5::Third(x));
+        }
+        if let Poll::Ready(x) = d.poll(cx) {
+            return Poll::Ready(Either5::Fourth(x));
+        }
+        if let Poll::Ready(x) = e.poll(cx) {
+            return Poll::Ready(Either5::Fifth(x));
+        }
+        Poll::Pending
+    }
+}
+
+// ====================================================================
+
+/// Result for [`select6`].
+#[derive(Debug, Clone)]
+
+pub enum Either6<A, B, C, D, E, F> {
+    /// First future finished first.
+    First(A),
+    /// Second future finished first.
+    Second(B),
+    /// Third future finished first.
+    Third(C),
+    /// Fourth future finished first.
+    Fourth(D),
+    /// Fifth future finished first.
+    Fifth(E),
+    /// Sixth future finished first.
+    Sixth(F),
+}
+
+/// Same as [`select`], but with more futures.
+pub fn select6<A, B, C, D, E, F>(a: A, b: B, c: C, d: D, e: E, f: F) -> Select6<A, B, C, D, E, F>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+    F: Future,
+{
+    Select6 { a, b, c, d, e, f }
+}
+
+/// Future for the [`select6`] function.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Select6<A, B, C, D, E, F> {
+    a: A,
+    b: B,
+    c: C,
+    d: D,
+    e: E,
+    f: F,
+}
+
+impl<A, B, C, D, E, F> Future for Select6<A, B, C, D, E, F>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+    F: Future,
+{
+    type Output = Either6<A::Output, B::Output, C::Output, D::Output, E::Output, F::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let b = unsafe { Pin::new_unchecked(&mut this.b) };
+        let c = unsafe { Pin::new_unchecked(&mut this.c) };
+        let d = unsafe { Pin::new_unchecked(&mut this.d) };
+        let e = unsafe { Pin::new_unchecked(&mut this.e) };
+        let f = unsafe { Pin::new_unchecked(&mut this.f) };
+        if let Poll::Ready(x) = a.poll(cx) {
+            return Poll::Ready(Either6::First(x));
+        }
+        if let Poll::Ready(x) = b.poll(cx) {
+            return Poll::Ready(Either6::Second(x));
+        }
+        if let Poll::Ready(x) = c.poll(cx) {
+            return Poll::Ready(Either6::Third(x));
+        }
+        if let Poll::Ready(x) = d.poll(cx) {
+            return Poll::Ready(Either6::Fourth(x));
+        }
+        if let Poll::Ready(x) = e.poll(cx) {
+            return Poll::Ready(Either6::Fifth(x));
+        }
+        if let Poll::Ready(x) = f.poll(cx) {
+            return Poll::Ready(Either6::Sixth(x));
+        }
+        Poll::Pending
+    }
+}
+
+// ====================================================================
+
+/// Result for [`select7`].
+#[derive(Debug, Clone)]
+
+pub enum Either7<A, B, C, D, E, F, G> {
+    /// First future finished first.
+    First(A),
+    /// Second future finished first.
+    Second(B),
+    /// Third future finished first.
+    Third(C),
+    /// Fourth future finished first.
+    Fourth(D),
+    /// Fifth future finished first.
+    Fifth(E),
+    /// Sixth future finished first.
+    Sixth(F),
+    /// Seventh future finished first.
+    Seventh(G),
+}
+
+/// Same as [`select`], but with more futures.
+pub fn select7<A, B, C, D, E, F, G>(
+    a: A,
+    b: B,
+    c: C,
+    d: D,
+    e: E,
+    f: F,
+    g: G,
+) -> Select7<A, B, C, D, E, F, G>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+    F: Future,
+    G: Future,
+{
+    Select7 {
+        a,
+        b,
+        c,
+        d,
+        e,
+        f,
+        g,
+    }
+}
+
+/// Future for the [`select7`] function.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Select7<A, B, C, D, E, F, G> {
+    a: A,
+    b: B,
+    c: C,
+    d: D,
+    e: E,
+    f: F,
+    g: G,
+}
+
+impl<A, B, C, D, E, F, G> Future for Select7<A, B, C, D, E, F, G>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+    F: Future,
+    G: Future,
+{
+    type Output =
+        Either7<A::Output, B::Output, C::Output, D::Output, E::Output, F::Output, G::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let b = unsafe { Pin::new_unchecked(&mut this.b) };
+        let c = unsafe { Pin::new_unchecked(&mut this.c) };
+        let d = unsafe { Pin::new_unchecked(&mut this.d) };
+        let e = unsafe { Pin::new_unchecked(&mut this.e) };
+        let f = unsafe { Pin::new_unchecked(&mut this.f) };
+        let g = unsafe { Pin::new_unchecked(&mut this.g) };
+        if let Poll::Ready(x) = a.poll(cx) {
+            return Poll::Ready(Either7::First(x));
+        }
+        if let Poll::Ready(x) = b.poll(cx) {
+            return Poll::Ready(Either7::Second(x));
+        }
+        if let Poll::Ready(x) = c.poll(cx) {
+            return Poll::Ready(Either7::Third(x));
+        }
+        if let Poll::Ready(x) = d.poll(cx) {
+            return Poll::Ready(Either7::Fourth(x));
+        }
+        if let Poll::Ready(x) = e.poll(cx) {
+            return Poll::Ready(Either7::Fifth(x));
+        }
+        if let Poll::Ready(x) = f.poll(cx) {
+            return Poll::Ready(Either7::Sixth(x));
+        }
+        if let Poll::Ready(x) = g.poll(cx) {
+            return Poll::Ready(Either7::Seventh(x));
+        }
+        Poll::Pending
+    }
+}
+
+// ====================================================================
+
+/// Result for [`select8`].
+#[derive(Debug, Clone)]
+pub enum Either8<A, B, C, D, E, F, G, H> {
+    /// First future finished first.
+    First(A),
+    /// Second future finished first.
+    Second(B),
+    /// Third future finished first.
+    Third(C),
+    /// Fourth future finished first.
+    Fourth(D),
+    /// Fifth future finished first.
+    Fifth(E),
+    /// Sixth future finished first.
+    Sixth(F),
+    /// Seventh future finished first.
+    Seventh(G),
+    /// Eighth future finished first.
+    Eighth(H),
+}
+
+/// Same as [`select`], but with more futures.
+pub fn select8<A, B, C, D, E, F, G, H>(
+    a: A,
+    b: B,
+    c: C,
+    d: D,
+    e: E,
+    f: F,
+    g: G,
+    h: H,
+) -> Select8<A, B, C, D, E, F, G, H>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+    F: Future,
+    G: Future,
+    H: Future,
+{
+    Select8 {
+        a,
+        b,
+        c,
+        d,
+        e,
+        f,
+        g,
+        h,
+    }
+}
+
+/// Future for the [`select8`] function.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Select8<A, B, C, D, E, F, G, H> {
+    a: A,
+    b: B,
+    c: C,
+    d: D,
+    e: E,
+    f: F,
+    g: G,
+    h: H,
+}
+
+impl<A, B, C, D, E, F, G, H> Future for Select8<A, B, C, D, E, F, G, H>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+    F: Future,
+    G: Future,
+    H: Future,
+{
+    type Output = Either8<
+        A::Output,
+        B::Output,
+        C::Output,
+        D::Output,
+        E::Output,
+        F::Output,
+        G::Output,
+        H::Output,
+    >;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let b = unsafe { Pin::new_unchecked(&mut this.b) };
+        let c = unsafe { Pin::new_unchecked(&mut this.c) };
+        let d = unsafe { Pin::new_unchecked(&mut this.d) };
+        let e = unsafe { Pin::new_unchecked(&mut this.e) };
+        let f = unsafe { Pin::new_unchecked(&mut this.f) };
+        let g = unsafe { Pin::new_unchecked(&mut this.g) };
+        let h = unsafe { Pin::new_unchecked(&mut this.h) };
+        if let Poll::Ready(x) = a.poll(cx) {
+            return Poll::Ready(Either8::First(x));
+        }
+        if let Poll::Ready(x) = b.poll(cx) {
+            return Poll::Ready(Either8::Second(x));
+        }
+        if let Poll::Ready(x) = c.poll(cx) {
+            return Poll::Ready(Either8::Third(x));
+        }
+        if let Poll::Ready(x) = d.poll(cx) {
+            return Poll::Ready(Either8::Fourth(x));
+        }
+        if let Poll::Ready(x) = e.poll(cx) {
+            return Poll::Ready(Either8::Fifth(x));
+        }
+        if let Poll::Ready(x) = f.poll(cx) {
+            return Poll::Ready(Either8::Sixth(x));
+        }
+        if let Poll::Ready(x) = g.poll(cx) {
+            return Poll::Ready(Either8::Seventh(x));
+        }
+        if let Poll::Ready(x) = h.poll(cx) {
+            return Poll::Ready(Either8::Eighth(x));
+        }
+        Poll::Pending
+    }
+}
+
+// ====================================================================
+
+/// Result for [`select9`].
+#[derive(Debug, Clone)]
+pub enum Either9<A, B, C, D, E, F, G, H, I> {
+    /// First future finished first.
+    First(A),
+    /// Second future finished first.
+    Second(B),
+    /// Third future finished first.
+    Third(C),
+    /// Fourth future finished first.
+    Fourth(D),
+    /// Fifth future finished first.
+    Fifth(E),
+    /// Sixth future finished first.
+    Sixth(F),
+    /// Seventh future finished first.
+    Seventh(G),
+    /// Eighth future finished first.
+    Eighth(H),
+    /// Ninth future finished first.
+    Ninth(I),
+}
+
+/// Same as [`select`], but with more futures.
+pub fn select9<A, B, C, D, E, F, G, H, I>(
+    a: A,
+    b: B,
+    c: C,
+    d: D,
+    e: E,
+    f: F,
+    g: G,
+    h: H,
+    i: I,
+) -> Select9<A, B, C, D, E, F, G, H, I>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+    F: Future,
+    G: Future,
+    H: Future,
+    I: Future,
+{
+    Select9 {
+        a,
+        b,
+        c,
+        d,
+        e,
+        f,
+        g,
+        h,
+        i,
+    }
+}
+
+/// Future for the [`select9`] function.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Select9<A, B, C, D, E, F, G, H, I> {
+    a: A,
+    b: B,
+    c: C,
+    d: D,
+    e: E,
+    f: F,
+    g: G,
+    h: H,
+    i: I,
+}
+
+impl<A, B, C, D, E, F, G, H, I> Future for Select9<A, B, C, D, E, F, G, H, I>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+    F: Future,
+    G: Future,
+    H: Future,
+    I: Future,
+{
+    type Output = Either9<
+        A::Output,
+        B::Output,
+        C::Output,
+        D::Output,
+        E::Output,
+        F::Output,
+        G::Output,
+        H::Output,
+        I::Output,
+    >;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let b = unsafe { Pin::new_unchecked(&mut this.b) };
+        let c = unsafe { Pin::new_unchecked(&mut this.c) };
+        let d = unsafe { Pin::new_unchecked(&mut this.d) };
+        let e = unsafe { Pin::new_unchecked(&mut this.e) };
+        let f = unsafe { Pin::new_unchecked(&mut this.f) };
+        let g = unsafe { Pin::new_unchecked(&mut this.g) };
+        let h = unsafe { Pin::new_unchecked(&mut this.h) };
+        let i = unsafe { Pin::new_unchecked(&mut this.i) };
+        if let Poll::Ready(x) = a.poll(cx) {
+            return Poll::Ready(Either9::First(x));
+        }
+        if let Poll::Ready(x) = b.poll(cx) {
+            return Poll::Ready(Either9::Second(x));
+        }
+        if let Poll::Ready(x) = c.poll(cx) {
+            return Poll::Ready(Either9::Third(x));
+        }
+        if let Poll::Ready(x) = d.poll(cx) {
+            return Poll::Ready(Either9::Fourth(x));
+        }
+        if let Poll::Ready(x) = e.poll(cx) {
+            return Poll::Ready(Either9::Fifth(x));
+        }
+        if let Poll::Ready(x) = f.poll(cx) {
+            return Poll::Ready(Either9::Sixth(x));
+        }
+        if let Poll::Ready(x) = g.poll(cx) {
+            return Poll::Ready(Either9::Seventh(x));
+        }
+        if let Poll::Ready(x) = h.poll(cx) {
+            return Poll::Ready(Either9::Eighth(x));
+        }
+        if let Poll::Ready(x) = i.poll(cx) {
+            return Poll::Ready(Either9::Ninth(x));
+        }
+        Poll::Pending
+    }
+}
+
+// ====================================================================
+
+/// Result for [`select10`].
+#[derive(Debug, Clone)]
+pub enum Either10<A, B, C, D, E, F, G, H, I, J> {
+    /// First future finished first.
+    First(A),
+    /// Second future finished first.
+    Second(B),
+    /// Third future finished first.
+    Third(C),
+    /// Fourth future finished first.
+    Fourth(D),
+    /// Fifth future finished first.
+    Fifth(E),
+    /// Sixth future finished first.
+    Sixth(F),
+    /// Seventh future finished first.
+    Seventh(G),
+    /// Eighth future finished first.
+    Eighth(H),
+    /// Ninth future finished first.
+    Ninth(I),
+    /// Tenth future finished first.
+    Tenth(J),
+}
+
+/// Same as [`select`], but with more futures.
+pub fn select10<A, B, C, D, E, F, G, H, I, J>(
+    a: A,
+    b: B,
+    c: C,
+    d: D,
+    e: E,
+    f: F,
+    g: G,
+    h: H,
+    i: I,
+    j: J,
+) -> Select10<A, B, C, D, E, F, G, H, I, J>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+    F: Future,
+    G: Future,
+    H: Future,
+    I: Future,
+    J: Future,
+{
+    Select10 {
+        a,
+        b,
+        c,
+        d,
+        e,
+        f,
+        g,
+        h,
+        i,
+        j,
+    }
+}
+
+/// Future for the [`select10`] function.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Select10<A, B, C, D, E, F, G, H, I, J> {
+    a: A,
+    b: B,
+    c: C,
+    d: D,
+    e: E,
+    f: F,
+    g: G,
+    h: H,
+    i: I,
+    j: J,
+}
+
+impl<A, B, C, D, E, F, G, H, I, J> Future for Select10<A, B, C, D, E, F, G, H, I, J>
+where
+    A: Future,
+    B: Future,
+    C: Future,
+    D: Future,
+    E: Future,
+    F: Future,
+    G: Future,
+    H: Future,
+    I: Future,
+    J: Future,
+{
+    type Output = Either10<
+        A::Output,
+        B::Output,
+        C::Output,
+        D::Output,
+        E::Output,
+        F::Output,
+        G::Output,
+        H::Output,
+        I::Output,
+        J::Output,
+    >;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+        let a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let b = unsafe { Pin::new_unchecked(&mut this.b) };
+        let c = unsafe { Pin::new_unchecked(&mut this.c) };
+        let d = unsafe { Pin::new_unchecked(&mut this.d) };
+        let e = unsafe { Pin::new_unchecked(&mut this.e) };
+        let f = unsafe { Pin::new_unchecked(&mut this.f) };
+        let g = unsafe { Pin::new_unchecked(&mut this.g) };
+        let h = unsafe { Pin::new_unchecked(&mut this.h) };
+        let i = unsafe { Pin::new_unchecked(&mut this.i) };
+        let j = unsafe { Pin::new_unchecked(&mut this.j) };
+        if let Poll::Ready(x) = a.poll(cx) {
+            return Poll::Ready(Either10::First(x));
+        }
+        if let Poll::Ready(x) = b.poll(cx) {
+            return Poll::Ready(Either10::Second(x));
+        }
+        if let Poll::Ready(x) = c.poll(cx) {
+            return Poll::Ready(Either10::Third(x));
+        }
+        if let Poll::Ready(x) = d.poll(cx) {
+            return Poll::Ready(Either10::Fourth(x));
+        }
+        if let Poll::Ready(x) = e.poll(cx) {
+            return Poll::Ready(Either10::Fifth(x));
+        }
+        if let Poll::Ready(x) = f.poll(cx) {
+            return Poll::Ready(Either10::Sixth(x));
+        }
+        if let Poll::Ready(x) = g.poll(cx) {
+            return Poll::Ready(Either10::Seventh(x));
+        }
+        if let Poll::Ready(x) = h.poll(cx) {
+            return Poll::Ready(Either10::Eighth(x));
+        }
+        if let Poll::Ready(x) = i.poll(cx) {
+            return Poll::Ready(Either10::Ninth(x));
+        }
+        if let Poll::Ready(x) = j.poll(cx) {
+            return Poll::Ready(Either10::Tenth(x));
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{
+        cell::Cell,
+        future::{pending, ready},
+        pin::pin,
+        task::Waker,
+    };
+
+    fn poll_once<F: Future>(fut: F) -> Poll<F::Output> {
+        let fut = pin!(fut);
+        fut.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    // Completes on its `polls`-th poll, and records that it was dropped.
+    struct CountdownFuture<'a> {
+        polls: u32,
+        dropped: &'a Cell<bool>,
+    }
+
+    impl Future for CountdownFuture<'_> {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            self.polls -= 1;
+            if self.polls == 0 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    impl Drop for CountdownFuture<'_> {
+        fn drop(&mut self) {
+            self.dropped.set(true);
+        }
+    }
+
+    // Returns pending once, then completes.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn earlier_futures_win_ties() {
+        let result = poll_once(select5(
+            pending::<()>(),
+            ready(2),
+            ready(3),
+            ready(4),
+            ready(5),
+        ));
+        assert!(matches!(result, Poll::Ready(Either5::Second(2))));
+    }
+
+    #[test]
+    fn held_future_resumes_across_selects() {
+        let dropped = Cell::new(false);
+        let mut held = CountdownFuture {
+            polls: 3,
+            dropped: &dropped,
+        };
+
+        // Another future wins the first two selects, each polling the held future once.
+        for _ in 0..2 {
+            let result = poll_once(select5(
+                &mut held,
+                ready(()),
+                pending::<()>(),
+                pending::<()>(),
+                pending::<()>(),
+            ));
+            assert!(matches!(result, Poll::Ready(Either5::Second(()))));
+            assert!(!dropped.get(), "the held future was dropped by a select");
+        }
+
+        // Had it been reset, it would still need three polls.
+        let result = poll_once(select5(
+            &mut held,
+            ready(()),
+            pending::<()>(),
+            pending::<()>(),
+            pending::<()>(),
+        ));
+        assert!(matches!(result, Poll::Ready(Either5::First(()))));
+    }
+
+    #[test]
+    fn future_created_for_each_select_is_dropped() {
+        let dropped = Cell::new(false);
+        let fresh = CountdownFuture {
+            polls: 3,
+            dropped: &dropped,
+        };
+
+        let result = poll_once(select5(
+            fresh,
+            ready(()),
+            pending::<()>(),
+            pending::<()>(),
+            pending::<()>(),
+        ));
+        assert!(matches!(result, Poll::Ready(Either5::Second(()))));
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn pinned_future_resumes_across_selects() {
+        // An async block is not `Unpin`, so it is pinned and passed as `fut.as_mut()`.
+        let mut held = pin!(async {
+            YieldOnce(false).await;
+            YieldOnce(false).await;
+            42
+        });
+
+        let mut selects = 0;
+        let value = loop {
+            selects += 1;
+            let select = select10(
+                held.as_mut(),
+                ready(()),
+                pending::<()>(),
+                pending::<()>(),
+                pending::<()>(),
+                pending::<()>(),
+                pending::<()>(),
+                pending::<()>(),
+                pending::<()>(),
+                pending::<()>(),
+            );
+            if let Poll::Ready(Either10::First(value)) = poll_once(select) {
+                break value;
+            }
+        };
+        assert_eq!(value, 42);
+        assert_eq!(selects, 3);
+    }
+}
@@ -0,0 +1,678 @@
+use core::{fmt, str::FromStr};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::pattern::{Pattern, TOTAL_STEPS, generate_evenly_distributed_steps};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SsrCommand {
+    /// Sets the SSR duty to zero and locks it from being updated. An over-temperature lock does
+    /// not replace an operator's lock, which only an operator releases.
+    Lock(LockReason),
+    /// Releases a lock with the given reason, allowing the SSR duty to be updated: the temperature
+    /// dropped, or an operator released their lock. Remains set to zero until an update. Does not
+    /// clear a fault, or a lock with another reason.
+    Unlock(LockReason),
+    /// Sets the SSR duty to zero and latches it off until a `ManualUnlock` or a reboot.
+    Fault(FaultReason),
+    /// Unlocks the SSR duty and clears a fault. Only issued by an operator.
+    ManualUnlock,
+    /// Latches the SSR off on an open interlock, over any other lock or fault.
+    /// Operator unlocks are refused until an `InterlockClosed`.
+    InterlockOpen,
+    /// The interlock closed again. The SSR stays latched off until a `ManualUnlock`.
+    InterlockClosed,
+}
+
+impl SsrCommand {
+    pub fn name(self) -> &'static str {
+        match self {
+            SsrCommand::Lock(_) => "lock",
+            SsrCommand::Unlock(_) => "unlock",
+            SsrCommand::Fault(_) => "fault",
+            SsrCommand::ManualUnlock => "manual-unlock",
+            SsrCommand::InterlockOpen => "interlock-open",
+            SsrCommand::InterlockClosed => "interlock-closed",
+        }
+    }
+
+    /// Why the command was issued, for display.
+    pub fn reason(self) -> &'static str {
+        match self {
+            SsrCommand::Lock(reason) => reason.description(),
+            SsrCommand::Unlock(LockReason::OverTemperature) => "temperature back under the limit",
+            SsrCommand::Unlock(LockReason::Manual) => "released by an operator",
+            SsrCommand::Fault(reason) => reason.description(),
+            SsrCommand::ManualUnlock => "cleared by an operator",
+            SsrCommand::InterlockOpen => FaultReason::InterlockOpen.description(),
+            SsrCommand::InterlockClosed => "interlock closed, awaiting an operator unlock",
+        }
+    }
+}
+
+/// Why the SSR was locked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockReason {
+    /// The temperature reached the high limit.
+    OverTemperature,
+    /// Locked by an operator.
+    Manual,
+}
+
+impl LockReason {
+    pub fn description(self) -> &'static str {
+        match self {
+            LockReason::OverTemperature => "over-temperature",
+            LockReason::Manual => "locked by an operator",
+        }
+    }
+}
+
+/// Conditions that latch the SSR off until an operator clears them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultReason {
+    /// The cutoff sensor exceeded its absolute limit.
+    CutoffTemperature,
+    /// The primary temperature sensor stopped responding.
+    SensorFailure,
+    /// The cutoff sensor stopped responding.
+    CutoffSensorFailure,
+    /// The SSR feedback line stopped following the pattern, e.g. on a stuck MOSFET or SSR.
+    OutputMismatch,
+    /// The hardware interlock is or was open.
+    InterlockOpen,
+}
+
+impl FaultReason {
+    pub fn description(self) -> &'static str {
+        match self {
+            FaultReason::CutoffTemperature => "secondary cutoff over-temperature",
+            FaultReason::SensorFailure => "temperature sensor fault",
+            FaultReason::CutoffSensorFailure => "secondary cutoff sensor fault",
+            FaultReason::OutputMismatch => "ssr output not following the pattern",
+            FaultReason::InterlockOpen => "interlock open",
+        }
+    }
+}
+
+/// Whether the SSR duty can be updated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SsrStatus {
+    #[default]
+    Unlocked,
+    /// Locked until unlocked, on its own once the temperature drops or by an operator.
+    Locked(LockReason),
+    /// Latched off until an operator unlocks it.
+    Fault(FaultReason),
+}
+
+/// The safety mechanism behind an alert.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertReason {
+    Lock(LockReason),
+    Fault(FaultReason),
+    /// The duty was capped by thermal derating.
+    Derating,
+}
+
+impl AlertReason {
+    pub fn description(self) -> &'static str {
+        match self {
+            AlertReason::Lock(reason) => reason.description(),
+            AlertReason::Fault(reason) => reason.description(),
+            AlertReason::Derating => "thermal derating",
+        }
+    }
+}
+
+/// Raised when a safety mechanism holds the applied duty below the commanded duty.
+#[derive(Clone, Copy, Debug)]
+pub struct SsrAlert {
+    pub commanded: Duty,
+    pub applied: Duty,
+    pub reason: AlertReason,
+}
+
+/// A duty cycle percentage, always between 0 and 100. Deserializing checks the range too.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "u8")]
+pub struct Duty(u8);
+
+impl Duty {
+    pub const OFF: Duty = Duty(0);
+    pub const FULL: Duty = Duty(100);
+
+    /// Returns an error unless the percentage is between 0 and 100.
+    pub fn new(percent: u8) -> Result<Self, DutyError> {
+        if percent > 100 {
+            return Err(DutyError::OutOfRange);
+        }
+        Ok(Duty(percent))
+    }
+
+    /// Clamps the percentage to 100.
+    pub const fn saturating(percent: u8) -> Self {
+        Duty(if percent > 100 { 100 } else { percent })
+    }
+
+    pub fn percent(self) -> u8 {
+        self.0
+    }
+
+    /// The duty applied to the SSR for this commanded duty, following `DUTY_CURVE`.
+    pub fn mapped(self) -> Duty {
+        let commanded = self.0 as f32;
+        for segment in DUTY_CURVE.windows(2) {
+            let ((x0, y0), (x1, y1)) = (segment[0], segment[1]);
+            if x0 < x1 && (x0..=x1).contains(&self.0) {
+                let (x0, y0, x1, y1) = (x0 as f32, y0 as f32, x1 as f32, y1 as f32);
+                let applied = y0 + (y1 - y0) * (commanded - x0) / (x1 - x0);
+                // Round to the nearest percent.
+                return Duty::saturating((applied.clamp(0.0, 100.0) + 0.5) as u8);
+            }
+        }
+        // Outside the curve, apply the duty as commanded.
+        self
+    }
+}
+
+impl TryFrom<u8> for Duty {
+    type Error = DutyError;
+
+    fn try_from(percent: u8) -> Result<Self, Self::Error> {
+        Duty::new(percent)
+    }
+}
+
+impl From<Duty> for u8 {
+    fn from(duty: Duty) -> Self {
+        duty.0
+    }
+}
+
+impl fmt::Display for Duty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for Duty {
+    type Err = DutyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let percent = s.trim().parse::<u8>().map_err(|_| DutyError::Invalid)?;
+        Duty::new(percent)
+    }
+}
+
+// Maps the commanded duty to the duty applied to the SSR, for loads whose output isn't linear
+// with duty. Points are (commanded, applied) percentages, interpolated linearly. Commanded
+// percentages must increase and span 0 to 100. The default is the identity.
+const DUTY_CURVE: &[(u8, u8)] = &[(0, 0), (100, 100)];
+
+#[derive(Clone, Copy, Debug, Error)]
+pub enum DutyError {
+    #[error("duty must be between 0 and 100")]
+    OutOfRange,
+    #[error("duty must be a whole number")]
+    Invalid,
+}
+
+/// An output switching an SSR, on the board or mocked in tests.
+pub trait SsrOutput {
+    fn set_on(&mut self, on: bool);
+}
+
+/// Fires the SSR outputs from their patterns, and applies lock, unlock and fault commands.
+///
+/// Holds no timing of its own: the SSR control task calls `fire` once per pattern step.
+#[derive(Clone, Debug)]
+pub struct SsrController<const ZONES: usize> {
+    pattern: Pattern,
+    zone_patterns: [Pattern; ZONES],
+    // The commanded duty, and the derating cap from the last good temperature reading.
+    duty: Duty,
+    cap: Duty,
+    zone_duties: [Duty; ZONES],
+    // Locking the SSR sets its duty to zero and ignores any updates until an unlock.
+    // A fault keeps the SSR locked until an operator unlocks it.
+    status: SsrStatus,
+    // Whether the interlock is open, which holds off operator unlocks.
+    interlock_open: bool,
+}
+
+/// What a command changed, for the SSR control task to report.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CommandOutcome {
+    pub status_changed: bool,
+    /// The outputs were switched off, and stay off until a new duty after an unlock.
+    pub cleared: bool,
+    /// An operator unlock was refused, as the interlock is open.
+    pub refused: bool,
+    /// Raised when a safety lock or fault overrides a running duty.
+    pub alert: Option<SsrAlert>,
+}
+
+impl<const ZONES: usize> SsrController<ZONES> {
+    /// Starts with every output off, in agreement with the heater state starting Off, until a
+    /// duty arrives.
+    ///
+    /// Starting with a lock or fault in place, as after a restart, also ignores duties until an
+    /// unlock. An interlock fault is assumed to still be open, until the interlock reports
+    /// otherwise.
+    pub fn new(status: SsrStatus) -> Self {
+        SsrController {
+            pattern: [false; TOTAL_STEPS],
+            zone_patterns: [[false; TOTAL_STEPS]; ZONES],
+            duty: Duty::OFF,
+            cap: Duty::FULL,
+            zone_duties: [Duty::OFF; ZONES],
+            status,
+            interlock_open: status == SsrStatus::Fault(FaultReason::InterlockOpen),
+        }
+    }
+
+    pub fn status(&self) -> SsrStatus {
+        self.status
+    }
+
+    /// Returns the commanded duty.
+    pub fn duty(&self) -> Duty {
+        self.duty
+    }
+
+    /// Returns the pattern fired on the main output.
+    pub fn pattern(&self) -> Pattern {
+        self.pattern
+    }
+
+    /// Sets the outputs for a step of their patterns. Returns whether the main output is on.
+    pub fn fire<O: SsrOutput, Z: SsrOutput>(
+        &self,
+        step: usize,
+        output: &mut O,
+        zone_outputs: &mut [Z; ZONES],
+    ) -> bool {
+        let on = self.pattern[step];
+        output.set_on(on);
+
+        // Offset each extra zone by one step, so that zones don't switch on together and
+        // add up their inrush current.
+        for (zone, zone_output) in zone_outputs.iter_mut().enumerate() {
+            let offset_step = (step + zone + 1) % TOTAL_STEPS;
+            zone_output.set_on(self.zone_patterns[zone][offset_step]);
+        }
+        on
+    }
+
+    /// Applies a lock, unlock or fault command. Every output is off from the next step on, unless
+    /// the SSR is left unlocked.
+    pub fn command(&mut self, command: SsrCommand) -> CommandOutcome {
+        let status = self.status;
+        let mut refused = false;
+        let new_status = match (command, status) {
+            // The interlock wins over any other lock or fault.
+            (SsrCommand::InterlockOpen, _) => SsrStatus::Fault(FaultReason::InterlockOpen),
+            (SsrCommand::InterlockClosed, _) => status,
+            (SsrCommand::ManualUnlock, _) if self.interlock_open => {
+                refused = true;
+                status
+            }
+            // A fault is only cleared by an operator, and keeps its first reason.
+            (SsrCommand::ManualUnlock, _) => SsrStatus::Unlocked,
+            (_, SsrStatus::Fault(_)) => status,
+            // Keep an operator's lock, or the over-temperature unlock would release it.
+            (
+                SsrCommand::Lock(LockReason::OverTemperature),
+                SsrStatus::Locked(LockReason::Manual),
+            ) => status,
+            (SsrCommand::Lock(reason), _) => SsrStatus::Locked(reason),
+            (SsrCommand::Unlock(reason), SsrStatus::Locked(locked)) if reason == locked => {
+                SsrStatus::Unlocked
+            }
+            (SsrCommand::Unlock(_), _) => status,
+            (SsrCommand::Fault(reason), _) => SsrStatus::Fault(reason),
+        };
+
+        match command {
+            SsrCommand::InterlockOpen => self.interlock_open = true,
+            SsrCommand::InterlockClosed => self.interlock_open = false,
+            _ => (),
+        }
+
+        // Alert if a safety lock or fault overrides a running duty.
+        let safety_reason = match new_status {
+            _ if new_status == status || self.duty == Duty::OFF => None,
+            SsrStatus::Locked(LockReason::OverTemperature) => {
+                Some(AlertReason::Lock(LockReason::OverTemperature))
+            }
+            SsrStatus::Fault(reason) => Some(AlertReason::Fault(reason)),
+            _ => None,
+        };
+        let alert = safety_reason.map(|reason| SsrAlert {
+            commanded: self.duty,
+            applied: Duty::OFF,
+            reason,
+        });
+
+        // Stays at zero after an unlock, until a new duty arrives.
+        let cleared = new_status != SsrStatus::Unlocked;
+        if cleared {
+            self.clear();
+        }
+        self.status = new_status;
+
+        CommandOutcome {
+            status_changed: new_status != status,
+            cleared,
+            refused,
+            alert,
+        }
+    }
+
+    /// Records the derating cap. Returns whether it changed. Applies on the next duty update.
+    pub fn set_cap(&mut self, cap: Duty) -> bool {
+        let changed = cap != self.cap;
+        self.cap = cap;
+        changed
+    }
+
+    /// Sets a new commanded duty, or keeps the current one if None, and regenerates the main
+    /// pattern after the duty curve and under the derating cap. The pattern takes effect from the
+    /// next step, continuing from the same step position. Since the pattern is evenly
+    /// distributed, this puts the SSR right into the new duty cycle.
+    ///
+    /// Ignored unless unlocked. Returns an alert if the cap holds the duty below the commanded one.
+    pub fn update_duty(&mut self, duty: Option<Duty>) -> Option<SsrAlert> {
+        if self.status != SsrStatus::Unlocked {
+            return None;
+        }
+
+        if let Some(duty) = duty {
+            self.duty = duty;
+        }
+        self.pattern = generate_evenly_distributed_steps(self.duty.mapped().min(self.cap));
+
+        (self.cap < self.duty.mapped()).then_some(SsrAlert {
+            commanded: self.duty,
+            applied: self.cap,
+            reason: AlertReason::Derating,
+        })
+    }
+
+    /// Likewise for the extra zones, under the same derating cap. Ignored unless unlocked.
+    pub fn update_zone_duties(&mut self, duties: Option<[Duty; ZONES]>) {
+        if self.status != SsrStatus::Unlocked {
+            return;
+        }
+
+        if let Some(duties) = duties {
+            self.zone_duties = duties;
+        }
+        for (zone_pattern, zone_duty) in self.zone_patterns.iter_mut().zip(self.zone_duties) {
+            *zone_pattern = generate_evenly_distributed_steps(zone_duty.mapped().min(self.cap));
+        }
+    }
+
+    // Switches every output off, and zeroes the commanded duties.
+    fn clear(&mut self) {
+        self.duty = Duty::OFF;
+        self.pattern = [false; TOTAL_STEPS];
+        self.zone_duties = [Duty::OFF; ZONES];
+        self.zone_patterns = [[false; TOTAL_STEPS]; ZONES];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records every level set, one per step fired.
+    #[derive(Debug, Default)]
+    struct MockOutput {
+        timeline: Vec<bool>,
+    }
+
+    impl SsrOutput for MockOutput {
+        fn set_on(&mut self, on: bool) {
+            self.timeline.push(on);
+        }
+    }
+
+    struct Outputs<const ZONES: usize> {
+        main: MockOutput,
+        zones: [MockOutput; ZONES],
+    }
+
+    impl<const ZONES: usize> Outputs<ZONES> {
+        fn new() -> Self {
+            Outputs {
+                main: MockOutput::default(),
+                zones: core::array::from_fn(|_| MockOutput::default()),
+            }
+        }
+
+        fn fire(&mut self, controller: &SsrController<ZONES>, steps: core::ops::Range<usize>) {
+            for step in steps {
+                controller.fire(step, &mut self.main, &mut self.zones);
+            }
+        }
+    }
+
+    fn duty(percent: u8) -> Duty {
+        Duty::new(percent).unwrap()
+    }
+
+    fn running(percent: u8) -> SsrController<0> {
+        let mut controller = SsrController::new(SsrStatus::Unlocked);
+        controller.update_duty(Some(duty(percent)));
+        controller
+    }
+
+    #[test]
+    fn duty_change_takes_effect_on_the_next_step() {
+        let mut controller = running(0);
+        let mut outputs = Outputs::new();
+        outputs.fire(&controller, 0..40);
+        assert!(outputs.main.timeline.iter().all(|&on| !on));
+
+        // The new pattern picks up from the same step position.
+        controller.update_duty(Some(duty(50)));
+        outputs.fire(&controller, 40..TOTAL_STEPS);
+        let expected = generate_evenly_distributed_steps(duty(50));
+        assert_eq!(outputs.main.timeline[40..], expected[40..]);
+    }
+
+    #[test]
+    fn lock_forces_all_off_on_the_next_step() {
+        let mut controller = running(100);
+        let mut outputs = Outputs::new();
+        outputs.fire(&controller, 0..10);
+        assert!(outputs.main.timeline.iter().all(|&on| on));
+
+        let outcome = controller.command(SsrCommand::Lock(LockReason::OverTemperature));
+        assert!(outcome.status_changed && outcome.cleared);
+        let alert = outcome.alert.unwrap();
+        assert_eq!(alert.commanded, Duty::FULL);
+        assert_eq!(alert.applied, Duty::OFF);
+        assert_eq!(alert.reason, AlertReason::Lock(LockReason::OverTemperature));
+
+        // Duty updates are ignored while locked.
+        assert!(controller.update_duty(Some(Duty::FULL)).is_none());
+        outputs.fire(&controller, 10..TOTAL_STEPS);
+        assert!(outputs.main.timeline[10..].iter().all(|&on| !on));
+        assert_eq!(
+            controller.status(),
+            SsrStatus::Locked(LockReason::OverTemperature)
+        );
+    }
+
+    #[test]
+    fn unlock_stays_off_until_a_new_duty() {
+        let mut controller = running(100);
+        controller.command(SsrCommand::Lock(LockReason::OverTemperature));
+
+        // Only a matching reason releases the lock.
+        let outcome = controller.command(SsrCommand::Unlock(LockReason::Manual));
+        assert!(!outcome.status_changed);
+        assert_eq!(
+            controller.status(),
+            SsrStatus::Locked(LockReason::OverTemperature)
+        );
+
+        let outcome = controller.command(SsrCommand::Unlock(LockReason::OverTemperature));
+        assert!(outcome.status_changed && !outcome.cleared);
+        assert_eq!(controller.status(), SsrStatus::Unlocked);
+        assert_eq!(controller.pattern(), [false; TOTAL_STEPS]);
+
+        controller.update_duty(Some(duty(30)));
+        assert_eq!(
+            controller.pattern(),
+            generate_evenly_distributed_steps(duty(30))
+        );
+    }
+
+    #[test]
+    fn over_temperature_keeps_an_operator_lock() {
+        let mut controller = running(60);
+        controller.command(SsrCommand::Lock(LockReason::Manual));
+
+        let outcome = controller.command(SsrCommand::Lock(LockReason::OverTemperature));
+        assert!(!outcome.status_changed);
+        let outcome = controller.command(SsrCommand::Unlock(LockReason::OverTemperature));
+        assert!(!outcome.status_changed);
+        assert_eq!(controller.status(), SsrStatus::Locked(LockReason::Manual));
+
+        // An operator's lock replaces an over-temperature one, and outlasts the temperature.
+        let mut controller = running(60);
+        controller.command(SsrCommand::Lock(LockReason::OverTemperature));
+        controller.command(SsrCommand::Lock(LockReason::Manual));
+        controller.command(SsrCommand::Unlock(LockReason::OverTemperature));
+        assert_eq!(controller.status(), SsrStatus::Locked(LockReason::Manual));
+
+        controller.command(SsrCommand::Unlock(LockReason::Manual));
+        assert_eq!(controller.status(), SsrStatus::Unlocked);
+    }
+
+    #[test]
+    fn fault_latches_until_an_operator_unlock() {
+        let mut controller = running(60);
+        let outcome = controller.command(SsrCommand::Fault(FaultReason::SensorFailure));
+        assert_eq!(
+            outcome.alert.unwrap().reason,
+            AlertReason::Fault(FaultReason::SensorFailure)
+        );
+
+        // Neither an unlock nor a later fault or lock changes it.
+        for command in [
+            SsrCommand::Unlock(LockReason::OverTemperature),
+            SsrCommand::Lock(LockReason::Manual),
+            SsrCommand::Fault(FaultReason::CutoffTemperature),
+        ] {
+            let outcome = controller.command(command);
+            assert!(!outcome.status_changed && outcome.alert.is_none());
+            assert_eq!(
+                controller.status(),
+                SsrStatus::Fault(FaultReason::SensorFailure)
+            );
+        }
+
+        controller.command(SsrCommand::ManualUnlock);
+        assert_eq!(controller.status(), SsrStatus::Unlocked);
+    }
+
+    #[test]
+    fn open_interlock_refuses_operator_unlocks() {
+        let mut controller = running(60);
+        controller.command(SsrCommand::Lock(LockReason::Manual));
+        controller.command(SsrCommand::InterlockOpen);
+        assert_eq!(
+            controller.status(),
+            SsrStatus::Fault(FaultReason::InterlockOpen)
+        );
+
+        let outcome = controller.command(SsrCommand::ManualUnlock);
+        assert!(outcome.refused && !outcome.status_changed);
+
+        // Closing the interlock leaves the SSR latched off until an operator unlocks it.
+        controller.command(SsrCommand::InterlockClosed);
+        assert_eq!(
+            controller.status(),
+            SsrStatus::Fault(FaultReason::InterlockOpen)
+        );
+        let outcome = controller.command(SsrCommand::ManualUnlock);
+        assert!(!outcome.refused && outcome.status_changed);
+        assert_eq!(controller.status(), SsrStatus::Unlocked);
+    }
+
+    #[test]
+    fn starts_off_until_a_duty_arrives() {
+        let mut controller = SsrController::<1>::new(SsrStatus::Unlocked);
+        let mut outputs = Outputs::new();
+        outputs.fire(&controller, 0..TOTAL_STEPS);
+        assert!(outputs.main.timeline.iter().all(|&on| !on));
+        assert!(outputs.zones[0].timeline.iter().all(|&on| !on));
+        assert_eq!(controller.duty(), Duty::OFF);
+
+        controller.update_duty(Some(Duty::FULL));
+        assert_eq!(controller.pattern(), [true; TOTAL_STEPS]);
+    }
+
+    #[test]
+    fn restart_keeps_a_lock_or_fault_in_place() {
+        for status in [
+            SsrStatus::Locked(LockReason::Manual),
+            SsrStatus::Fault(FaultReason::OutputMismatch),
+        ] {
+            let controller = SsrController::<0>::new(status);
+            let mut outputs = Outputs::new();
+            outputs.fire(&controller, 0..TOTAL_STEPS);
+            assert!(outputs.main.timeline.iter().all(|&on| !on));
+        }
+
+        // An interlock fault is assumed to still be open.
+        let mut controller = SsrController::<0>::new(SsrStatus::Fault(FaultReason::InterlockOpen));
+        assert!(controller.command(SsrCommand::ManualUnlock).refused);
+    }
+
+    #[test]
+    fn derating_caps_the_pattern_and_alerts() {
+        let mut controller = running(80);
+        assert!(controller.set_cap(duty(50)));
+        assert!(!controller.set_cap(duty(50)));
+
+        let alert = controller.update_duty(None).unwrap();
+        assert_eq!(alert.commanded, duty(80));
+        assert_eq!(alert.applied, duty(50));
+        assert_eq!(alert.reason, AlertReason::Derating);
+        assert_eq!(
+            controller.pattern(),
+            generate_evenly_distributed_steps(duty(50))
+        );
+
+        // No alert once the commanded duty is under the cap.
+        assert!(controller.update_duty(Some(duty(40))).is_none());
+    }
+
+    #[test]
+    fn zones_fire_offset_and_lock_with_the_main_heater() {
+        let mut controller = SsrController::<2>::new(SsrStatus::Unlocked);
+        controller.update_duty(Some(Duty::OFF));
+        controller.update_zone_duties(Some([duty(1), duty(1)]));
+        let mut outputs = Outputs::new();
+        outputs.fire(&controller, 0..TOTAL_STEPS);
+
+        // Each zone fires its single step one step earlier than the zone before it.
+        let on_step = |timeline: &[bool]| timeline.iter().position(|&on| on).unwrap();
+        let pattern_step = on_step(&generate_evenly_distributed_steps(duty(1)));
+        assert_eq!(on_step(&outputs.zones[0].timeline), pattern_step - 1);
+        assert_eq!(on_step(&outputs.zones[1].timeline), pattern_step - 2);
+
+        controller.command(SsrCommand::Lock(LockReason::Manual));
+        let mut outputs = Outputs::new();
+        outputs.fire(&controller, 0..TOTAL_STEPS);
+        for zone in &outputs.zones {
+            assert!(zone.timeline.iter().all(|&on| !on));
+        }
+    }
+}
@@ -0,0 +1,660 @@
+use alloc::string::String;
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+use embassy_time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::ssr::{Duty, FaultReason, SsrStatus};
+
+// Remotes must check in periodically or the heater shuts off.
+pub const REMOTE_CHECKIN_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Default)]
+pub struct HeaterControlState {
+    duty: Duty,
+    // Target temperature for the thermostat, in Celsius.
+    setpoint: Option<f32>,
+    state: HeaterState,
+    // Time spent in each state since boot.
+    state_times: StateTimes,
+    // Whether the SSR is locked or faulted. Kept here rather than in the SSR control task, so
+    // that a lock survives the task being restarted.
+    ssr_status: SsrStatus,
+}
+
+#[derive(Clone, Debug, Default)]
+struct StateTimes {
+    // When the current state was entered. None for the state held since boot.
+    entered_at: Option<Instant>,
+    // Time spent in each state before the current one, in the order of `HeaterState::NAMES`.
+    totals: [Duration; HeaterState::COUNT],
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum HeaterState {
+    #[default]
+    Off,
+    // The heater is being controlled by a remote.
+    Remote {
+        // An identifier for the remote that is actively controlling the heater.
+        remote_id: String,
+        // Automatically turn off the heater if a remote has not been seen for some time.
+        expires: embassy_time::Instant,
+        // The highest duty this remote may set, fixed when it takes control.
+        max_duty: Duty,
+    },
+    // The heater is being controlled manually.
+    Manual,
+    // The heater duty is set by the thermostat to reach the setpoint.
+    Thermostat,
+}
+
+impl HeaterState {
+    pub const COUNT: usize = 4;
+    pub const NAMES: [&'static str; HeaterState::COUNT] = ["off", "remote", "manual", "thermostat"];
+
+    /// The name of this state, from `NAMES`.
+    pub fn name(&self) -> &'static str {
+        HeaterState::NAMES[self.index()]
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            HeaterState::Off => 0,
+            HeaterState::Remote { .. } => 1,
+            HeaterState::Manual => 2,
+            HeaterState::Thermostat => 3,
+        }
+    }
+}
+
+/// What the heater is doing, as reported by the mode queries: its state, or a fault that
+/// overrides it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaterMode {
+    Off,
+    Manual,
+    Remote(String),
+    Thermostat,
+    Fault(FaultReason),
+}
+
+impl fmt::Display for HeaterMode {
+    /// Formats as the state name, e.g. `manual`, with the remote ID as in `remote:<id>`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaterMode::Off => f.write_str("off"),
+            HeaterMode::Manual => f.write_str("manual"),
+            HeaterMode::Remote(remote_id) => write!(f, "remote:{remote_id}"),
+            HeaterMode::Thermostat => f.write_str("thermostat"),
+            HeaterMode::Fault(_) => f.write_str("fault"),
+        }
+    }
+}
+
+impl Deref for HeaterControlState {
+    type Target = HeaterState;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+impl DerefMut for HeaterControlState {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.state
+    }
+}
+
+impl HeaterControlState {
+    pub fn is_remote(&self) -> bool {
+        matches!(self.state, HeaterState::Remote { .. })
+    }
+
+    pub fn is_manual(&self) -> bool {
+        matches!(self.state, HeaterState::Manual)
+    }
+
+    pub fn is_off(&self) -> bool {
+        matches!(self.state, HeaterState::Off)
+    }
+
+    pub fn is_thermostat(&self) -> bool {
+        matches!(self.state, HeaterState::Thermostat)
+    }
+
+    /// Returns the last recorded duty cycle.
+    pub fn duty(&self) -> Duty {
+        self.duty
+    }
+
+    /// Returns the thermostat setpoint, if one was set.
+    pub fn setpoint(&self) -> Option<f32> {
+        self.setpoint
+    }
+
+    /// Sets the thermostat setpoint, in Celsius.
+    pub fn set_setpoint(&mut self, setpoint: f32) {
+        self.setpoint = Some(setpoint);
+    }
+
+    /// Returns whether the SSR is locked or faulted.
+    pub fn ssr_status(&self) -> SsrStatus {
+        self.ssr_status
+    }
+
+    /// Records the SSR lock or fault status. Only the SSR control task sets it.
+    pub fn set_ssr_status(&mut self, status: SsrStatus) {
+        self.ssr_status = status;
+    }
+
+    /// Returns the time spent in each state since boot, in the order of `HeaterState::NAMES`.
+    pub fn state_times(&self) -> [Duration; HeaterState::COUNT] {
+        let mut times = self.state_times.totals;
+        times[self.state.index()] += self.time_in_state();
+        times
+    }
+
+    // Time spent in the current state so far. Boot is at `Instant::MIN`.
+    fn time_in_state(&self) -> Duration {
+        Instant::now() - self.state_times.entered_at.unwrap_or(Instant::MIN)
+    }
+
+    // Moves to a new state, accounting for the time spent in the current one.
+    fn set_state(&mut self, state: HeaterState) {
+        let index = self.state.index();
+        self.state_times.totals[index] += self.time_in_state();
+        self.state_times.entered_at = Some(Instant::now());
+        self.state = state;
+    }
+
+    /// Returns the ID of the currently controlling remote, if any.
+    pub fn remote_id(&self) -> Option<&str> {
+        if let HeaterState::Remote { remote_id, .. } = &self.state {
+            Some(remote_id.as_str())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the heater mode. A fault overrides the state, as it holds the heater off.
+    ///
+    /// A lock does not, as it clears on its own once the temperature drops.
+    pub fn mode(&self) -> HeaterMode {
+        if let SsrStatus::Fault(reason) = self.ssr_status {
+            return HeaterMode::Fault(reason);
+        }
+
+        match &self.state {
+            HeaterState::Off => HeaterMode::Off,
+            HeaterState::Remote { remote_id, .. } => HeaterMode::Remote(remote_id.clone()),
+            HeaterState::Manual => HeaterMode::Manual,
+            HeaterState::Thermostat => HeaterMode::Thermostat,
+        }
+    }
+
+    /// Transition to Off.
+    ///
+    /// This transition is always possible.
+    pub fn transition_to_off(&mut self) {
+        self.set_state(HeaterState::Off);
+    }
+
+    /// Transition to Manual and set a duty cycle.
+    ///
+    /// This transition is always possible. It takes control from any other source, so it's meant
+    /// for explicit mode changes. Duty updates go through `manual_update_duty`.
+    pub fn transition_to_manual(&mut self, heater_duty: Duty) {
+        self.duty = heater_duty;
+        self.set_state(HeaterState::Manual);
+    }
+
+    /// Updates the duty cycle set manually, on the serial console or over MQTT without a remote.
+    ///
+    /// Takes control if the heater is off. Setting a zero duty releases control, back to Off.
+    /// Returns an error if the thermostat or an active remote is in control.
+    pub fn manual_update_duty(&mut self, heater_duty: Duty) -> Result<(), StateError> {
+        match &self.state {
+            HeaterState::Off | HeaterState::Manual => (),
+            // An expired remote is about to be cleared, don't hold up control for it.
+            HeaterState::Remote { expires, .. }
+                if Instant::now().checked_duration_since(*expires).is_some() => {}
+            other => return Err(StateError::NotInControl(other.name())),
+        }
+
+        if heater_duty == Duty::OFF {
+            self.duty = heater_duty;
+            self.transition_to_off();
+        } else {
+            self.transition_to_manual(heater_duty);
+        }
+        Ok(())
+    }
+
+    /// Transition to Thermostat.
+    ///
+    /// Returns an error if no setpoint has been set.
+    pub fn transition_to_thermostat(&mut self) -> Result<(), StateError> {
+        if self.setpoint.is_none() {
+            return Err(StateError::NoSetpoint);
+        }
+
+        self.set_state(HeaterState::Thermostat);
+        Ok(())
+    }
+
+    /// Updates the duty cycle set by the thermostat.
+    ///
+    /// Returns an error if the heater is not in Thermostat mode.
+    pub fn thermostat_update_duty(&mut self, heater_duty: Duty) -> Result<(), StateError> {
+        if !self.is_thermostat() {
+            return Err(StateError::NotThermostat);
+        }
+
+        self.duty = heater_duty;
+        Ok(())
+    }
+
+    /// Updates the duty cycle set by a remote, and returns the duty to apply.
+    ///
+    /// A remote takes control only if the heater is off, and can declare a `max_duty` as it does.
+    /// Its duty updates are then clamped to that limit until it gives up control. A limit sent
+    /// with later updates is ignored, so a remote can't raise its own limit while in control.
+    ///
+    /// Returns an error if the heater is in Manual or Thermostat mode, or if the requesting remote
+    /// is not the active remote, whether because it has failed to check in on time.
+    pub fn remote_update_duty(
+        &mut self,
+        remote_id: impl Into<String>,
+        heater_duty: Duty,
+        max_duty: Option<Duty>,
+    ) -> Result<Duty, StateError> {
+        match &mut self.state {
+            HeaterState::Manual | HeaterState::Thermostat => {
+                Err(StateError::NotInControl(self.state.name()))
+            }
+
+            HeaterState::Off => {
+                // Set the mode to remote, record the remote identifier and its limit.
+                let max_duty = max_duty.unwrap_or(Duty::FULL);
+                self.duty = heater_duty.min(max_duty);
+                self.set_state(HeaterState::Remote {
+                    remote_id: remote_id.into(),
+                    expires: Instant::now() + REMOTE_CHECKIN_INTERVAL,
+                    max_duty,
+                });
+                Ok(self.duty)
+            }
+
+            HeaterState::Remote {
+                remote_id: current_remote,
+                expires,
+                max_duty,
+            } => {
+                // See if the requesting remote is the one controlling the heater.
+                let remote_id = remote_id.into();
+                if *current_remote != remote_id {
+                    return Err(StateError::RemoteMismatch);
+                }
+
+                // See if the expiry time has elapsed.
+                // We use checked_duration_since because if `expires` is in the future, a regular duration
+                // calculation would underflow since Duration is unsigned.
+                if Instant::now().checked_duration_since(*expires).is_some() {
+                    return Err(StateError::RemoteExpired);
+                }
+
+                // Update the recorded duty, within the remote's limit.
+                let heater_duty = heater_duty.min(*max_duty);
+
+                // Set a new expiry time.
+                *expires = Instant::now() + REMOTE_CHECKIN_INTERVAL;
+
+                self.duty = heater_duty;
+                Ok(heater_duty)
+            }
+        }
+    }
+
+    /// Takes control from a remote that failed to check in, leaving the heater Off, or in Manual
+    /// mode if `expiry_duty` is above zero.
+    ///
+    /// Returns the ID of the expired remote and the duty it had set, or None if no remote expired.
+    pub fn expire_remote(&mut self, expiry_duty: Duty) -> Option<(String, Duty)> {
+        let HeaterState::Remote {
+            remote_id, expires, ..
+        } = &self.state
+        else {
+            return None;
+        };
+        Instant::now().checked_duration_since(*expires)?;
+
+        let expired = (remote_id.clone(), self.duty);
+        if expiry_duty == Duty::OFF {
+            self.transition_to_off();
+        } else {
+            self.transition_to_manual(expiry_duty);
+        }
+        Some(expired)
+    }
+}
+
+/// The part of the heater state that is persisted, to be resumed on boot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    duty: Duty,
+    setpoint: Option<f32>,
+    mode: SavedMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SavedMode {
+    #[default]
+    Off,
+    Manual,
+    Thermostat,
+}
+
+impl SavedState {
+    /// Returns the saved mode.
+    pub fn mode(&self) -> SavedMode {
+        self.mode
+    }
+}
+
+impl HeaterControlState {
+    /// Returns the state to persist.
+    ///
+    /// A remote can't have checked in across a reboot, so a remote-controlled heater saves as Off.
+    /// The duty is only saved in Manual mode. Elsewhere it follows the thermostat or a remote, and
+    /// saving it would write to flash on every change.
+    pub fn to_saved(&self) -> SavedState {
+        let (mode, duty) = match self.state {
+            HeaterState::Off | HeaterState::Remote { .. } => (SavedMode::Off, Duty::OFF),
+            HeaterState::Manual => (SavedMode::Manual, self.duty),
+            HeaterState::Thermostat => (SavedMode::Thermostat, Duty::OFF),
+        };
+
+        SavedState {
+            duty,
+            setpoint: self.setpoint,
+            mode,
+        }
+    }
+
+    /// Restores a saved state. Returns the duty to apply, if any.
+    ///
+    /// In Thermostat mode, the thermostat sets the duty once it has a reading.
+    pub fn restore(&mut self, saved: SavedState) -> Option<Duty> {
+        self.setpoint = saved.setpoint;
+        match saved.mode {
+            SavedMode::Off => None,
+            SavedMode::Manual => {
+                self.transition_to_manual(saved.duty);
+                Some(saved.duty)
+            }
+            SavedMode::Thermostat => {
+                // A saved Thermostat mode always has a setpoint, but fall back to Off regardless.
+                self.transition_to_thermostat().ok()?;
+                None
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Error)]
+pub enum StateError {
+    #[error("the heater is being controlled by another remote")]
+    RemoteMismatch,
+    #[error("the remote failed to check in and has expired")]
+    RemoteExpired,
+    #[error("no thermostat setpoint has been set")]
+    NoSetpoint,
+    #[error("the heater is not in thermostat mode")]
+    NotThermostat,
+    /// Another duty source is in control. Holds the name of the current state.
+    #[error("the heater is in {0} mode, change modes or stop it first")]
+    NotInControl(&'static str),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssr::LockReason;
+    use embassy_time::MockDriver;
+    use std::sync::{Mutex, MutexGuard};
+
+    // The mock clock is shared by all tests. Those that depend on a remote not expiring, or that
+    // advance the clock, hold this lock.
+    static CLOCK: Mutex<()> = Mutex::new(());
+
+    fn clock() -> MutexGuard<'static, ()> {
+        CLOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn duty(percent: u8) -> Duty {
+        Duty::new(percent).unwrap()
+    }
+
+    fn manual(percent: u8) -> HeaterControlState {
+        let mut state = HeaterControlState::default();
+        state.transition_to_manual(duty(percent));
+        state
+    }
+
+    fn thermostat() -> HeaterControlState {
+        let mut state = HeaterControlState::default();
+        state.set_setpoint(20.0);
+        state.transition_to_thermostat().unwrap();
+        state
+    }
+
+    fn remote(remote_id: &str, percent: u8) -> HeaterControlState {
+        let mut state = HeaterControlState::default();
+        state
+            .remote_update_duty(remote_id, duty(percent), None)
+            .unwrap();
+        state
+    }
+
+    #[test]
+    fn manual_takes_control_from_off_and_releases_at_zero() {
+        let mut state = HeaterControlState::default();
+        state.manual_update_duty(duty(50)).unwrap();
+        assert!(state.is_manual());
+        assert_eq!(state.duty(), duty(50));
+
+        state.manual_update_duty(Duty::OFF).unwrap();
+        assert!(state.is_off());
+        assert_eq!(state.duty(), Duty::OFF);
+    }
+
+    #[test]
+    fn thermostat_needs_a_setpoint() {
+        let mut state = HeaterControlState::default();
+        assert!(matches!(
+            state.transition_to_thermostat(),
+            Err(StateError::NoSetpoint)
+        ));
+        assert!(state.is_off());
+
+        state.set_setpoint(20.0);
+        state.transition_to_thermostat().unwrap();
+        assert!(state.is_thermostat());
+    }
+
+    #[test]
+    fn manual_is_rejected_while_another_source_is_in_control() {
+        let _clock = clock();
+        for (mut state, owner) in [(thermostat(), "thermostat"), (remote("a", 30), "remote")] {
+            let duty_before = state.duty();
+            assert!(matches!(
+                state.manual_update_duty(duty(80)),
+                Err(StateError::NotInControl(name)) if name == owner
+            ));
+            assert_eq!(state.name(), owner);
+            assert_eq!(state.duty(), duty_before);
+        }
+    }
+
+    #[test]
+    fn thermostat_is_rejected_outside_thermostat_mode() {
+        let _clock = clock();
+        for mut state in [HeaterControlState::default(), manual(40), remote("a", 30)] {
+            let duty_before = state.duty();
+            assert!(matches!(
+                state.thermostat_update_duty(duty(80)),
+                Err(StateError::NotThermostat)
+            ));
+            assert_eq!(state.duty(), duty_before);
+        }
+
+        let mut state = thermostat();
+        state.thermostat_update_duty(duty(80)).unwrap();
+        assert_eq!(state.duty(), duty(80));
+    }
+
+    #[test]
+    fn remote_is_rejected_in_manual_and_thermostat() {
+        for (mut state, owner) in [(manual(40), "manual"), (thermostat(), "thermostat")] {
+            assert!(matches!(
+                state.remote_update_duty("a", duty(80), None),
+                Err(StateError::NotInControl(name)) if name == owner
+            ));
+            assert_eq!(state.name(), owner);
+        }
+    }
+
+    #[test]
+    fn remote_takes_control_from_off_and_keeps_it() {
+        let _clock = clock();
+        let mut state = HeaterControlState::default();
+        assert_eq!(
+            state.remote_update_duty("a", duty(30), None).unwrap(),
+            duty(30)
+        );
+        assert!(state.is_remote());
+        assert_eq!(state.remote_id(), Some("a"));
+        assert_eq!(state.mode(), HeaterMode::Remote("a".into()));
+
+        assert!(matches!(
+            state.remote_update_duty("b", duty(80), None),
+            Err(StateError::RemoteMismatch)
+        ));
+        assert_eq!(state.remote_id(), Some("a"));
+        assert_eq!(state.duty(), duty(30));
+
+        assert_eq!(
+            state.remote_update_duty("a", duty(60), None).unwrap(),
+            duty(60)
+        );
+        assert_eq!(state.duty(), duty(60));
+    }
+
+    #[test]
+    fn remote_duty_is_clamped_to_the_limit_set_when_taking_control() {
+        let _clock = clock();
+        let mut state = HeaterControlState::default();
+        let applied = state
+            .remote_update_duty("a", duty(80), Some(duty(50)))
+            .unwrap();
+        assert_eq!(applied, duty(50));
+
+        // A limit sent with a later update can't raise it.
+        let applied = state
+            .remote_update_duty("a", duty(90), Some(Duty::FULL))
+            .unwrap();
+        assert_eq!(applied, duty(50));
+        assert_eq!(state.duty(), duty(50));
+    }
+
+    #[test]
+    fn explicit_transitions_take_control_from_any_source() {
+        let _clock = clock();
+        for mut state in [manual(40), thermostat(), remote("a", 30)] {
+            state.transition_to_manual(duty(20));
+            assert!(state.is_manual());
+            assert_eq!(state.duty(), duty(20));
+        }
+        for mut state in [manual(40), thermostat(), remote("a", 30)] {
+            state.transition_to_off();
+            assert!(state.is_off());
+        }
+    }
+
+    #[test]
+    fn expired_remote_loses_control() {
+        let _clock = clock();
+        let mut live = remote("a", 30);
+        assert!(live.expire_remote(Duty::OFF).is_none());
+        assert!(live.is_remote());
+
+        let mut state = remote("a", 30);
+        let mut floored = remote("b", 60);
+        let mut taken_over = remote("c", 60);
+        MockDriver::get().advance(REMOTE_CHECKIN_INTERVAL);
+
+        assert!(matches!(
+            state.remote_update_duty("a", duty(40), None),
+            Err(StateError::RemoteExpired)
+        ));
+        assert_eq!(state.expire_remote(Duty::OFF), Some(("a".into(), duty(30))));
+        assert!(state.is_off());
+        assert!(state.expire_remote(Duty::OFF).is_none());
+
+        // Above zero, the expiry duty is held in Manual mode.
+        assert_eq!(
+            floored.expire_remote(duty(10)),
+            Some(("b".into(), duty(60)))
+        );
+        assert!(floored.is_manual());
+        assert_eq!(floored.duty(), duty(10));
+
+        // An expired remote doesn't hold up manual control until it's cleared.
+        taken_over.manual_update_duty(duty(20)).unwrap();
+        assert!(taken_over.is_manual());
+        assert_eq!(taken_over.duty(), duty(20));
+    }
+
+    #[test]
+    fn fault_overrides_the_mode_and_lock_does_not() {
+        let mut state = manual(40);
+        state.set_ssr_status(SsrStatus::Locked(LockReason::OverTemperature));
+        assert_eq!(state.mode(), HeaterMode::Manual);
+
+        state.set_ssr_status(SsrStatus::Fault(FaultReason::SensorFailure));
+        assert_eq!(state.mode(), HeaterMode::Fault(FaultReason::SensorFailure));
+        assert!(state.is_manual());
+
+        state.set_ssr_status(SsrStatus::Unlocked);
+        assert_eq!(state.mode(), HeaterMode::Manual);
+    }
+
+    #[test]
+    fn saved_state_round_trips() {
+        let _clock = clock();
+        let saved = manual(40).to_saved();
+        assert_eq!(saved.mode(), SavedMode::Manual);
+        let mut restored = HeaterControlState::default();
+        assert_eq!(restored.restore(saved), Some(duty(40)));
+        assert!(restored.is_manual());
+
+        let saved = thermostat().to_saved();
+        assert_eq!(saved.mode(), SavedMode::Thermostat);
+        let mut restored = HeaterControlState::default();
+        assert_eq!(restored.restore(saved), None);
+        assert!(restored.is_thermostat());
+        assert_eq!(restored.setpoint(), Some(20.0));
+
+        // A remote can't have checked in across a reboot.
+        let saved = remote("a", 30).to_saved();
+        assert_eq!(saved.mode(), SavedMode::Off);
+        let mut restored = HeaterControlState::default();
+        assert_eq!(restored.restore(saved), None);
+        assert!(restored.is_off());
+    }
+}
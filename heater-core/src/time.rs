@@ -0,0 +1,64 @@
+//! Time formatting for logs and status reports.
+use alloc::{format, string::String};
+
+/// Formats a u64 millisecond value into "HHHHH:MM:SS.xxx" string.
+///
+/// Past 99999 hours the hours no longer fit their field, so days are split out instead,
+/// as "<days>d HH:MM:SS.xxx". Any u64 is handled, with no overflow.
+#[inline]
+pub fn format_milliseconds_to_hms(total_ms: u64) -> String {
+    let millis_part = total_ms % 1000;
+    let total_seconds = total_ms / 1000;
+
+    let seconds_part = total_seconds % 60;
+    let total_minutes = total_seconds / 60;
+
+    let minutes_part = total_minutes % 60;
+    let hours_part = total_minutes / 60;
+
+    if hours_part <= 99999 {
+        format!(
+            "{:05}:{:02}:{:02}.{:03}",
+            hours_part, minutes_part, seconds_part, millis_part
+        )
+    } else {
+        format!(
+            "{}d {:02}:{:02}:{:02}.{:03}",
+            hours_part / 24,
+            hours_part % 24,
+            minutes_part,
+            seconds_part,
+            millis_part
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_MS: u64 = 60 * 60 * 1000;
+
+    #[test]
+    fn formats_within_the_hour_field() {
+        assert_eq!(format_milliseconds_to_hms(0), "00000:00:00.000");
+        assert_eq!(format_milliseconds_to_hms(59_999), "00000:00:59.999");
+        assert_eq!(format_milliseconds_to_hms(HOUR_MS), "00001:00:00.000");
+        assert_eq!(
+            format_milliseconds_to_hms(100_000 * HOUR_MS - 1),
+            "99999:59:59.999"
+        );
+    }
+
+    #[test]
+    fn splits_out_days_past_the_hour_field() {
+        assert_eq!(
+            format_milliseconds_to_hms(100_000 * HOUR_MS),
+            "4166d 16:00:00.000"
+        );
+        assert_eq!(
+            format_milliseconds_to_hms(u64::MAX),
+            "213503982334d 14:25:51.615"
+        );
+    }
+}
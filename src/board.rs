@@ -0,0 +1,154 @@
+//! Hardware configuration for the M5Stamp-S3 board: pin assignment and drive settings.
+//!
+//! Bringing up a different board revision should only require changes to this module.
+use esp_hal::gpio;
+use heater_core::ssr::SsrOutput;
+
+/// Drive settings for an output pin.
+pub struct OutputDrive {
+    pub strength: gpio::DriveStrength,
+    pub mode: gpio::DriveMode,
+    pub pull: gpio::Pull,
+}
+
+impl OutputDrive {
+    pub fn config(&self) -> gpio::OutputConfig {
+        gpio::OutputConfig::default()
+            .with_drive_strength(self.strength)
+            .with_drive_mode(self.mode)
+            .with_pull(self.pull)
+    }
+}
+
+/// Drive settings for the pin controlling the SSR MOSFET.
+pub const SSR_DRIVE: OutputDrive = OutputDrive {
+    strength: gpio::DriveStrength::_5mA,
+    mode: gpio::DriveMode::PushPull,
+    pull: gpio::Pull::None,
+};
+
+/// Whether the SSR driver turns the heater on with a low level on its control pin, as on driver
+/// boards that invert the gate signal.
+pub const SSR_ACTIVE_LOW: bool = false;
+
+/// The level to drive the SSR control pin at, for the SSR to be on or off.
+pub fn ssr_level(on: bool) -> gpio::Level {
+    gpio::Level::from(on != SSR_ACTIVE_LOW)
+}
+
+/// A pin controlling an SSR, driven at `ssr_level`.
+pub struct SsrPin(pub gpio::Output<'static>);
+
+impl SsrOutput for SsrPin {
+    fn set_on(&mut self, on: bool) {
+        self.0.set_level(ssr_level(on));
+    }
+}
+
+/// The number of SSR outputs beyond the main heater's, for boards driving more heating zones.
+/// Zone 0 is the main heater on `control_ssr`, and extra zones are numbered from 1.
+pub const SSR_EXTRA_ZONES: usize = 0;
+
+/// The number of spare pins read as generic inputs, for add-on boards.
+pub const EXPANSION_PIN_COUNT: usize = 2;
+
+/// The board pins, as assigned by `take_pins!`.
+// Not all pins are in use yet.
+#[allow(dead_code)]
+pub struct BoardPins {
+    /// Unused pins, taken here so they aren't used accidentally.
+    pub unused: [gpio::AnyPin<'static>; 3],
+    /// Controls the solid state relay (SSR) through a MOSFET.
+    pub control_ssr: gpio::AnyPin<'static>,
+    /// Control the SSRs of the extra heating zones, driven like `control_ssr`.
+    pub control_ssr_zones: [gpio::AnyPin<'static>; SSR_EXTRA_ZONES],
+    /// Reads back the SSR control level, on boards that have a feedback line for it. Expected to
+    /// follow the level on `control_ssr`, so the SSR can be latched off if it gets stuck.
+    pub ssr_feedback: Option<gpio::AnyPin<'static>>,
+    /// A hardware interlock, such as a thermal fuse or a door switch, that closes the line to GND.
+    /// The SSR is latched off while it is open.
+    pub interlock: Option<gpio::AnyPin<'static>>,
+    /// Reads the case button, which pulls the line to GND when pressed.
+    pub button: gpio::AnyPin<'static>,
+    /// The 1Wire bus commanding the DS18B20 temperature sensors, which are phantom-powered. The
+    /// primary and cutoff sensors share it, addressed by ROM.
+    pub sensor_temp: gpio::AnyPin<'static>,
+    /// Goes to the nMOS gate that switches 12VDC power on to the case fan.
+    pub power_fan: gpio::AnyPin<'static>,
+    /// Powers the case button LED.
+    pub button_led: gpio::AnyPin<'static>,
+    /// Spare pins for add-on boards, such as limit switches or a door sensor.
+    /// Monitored as generic inputs, pulled up.
+    pub expansion: [gpio::AnyPin<'static>; EXPANSION_PIN_COUNT],
+    pub uart_tx: gpio::AnyPin<'static>,
+    pub uart_rx: gpio::AnyPin<'static>,
+}
+
+/// Takes the board pins out of the peripherals.
+///
+/// This is the one place where GPIO numbers are assigned to functions.
+macro_rules! take_pins {
+    ($peripherals:ident) => {
+        $crate::board::BoardPins {
+            unused: [
+                $peripherals.GPIO0.into(),
+                $peripherals.GPIO3.into(),
+                $peripherals.GPIO13.into(),
+            ],
+            control_ssr: $peripherals.GPIO1.into(),
+            // A single zone. Assign unused pins here, one per `SSR_EXTRA_ZONES`, to add more.
+            control_ssr_zones: [],
+            // This board has no feedback line. Assign one of the unused pins to enable the check.
+            ssr_feedback: None,
+            // This board has no interlock wired. Assign one of the unused pins to enable it.
+            interlock: None,
+            button: $peripherals.GPIO5.into(),
+            sensor_temp: $peripherals.GPIO7.into(),
+            power_fan: $peripherals.GPIO9.into(),
+            button_led: $peripherals.GPIO15.into(),
+            expansion: [$peripherals.GPIO2.into(), $peripherals.GPIO4.into()],
+            uart_tx: $peripherals.GPIO43.into(),
+            uart_rx: $peripherals.GPIO44.into(),
+        }
+    };
+}
+pub(crate) use take_pins;
+
+/// Whether the chip last reset on a brown-out, after the supply voltage sagged.
+///
+/// The brown-out detector is configured by the second-stage bootloader, which enables it at its
+/// default threshold.
+pub fn brownout_reset() -> bool {
+    use esp_hal::rtc_cntl::SocResetReason;
+
+    matches!(
+        esp_hal::system::reset_reason(),
+        Some(SocResetReason::SysBrownOut)
+    )
+}
+
+/// Why the chip last reset, in broad terms.
+pub fn reset_reason() -> &'static str {
+    use esp_hal::rtc_cntl::SocResetReason;
+
+    match esp_hal::system::reset_reason() {
+        Some(SocResetReason::ChipPowerOn) => "power-on",
+        Some(SocResetReason::CoreSw | SocResetReason::CpuSw) => "software",
+        Some(
+            SocResetReason::CoreMwdt0
+            | SocResetReason::CoreMwdt1
+            | SocResetReason::CoreRtcWdt
+            | SocResetReason::CpuMwdt0
+            | SocResetReason::CpuMwdt1
+            | SocResetReason::CpuRtcWdt
+            | SocResetReason::SysRtcWdt
+            | SocResetReason::SysSuperWdt,
+        ) => "watchdog",
+        Some(SocResetReason::SysBrownOut) => "brownout",
+        Some(SocResetReason::SysClkGlitch | SocResetReason::CorePwrGlitch) => "glitch",
+        Some(SocResetReason::CoreDeepSleep) => "deep sleep",
+        Some(SocResetReason::CoreUsbUart | SocResetReason::CoreUsbJtag) => "usb",
+        Some(SocResetReason::CoreEfuseCrc) => "efuse",
+        None => "unknown",
+    }
+}
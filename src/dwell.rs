@@ -0,0 +1,50 @@
+//! Minimum dwell times for switched loads, so that they don't toggle rapidly near a threshold.
+use embassy_time::{Duration, Instant};
+
+/// A protective state that, once engaged, is held for at least a minimum dwell time.
+///
+/// Engaging is always immediate. Releasing is refused until the dwell time has passed since the
+/// state was engaged, independently of any hysteresis applied by the caller.
+pub struct Dwell {
+    engaged_at: Option<Instant>,
+    min_dwell: Duration,
+}
+
+impl Dwell {
+    pub fn new(min_dwell: Duration) -> Self {
+        Dwell {
+            engaged_at: None,
+            min_dwell,
+        }
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged_at.is_some()
+    }
+
+    /// Engages the state. Returns whether it was newly engaged.
+    pub fn engage(&mut self) -> bool {
+        if self.engaged_at.is_some() {
+            return false;
+        }
+        self.engaged_at = Some(Instant::now());
+        true
+    }
+
+    /// Releases the state immediately, regardless of the dwell time. For when the protected
+    /// load was released by other means, e.g. by an operator.
+    pub fn clear(&mut self) {
+        self.engaged_at = None;
+    }
+
+    /// Releases the state, once the dwell time has passed. Returns whether it was released.
+    pub fn release(&mut self) -> bool {
+        match self.engaged_at {
+            Some(engaged_at) if Instant::now() >= engaged_at + self.min_dwell => {
+                self.engaged_at = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
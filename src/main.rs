@@ -5,6 +5,7 @@
 
 extern crate alloc;
 
+use alloc::format;
 use embassy_executor::{SpawnError, Spawner};
 use esp_backtrace as _;
 use esp_hal::clock::CpuClock;
@@ -12,10 +13,14 @@ use esp_hal::gpio;
 use esp_hal::timer::systimer::SystemTimer;
 use esp_hal::timer::timg::TimerGroup;
 
+mod board;
 mod config;
+mod dwell;
 mod futures;
 mod memlog;
+mod settings;
 mod state;
+mod stats;
 mod task;
 
 esp_bootloader_esp_idf::esp_app_desc!();
@@ -32,34 +37,66 @@ async fn main(spawner: Spawner) {
     let rng = esp_hal::rng::Rng::new(peripherals.RNG);
     let timer1 = TimerGroup::new(peripherals.TIMG0);
 
-    //
-    // M5Stamp-S3 pinout
-    //
-    // Unused pins, taken here so they aren't used accidentally.
-    let _pin8_unused = peripherals.GPIO0;
-    let _pin8_unused = peripherals.GPIO3;
-    let _pin9_unused = peripherals.GPIO13;
-    // G1 controls the solid state relay (SSR) through a MOSFET.
-    let output_5ma = gpio::OutputConfig::default()
-        .with_drive_strength(gpio::DriveStrength::_5mA)
-        .with_drive_mode(gpio::DriveMode::PushPull)
-        .with_pull(gpio::Pull::None);
-    let pin_control_ssr = gpio::Output::new(peripherals.GPIO1, gpio::Level::Low, output_5ma);
-    // G5 reads the case button, which pulls the line to GND when pressed.
-    let _pin_button = peripherals.GPIO5;
-    // G7 is the 1Wire bus commanding the DS18B20 temperature sensors, which are phantom-powered.
-    let pin_sensor_temp = peripherals.GPIO7;
-    // G9 goes to the nMOS gate that switches 12VDC power on to the case fan.
-    let _pin_power_fan = peripherals.GPIO9;
-    // G15 powers the case button LED.
-    let _pin_button_led = peripherals.GPIO15;
-    // UART pins.
-    let pin_uart_tx = peripherals.GPIO43;
-    let pin_uart_rx = peripherals.GPIO44;
+    // Take the board pins and set up the SSR control output.
+    let pins = board::take_pins!(peripherals);
+    let pin_control_ssr = board::SsrPin(gpio::Output::new(
+        pins.control_ssr,
+        board::ssr_level(false),
+        board::SSR_DRIVE.config(),
+    ));
+    let pins_control_ssr_zones = pins.control_ssr_zones.map(|pin| {
+        board::SsrPin(gpio::Output::new(
+            pin,
+            board::ssr_level(false),
+            board::SSR_DRIVE.config(),
+        ))
+    });
+    let pin_ssr_feedback = pins
+        .ssr_feedback
+        .map(|pin| gpio::Input::new(pin, gpio::InputConfig::default()));
 
     // Initialize an in-memory logger with space for 480 characters.
     let memlog = memlog::init(480);
     memlog.info("heater control initialized");
+    let reset_reason = board::reset_reason();
+    memlog.info(format!("reset reason: {reset_reason}"));
+
+    // Load persisted settings from flash.
+    let settings = settings::init(memlog);
+
+    // Holding the case button through boot enables every control interface again, so a device
+    // with all of them disabled can still be recovered. The button pulls the line to GND.
+    let mut pin_button = pins.button;
+    let button_held = gpio::Input::new(
+        pin_button.reborrow(),
+        gpio::InputConfig::default().with_pull(gpio::Pull::Up),
+    )
+    .is_low();
+    if button_held {
+        let mut settings = settings.lock().await;
+        settings.interfaces = settings::Interfaces::default();
+        match settings.save() {
+            Ok(()) => memlog.info("button held at boot, all interfaces enabled"),
+            Err(error) => memlog.warn(format!(
+                "button held at boot, all interfaces enabled but not saved: {error}"
+            )),
+        }
+    }
+    let interfaces = settings.lock().await.interfaces;
+
+    // A brown-out means the supply is marginal. Count it, and don't resume the heater on it.
+    let brownout = board::brownout_reset();
+    if brownout {
+        let mut settings = settings.lock().await;
+        settings.brownouts = settings.brownouts.saturating_add(1);
+        if let Err(error) = settings.save() {
+            memlog.warn(format!("brown-out count not saved: {error}"));
+        }
+        memlog.error(format!(
+            "brown-out reset ({} so far), heater left off",
+            settings.brownouts
+        ));
+    }
 
     // Set up the WiFi.
     let (wifi_controller, wifi_interfaces) =
@@ -67,25 +104,96 @@ async fn main(spawner: Spawner) {
             .await
             .unwrap();
 
+    // Lets the network monitor and the serial console ask for a WiFi reconnect.
+    let wifi_reconnect_signal = task::wifi::init_reconnect();
+
+    // Lets the serial console enable and disable the MQTT client at runtime.
+    let mqtt_toggle_signal = task::mqtt::init_toggle();
+
     // Set up the network stack.
     let (net_stack, net_runner) = task::net::init(wifi_interfaces.sta, rng).await;
 
     //
-    // Watcher count: 1 for serial console, 1 for mqtt
+    // Watcher count: 1 for serial console, 1 for mqtt,
+    // and for the temp sensor only, 1 for the thermostat and 1 for ssr control (derating).
 
-    // Get a watcher to await changes in temperature sensor readings.
-    let tempsensor_watch = task::temp_sensor::init::<3>();
+    // Get a watcher to await changes in temperature sensor readings, and one for soft alarms.
+    // Also get a signal to request a measurement on demand.
+    // Alarm watchers: mqtt.
+    let (tempsensor_watch, tempalarm_watch, tempsensor_measure_signal) =
+        task::temp_sensor::init::<4, 1>();
 
     // Get a watcher to monitor the network interface.
     let netstatus_watch = task::net_monitor::init::<3>();
 
-    // Get a watcher to notify the SSR controller of a new duty cycle.
-    // Command publishers: serial console, temp sensor.
+    // Get a watcher for heap usage samples. Watchers: serial console, mqtt.
+    let heapstatus_watch = task::heap_monitor::init::<2>();
+
+    // Get a watcher for the levels of the expansion inputs. Watchers: mqtt.
+    let gpioinput_watch = task::gpio_monitor::init::<1>();
+
+    // Get a watcher to notify the SSR controller of a new duty cycle,
+    // a relay for commands from tasks that can't wait on the command channel,
+    // a rate limiter for duty writes from the control interfaces,
+    // a watcher for the SSR lock and fault status, one for the applied firing pattern,
+    // one for alerts raised when a safety mechanism overrides the commanded duty,
+    // and one for the duty of the extra zones.
+    // Command publishers: serial console, temp sensor, temp cutoff, interlock, command relay.
     // Command subscribers: ssr control, mqtt client.
-    let (ssrcontrol_duty_watch, ssrcontrol_command_pubsub) = task::ssr_control::init::<3, 2, 2>();
+    // Status watchers: serial console, mqtt client.
+    // Pattern watchers: serial console.
+    // Alert watchers: mqtt client.
+    // Zone watchers: ssr control, serial console.
+    let (
+        ssrcontrol_duty_watch,
+        ssrcontrol_command_pubsub,
+        ssrcontrol_command_relay,
+        ssrcontrol_duty_limiter,
+        ssrcontrol_status_watch,
+        ssrcontrol_pattern_watch,
+        ssrcontrol_alert_watch,
+        ssrcontrol_zone_watch,
+    ) = task::ssr_control::init::<3, 2, 5, 2, 1, 1, 2>(memlog);
 
-    // Allocate a shared heater state.
+    // Allocate a shared heater state, and resume the one from before a reboot if enabled.
+    // After a brown-out the heater stays off, as the supply may sag again under load.
     let state = state::init();
+    if !brownout {
+        state::resume(ssrcontrol_duty_watch.dyn_sender(), memlog, state, settings).await;
+    }
+
+    // Allocate diagnostic counters.
+    let stats = stats::init();
+
+    // Report the claimed watcher and subscriber slots, to catch a consumer about to run out.
+    stats.register_slots("temp watchers", || stats::watch_slots(tempsensor_watch));
+    stats.register_slots("temp alarm watchers", || {
+        stats::watch_slots(tempalarm_watch)
+    });
+    stats.register_slots("net watchers", || stats::watch_slots(netstatus_watch));
+    stats.register_slots("heap watchers", || stats::watch_slots(heapstatus_watch));
+    stats.register_slots("gpio watchers", || stats::watch_slots(gpioinput_watch));
+    stats.register_slots("duty watchers", || {
+        stats::watch_slots(ssrcontrol_duty_watch)
+    });
+    stats.register_slots("ssr command subscribers", || {
+        stats::subscriber_slots(ssrcontrol_command_pubsub)
+    });
+    stats.register_slots("ssr command publishers", || {
+        stats::publisher_slots(ssrcontrol_command_pubsub)
+    });
+    stats.register_slots("ssr status watchers", || {
+        stats::watch_slots(ssrcontrol_status_watch)
+    });
+    stats.register_slots("ssr pattern watchers", || {
+        stats::watch_slots(ssrcontrol_pattern_watch)
+    });
+    stats.register_slots("ssr alert watchers", || {
+        stats::watch_slots(ssrcontrol_alert_watch)
+    });
+    stats.register_slots("ssr zone watchers", || {
+        stats::watch_slots(ssrcontrol_zone_watch)
+    });
 
     //
     // Spawn tasks.
@@ -93,6 +201,8 @@ async fn main(spawner: Spawner) {
         // Keep the wifi connected.
         spawner.spawn(task::wifi::wifi_permanent_connection(
             wifi_controller,
+            wifi_reconnect_signal,
+            rng,
             memlog,
         ))?;
 
@@ -100,53 +210,155 @@ async fn main(spawner: Spawner) {
         spawner.spawn(task::net::stack_runner(net_runner))?;
 
         // Monitor the network stack for changes.
-        spawner.spawn(task::net_monitor(net_stack, netstatus_watch.dyn_sender()))?;
+        spawner.spawn(task::net_monitor(
+            net_stack,
+            netstatus_watch.dyn_sender(),
+            wifi_reconnect_signal,
+            memlog,
+            stats,
+        ))?;
+
+        // Sample the heap usage periodically.
+        spawner.spawn(task::heap_monitor(heapstatus_watch.dyn_sender(), memlog))?;
+
+        // Watch the expansion pins for level changes.
+        spawner.spawn(task::gpio_monitor(
+            pins.expansion,
+            gpioinput_watch.dyn_sender(),
+            memlog,
+        ))?;
 
         // Control the SSR duty cycle.
         spawner.spawn(task::ssr_control::ssr_control(
             pin_control_ssr,
+            pin_ssr_feedback,
+            pins_control_ssr_zones,
             ssrcontrol_duty_watch.dyn_receiver().unwrap(),
+            ssrcontrol_zone_watch.dyn_receiver().unwrap(),
             ssrcontrol_command_pubsub.dyn_subscriber().unwrap(),
+            ssrcontrol_status_watch.dyn_sender(),
+            ssrcontrol_pattern_watch.dyn_sender(),
+            ssrcontrol_alert_watch.dyn_sender(),
+            tempsensor_watch.dyn_receiver().unwrap(),
+            memlog,
+            state,
+            stats,
+        ))?;
+
+        // Apply rate-limited duty writes from the control interfaces.
+        spawner.spawn(task::ssr_control::duty_limiter(
+            ssrcontrol_duty_limiter,
+            ssrcontrol_duty_watch.dyn_sender(),
+        ))?;
+
+        // Publish the commands from the mqtt client.
+        spawner.spawn(task::ssr_control::command_relay(
+            ssrcontrol_command_relay,
+            ssrcontrol_command_pubsub.dyn_publisher().unwrap(),
         ))?;
 
         // Take a temperature measurement periodically.
+        let tempsensor_bus = task::temp_sensor::init_bus(pins.sensor_temp);
         spawner.spawn(task::temp_sensor(
-            pin_sensor_temp.into(),
+            tempsensor_bus,
             tempsensor_watch.dyn_sender(),
+            tempalarm_watch.dyn_sender(),
+            tempsensor_measure_signal,
             ssrcontrol_command_pubsub.dyn_publisher().unwrap(),
+            memlog,
+            state,
+            settings,
+            stats,
         ))?;
 
-        // Shut the heater off if a remote fails to check in.
-        spawner.spawn(state::expire_remote(
+        // Latch the SSR off if the independent cutoff sensor exceeds its limit.
+        spawner.spawn(task::temp_sensor::temp_cutoff(
+            tempsensor_bus,
+            ssrcontrol_command_pubsub.dyn_publisher().unwrap(),
+            memlog,
+            state,
+        ))?;
+
+        // Latch the SSR off while the hardware interlock is open, on boards that have one.
+        if let Some(interlock_pin) = pins.interlock {
+            spawner.spawn(task::interlock(
+                interlock_pin,
+                ssrcontrol_command_pubsub.dyn_publisher().unwrap(),
+                memlog,
+            ))?;
+        }
+
+        // Drive the heater duty towards the setpoint when in thermostat mode.
+        spawner.spawn(task::thermostat(
+            tempsensor_watch.dyn_receiver().unwrap(),
             ssrcontrol_duty_watch.dyn_sender(),
             memlog,
             state,
+            stats,
         ))?;
 
-        // Launch a control interface on UART0.
-        spawner.spawn(task::serial_console(
-            peripherals.UART0.into(),
-            pin_uart_rx.into(),
-            pin_uart_tx.into(),
+        // Shut the heater off if a remote fails to check in.
+        spawner.spawn(state::expire_remote(
             ssrcontrol_duty_watch.dyn_sender(),
-            ssrcontrol_duty_watch.dyn_receiver().unwrap(),
-            ssrcontrol_command_pubsub.dyn_publisher().unwrap(),
-            netstatus_watch.dyn_receiver().unwrap(),
-            tempsensor_watch.dyn_receiver().unwrap(),
             memlog,
             state,
         ))?;
 
-        // Run the MQTT client.
+        // Save the heater state to resume after a reboot.
+        spawner.spawn(state::persist_state(memlog, state, settings))?;
+
+        // Launch a control interface on UART0, unless disabled.
+        if interfaces.serial {
+            spawner.spawn(task::serial_console(
+                peripherals.UART0.into(),
+                pins.uart_rx,
+                pins.uart_tx,
+                pin_button,
+                net_stack,
+                wifi_reconnect_signal,
+                mqtt_toggle_signal,
+                ssrcontrol_duty_limiter,
+                ssrcontrol_duty_watch.dyn_sender(),
+                ssrcontrol_duty_watch.dyn_receiver().unwrap(),
+                ssrcontrol_zone_watch.dyn_sender(),
+                ssrcontrol_zone_watch.dyn_receiver().unwrap(),
+                ssrcontrol_command_pubsub.dyn_publisher().unwrap(),
+                ssrcontrol_status_watch.dyn_receiver().unwrap(),
+                ssrcontrol_pattern_watch.dyn_receiver().unwrap(),
+                heapstatus_watch.dyn_receiver().unwrap(),
+                netstatus_watch.dyn_receiver().unwrap(),
+                tempsensor_watch.dyn_receiver().unwrap(),
+                tempsensor_measure_signal,
+                memlog,
+                state,
+                settings,
+                stats,
+            ))?;
+        } else {
+            memlog.info("serial console disabled");
+        }
+
+        // Run the MQTT client. It waits while disabled, so it can be enabled at runtime.
         spawner.spawn(task::mqtt::run(
             net_stack,
-            ssrcontrol_duty_watch.dyn_sender(),
+            reset_reason,
+            mqtt_toggle_signal,
+            ssrcontrol_duty_limiter,
             ssrcontrol_duty_watch.dyn_receiver().unwrap(),
+            ssrcontrol_zone_watch.dyn_sender(),
             netstatus_watch.dyn_receiver().unwrap(),
             tempsensor_watch.dyn_receiver().unwrap(),
+            tempalarm_watch.dyn_receiver().unwrap(),
             ssrcontrol_command_pubsub.dyn_subscriber().unwrap(),
+            ssrcontrol_command_relay,
+            ssrcontrol_status_watch.dyn_receiver().unwrap(),
+            ssrcontrol_alert_watch.dyn_receiver().unwrap(),
+            heapstatus_watch.dyn_receiver().unwrap(),
+            gpioinput_watch.dyn_receiver().unwrap(),
             memlog,
             state,
+            settings,
+            stats,
         ))?;
 
         Ok(())
@@ -1,13 +1,24 @@
 //! An in-memory log storage, with a fixed size for records.
 #![allow(dead_code)]
 
-use alloc::{boxed::Box, collections::vec_deque::VecDeque, format, string::String};
-use core::{cell::RefCell, fmt::Display};
-use embassy_sync::{blocking_mutex::raw::NoopRawMutex, watch};
+use crate::task::heap_monitor;
+use alloc::{boxed::Box, collections::vec_deque::VecDeque, format, string::String, vec};
+use core::{cell::RefCell, fmt::Display, str::FromStr};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel, watch};
 use embassy_time::Instant;
+use serde::{Serialize, Serializer, ser::SerializeStruct};
+use thiserror::Error;
 
-const MEMLOG_WATCHERS: usize = 2;
+pub use heater_core::time::format_milliseconds_to_hms;
+
+// Watchers: serial console.
+const MEMLOG_WATCHERS: usize = 1;
+// Records held for the queue's receiver (the mqtt log forwarder) before new ones are dropped.
+// Sized for a burst of errors while a publish is in flight.
+const MEMLOG_QUEUE_LEN: usize = 16;
 const DISCARD_ERROR: &str = "log discarded: too large for storage";
+// Bounds the storage capacity set at runtime, to keep the log from exhausting the heap.
+const MEMLOG_MAX_CAPACITY: usize = 8 * 1024;
 
 #[derive(Clone, Copy)]
 pub struct SharedLogger {
@@ -15,6 +26,7 @@ pub struct SharedLogger {
 }
 
 pub type LogDynReceiver = watch::DynReceiver<'static, Record>;
+pub type LogQueueReceiver = channel::DynamicReceiver<'static, Record>;
 
 pub fn init(capacity: usize) -> SharedLogger {
     // Ensure we have enough space to store the error about not having enough space.
@@ -37,8 +49,45 @@ struct LogStorage {
     print: bool,
     // If set, broadcasts new records over the watch channel.
     watch: Option<&'static watch::Watch<NoopRawMutex, Record, MEMLOG_WATCHERS>>,
+    // If set, queues new records at or above the given level, in order. Unlike the watch, a
+    // burst isn't coalesced to the latest record, but records are dropped once the queue is full.
+    queue: Option<(
+        &'static channel::Channel<NoopRawMutex, Record, MEMLOG_QUEUE_LEN>,
+        Level,
+    )>,
+    // Records below this level are counted, but otherwise dropped.
+    min_level: Level,
+    // How records are formatted when printed or read out.
+    format: LogFormat,
+    // Number of records seen at each level, including dropped ones.
+    counts: [u32; Level::COUNT],
+    // Number of records broadcast over the watch channel.
+    watch_sent: u32,
+    // Number of records queued, and dropped because the queue was full.
+    queue_sent: u32,
+    queue_dropped: u32,
+    // Number of records dropped to make room for newer ones.
+    evicted: u32,
+    // Number of records stored since boot. Doubles as the sequence number of the next record.
+    stored: u64,
+}
+
+/// A snapshot of the log storage usage.
+#[derive(Clone, Copy, Debug)]
+pub struct LogUsage {
+    /// In characters.
+    pub capacity: usize,
+    /// In characters.
+    pub utilization: usize,
+    pub records: usize,
+    /// Records dropped to make room for newer ones, since boot.
+    pub evicted: u32,
 }
 
+#[derive(Clone, Copy, Debug, Error)]
+#[error("log capacity must be between {} and {}", DISCARD_ERROR.len(), MEMLOG_MAX_CAPACITY)]
+pub struct InvalidCapacity;
+
 #[derive(Clone, Debug)]
 pub struct Record {
     pub instant: Instant,
@@ -53,15 +102,132 @@ impl Display for Record {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+impl Record {
+    /// The subsystem that logged this record, by the convention of prefixing the text with it,
+    /// as in `"mqtt: connected"`. Returns the source and the remaining text.
+    fn source(&self) -> (Option<&str>, &str) {
+        match self.text.split_once(": ") {
+            Some((source, text)) => (Some(source), text),
+            None => (None, &self.text),
+        }
+    }
+
+    /// Formats this record as a single line, without a line terminator.
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Text => format!("{self}"),
+            LogFormat::Json => self.to_json(),
+        }
+    }
+
+    /// Serializes this record as a JSON object.
+    pub fn to_json(&self) -> String {
+        // Sized for the worst case, where every character is escaped as `\uXXXX`.
+        let mut buffer = vec![0u8; self.text.len() * 6 + 64];
+        match serde_json_core::to_slice(self, &mut buffer) {
+            Ok(length) => String::from_utf8_lossy(&buffer[..length]).into_owned(),
+            // Can't happen with the buffer sized as above.
+            Err(_) => String::from(r#"{"error":"log record too large"}"#),
+        }
+    }
+}
+
+/// Serializes as `{"ts":1234,"level":"info","source":"mqtt","text":"connected"}`.
+///
+/// The timestamp is in milliseconds since boot. The source is null if the text has no prefix.
+impl Serialize for Record {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (source, text) = self.source();
+        let mut state = serializer.serialize_struct("Record", 4)?;
+        state.serialize_field("ts", &self.instant.as_millis())?;
+        state.serialize_field("level", self.level.name())?;
+        state.serialize_field("source", &source)?;
+        state.serialize_field("text", text)?;
+        state.end()
+    }
+}
+
+/// How log records are written out, as text for people or JSON for machines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per record.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = InvalidFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(InvalidFormat),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Error)]
+#[error("log format must be one of text, json")]
+pub struct InvalidFormat;
+
+/// Ordered by severity, `Trace` being the lowest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Level {
-    Trace,
-    Debug,
-    Info,
-    Warn,
-    Error,
+    Trace = 0,
+    Debug = 1,
+    Info = 2,
+    Warn = 3,
+    Error = 4,
+}
+
+impl Level {
+    const COUNT: usize = 5;
+    pub const ALL: [Level; Level::COUNT] = [
+        Level::Trace,
+        Level::Debug,
+        Level::Info,
+        Level::Warn,
+        Level::Error,
+    ];
+
+    /// Numeric severity, from 0 (trace) to 4 (error).
+    pub fn priority(&self) -> u8 {
+        *self as u8
+    }
+
+    /// The name accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = InvalidLevel;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trace" => Ok(Level::Trace),
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            _ => Err(InvalidLevel),
+        }
+    }
 }
 
+#[derive(Clone, Copy, Debug, Error)]
+#[error("log level must be one of trace, debug, info, warn, error")]
+pub struct InvalidLevel;
+
 impl Display for Level {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
@@ -82,10 +248,38 @@ impl LogStorage {
             capacity,
             print: false,
             watch: None,
+            queue: None,
+            min_level: Level::Trace,
+            format: LogFormat::Text,
+            counts: [0; Level::COUNT],
+            watch_sent: 0,
+            queue_sent: 0,
+            queue_dropped: 0,
+            evicted: 0,
+            stored: 0,
+        }
+    }
+
+    /// Removes the oldest records until at most `utilization` characters are in use.
+    fn evict_to(&mut self, utilization: usize) {
+        while self.utilization > utilization {
+            let removed = self.records.pop_back().unwrap();
+            self.utilization -= removed.text.len();
+            self.evicted = self.evicted.saturating_add(1);
         }
     }
 
     fn add_record(&mut self, level: Level, text: impl Into<String>) {
+        let count = &mut self.counts[level.priority() as usize];
+        *count = count.saturating_add(1);
+        if level < self.min_level {
+            return;
+        }
+        // Stored records hold on to heap, so keep only warnings and errors while it's low.
+        if level < Level::Warn && heap_monitor::is_degraded() {
+            return;
+        }
+
         let text: String = text.into();
 
         // Can't fit this record in storage. Log a warning.
@@ -98,10 +292,7 @@ impl LogStorage {
         // records need to be removed), so we can safely use unwraps.
 
         // Pop existing records until we have enough space for the new record.
-        while (self.capacity - self.utilization) < text.len() {
-            let removed = self.records.pop_back().unwrap();
-            self.utilization -= removed.text.len();
-        }
+        self.evict_to(self.capacity - text.len());
 
         self.utilization += text.len();
 
@@ -113,22 +304,59 @@ impl LogStorage {
 
         // If log printing is enabled, print this record.
         if self.print {
-            esp_println::println!("{new_record}");
+            esp_println::println!("{}", new_record.format(self.format));
         }
 
         // If log watching is enabled, share this record.
         if let Some(watch) = self.watch {
             watch.sender().send(new_record.clone());
+            self.watch_sent = self.watch_sent.saturating_add(1);
+        }
+
+        // If log queueing is enabled, queue this record, or count it as dropped.
+        if let Some((queue, queue_level)) = self.queue {
+            if new_record.level >= queue_level {
+                match queue.try_send(new_record.clone()) {
+                    Ok(()) => self.queue_sent = self.queue_sent.saturating_add(1),
+                    Err(_) => self.queue_dropped = self.queue_dropped.saturating_add(1),
+                }
+            }
         }
 
         // Store the new record.
         self.records.push_front(new_record);
+        self.stored += 1;
+    }
+
+    /// Returns the oldest stored record numbered `seq` or later, along with its number.
+    fn record_from(&self, seq: u64) -> Option<(u64, Record)> {
+        // Records are numbered in the order they were stored, the oldest being at the back.
+        let oldest = self.stored - self.records.len() as u64;
+        let seq = seq.max(oldest);
+        let index = self
+            .records
+            .len()
+            .checked_sub((seq - oldest) as usize + 1)?;
+        Some((seq, self.records[index].clone()))
     }
 
     fn clear(&mut self) {
         self.utilization = 0;
         self.records.clear();
     }
+
+    /// Changes the capacity, dropping the oldest records that no longer fit.
+    fn set_capacity(&mut self, capacity: usize) -> Result<(), InvalidCapacity> {
+        if !(DISCARD_ERROR.len()..=MEMLOG_MAX_CAPACITY).contains(&capacity) {
+            return Err(InvalidCapacity);
+        }
+
+        // Shrink the utilization to fit, then release the memory the evicted records held.
+        self.evict_to(capacity);
+        self.capacity = capacity;
+        self.records.shrink_to_fit();
+        Ok(())
+    }
 }
 
 impl SharedLogger {
@@ -153,6 +381,27 @@ impl SharedLogger {
             .map(|watch| watch.dyn_receiver())?
     }
 
+    /// Queues new records at or above `level` for a single receiver, in order.
+    ///
+    /// Meant for forwarding, where the watch would coalesce a burst to its latest record.
+    /// Records that arrive while the queue is full are dropped, see `queue_dropped`.
+    pub fn enable_queue(&self, level: Level) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.queue.is_none() {
+            inner.queue = Some((Box::leak(Box::new(channel::Channel::new())), level));
+        }
+    }
+
+    // Get the receiver for queued logs.
+    //
+    // Returns None if log queueing is not enabled.
+    pub fn queue(&self) -> Option<LogQueueReceiver> {
+        self.inner
+            .borrow()
+            .queue
+            .map(|(queue, _level)| queue.dyn_receiver())
+    }
+
     pub fn trace(&self, text: impl Into<String>) {
         self.inner.borrow_mut().add_record(Level::Trace, text);
     }
@@ -171,25 +420,61 @@ impl SharedLogger {
     pub fn clear(&self) {
         self.inner.borrow_mut().clear();
     }
+    /// Drops new records below `level`. They are still counted.
+    pub fn set_level(&self, level: Level) {
+        self.inner.borrow_mut().min_level = level;
+    }
+    pub fn level(&self) -> Level {
+        self.inner.borrow().min_level
+    }
+    /// Sets how records are printed and read out. Stored records are unaffected.
+    pub fn set_format(&self, format: LogFormat) {
+        self.inner.borrow_mut().format = format;
+    }
+    pub fn format(&self) -> LogFormat {
+        self.inner.borrow().format
+    }
+    /// Returns the number of records seen at each level, in the order of `Level::ALL`.
+    pub fn counts(&self) -> [u32; Level::COUNT] {
+        self.inner.borrow().counts
+    }
+    /// Returns the number of records broadcast to watchers.
+    pub fn watch_sent(&self) -> u32 {
+        self.inner.borrow().watch_sent
+    }
+    /// Returns the number of records queued for the queue's receiver.
+    pub fn queue_sent(&self) -> u32 {
+        self.inner.borrow().queue_sent
+    }
+    /// Returns the number of records dropped because the queue was full.
+    pub fn queue_dropped(&self) -> u32 {
+        self.inner.borrow().queue_dropped
+    }
+    pub fn usage(&self) -> LogUsage {
+        let inner = self.inner.borrow();
+        LogUsage {
+            capacity: inner.capacity,
+            utilization: inner.utilization,
+            records: inner.records.len(),
+            evicted: inner.evicted,
+        }
+    }
+    /// Changes the storage capacity, in characters. Drops the oldest records that don't fit.
+    pub fn set_capacity(&self, capacity: usize) -> Result<(), InvalidCapacity> {
+        self.inner.borrow_mut().set_capacity(capacity)
+    }
+    /// Returns the number of records stored since boot, which numbers the next record.
+    pub fn stored(&self) -> u64 {
+        self.inner.borrow().stored
+    }
+    /// Returns a copy of the oldest record numbered `seq` or later, along with its number.
+    ///
+    /// Records are numbered from zero at boot. Unlike `records`, this doesn't hold a borrow on
+    /// the storage, so it can be used to read the log across awaits.
+    pub fn record_from(&self, seq: u64) -> Option<(u64, Record)> {
+        self.inner.borrow().record_from(seq)
+    }
     pub fn records(&self) -> core::cell::Ref<'_, VecDeque<Record>> {
         core::cell::Ref::map(self.inner.borrow(), |storage| &storage.records)
     }
 }
-
-/// Formats a u64 millisecond value into "HHHHH:MM:SS.xxx" string.
-#[inline]
-pub fn format_milliseconds_to_hms(total_ms: u64) -> String {
-    let millis_part = total_ms % 1000;
-    let total_seconds = total_ms / 1000;
-
-    let seconds_part = total_seconds % 60;
-    let total_minutes = total_seconds / 60;
-
-    let minutes_part = total_minutes % 60;
-    let hours_part = total_minutes / 60;
-
-    format!(
-        "{:05}:{:02}:{:02}.{:03}",
-        hours_part, minutes_part, seconds_part, millis_part
-    )
-}
@@ -0,0 +1,526 @@
+//! Runtime settings, persisted to flash.
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use core::str::FromStr;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::FlashStorage;
+use mountain_mqtt::packets::packet::KEEP_ALIVE_DEFAULT;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{memlog::SharedLogger, state::SavedState};
+
+// Settings are stored at the start of the `nvs` partition of the default partition table,
+// which is otherwise unused by this firmware.
+const SETTINGS_FLASH_OFFSET: u32 = 0x9000;
+// Marks a valid settings record, followed by the payload length (u16, little-endian).
+const SETTINGS_MAGIC: [u8; 4] = *b"HCS1";
+const SETTINGS_HEADER_SIZE: usize = SETTINGS_MAGIC.len() + 2;
+// Maximum size of the serialized settings payload, in bytes.
+const SETTINGS_MAX_SIZE: usize = 256;
+
+// The serial console history is stored in the flash sector after the settings, so that saving
+// one doesn't rewrite the other. The record is laid out as for the settings, with its own magic.
+const HISTORY_FLASH_OFFSET: u32 = SETTINGS_FLASH_OFFSET + 0x1000;
+const HISTORY_MAGIC: [u8; 4] = *b"HCH1";
+// Maximum size of the stored history, in bytes. The oldest commands that don't fit are dropped.
+const HISTORY_MAX_SIZE: usize = 512;
+
+// Marks an exported settings blob, which is hex-encoded and ends with a Fletcher-16 checksum.
+const SETTINGS_EXPORT_MAGIC: [u8; 4] = *b"HCX1";
+
+pub type SharedSettings = &'static Mutex<NoopRawMutex, Settings>;
+
+// Minimum gap between the over-temperature limits, to prevent the lock from chattering.
+const TEMP_LIMITS_MIN_GAP: f32 = 5.0;
+// Bounds for the temperature calibration, to catch typos that would defeat the safety limits.
+const TEMP_CALIBRATION_MAX_OFFSET: f32 = 10.0;
+const TEMP_CALIBRATION_SCALE_RANGE: core::ops::RangeInclusive<f32> = 0.8..=1.2;
+// Maximum length of the device name, in bytes.
+const DEVICE_NAME_MAX_LEN: usize = 32;
+// Bounds for the MQTT ping interval, in seconds. The broker disconnects a client that stays quiet
+// for longer than its keepalive, which mountain-mqtt always sends as its default. The maximum
+// leaves a quarter of the keepalive for the ping to reach the broker.
+const MQTT_PING_INTERVAL_MIN: u16 = 5;
+const MQTT_PING_INTERVAL_MAX: u16 = MQTT_KEEP_ALIVE_SECS * 3 / 4;
+pub const MQTT_KEEP_ALIVE_SECS: u16 = KEEP_ALIVE_DEFAULT;
+
+/// Note: the settings are stored with a non-self-describing format, so changing the layout
+/// of this struct invalidates any stored settings, which then revert to their defaults.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Settings {
+    /// The unit used to display temperatures. Control logic always works in Celsius.
+    pub temp_unit: TempUnit,
+    /// Hysteresis range for locking and unlocking the SSR on over-temperature.
+    pub temp_limits: TempLimits,
+    /// Correction applied to readings from the primary temperature sensor.
+    pub temp_calibration: TempCalibration,
+    /// Soft alarm thresholds, which notify but don't act on the SSR.
+    pub temp_alarms: TempAlarms,
+    /// Overrides the compile-time MQTT device name and client ID, if set.
+    pub device_name: Option<heapless::String<DEVICE_NAME_MAX_LEN>>,
+    /// How often to ping the MQTT broker.
+    pub mqtt_ping_interval: MqttPingInterval,
+    /// The heater state to resume on boot, if enabled.
+    pub saved_state: SavedState,
+    /// The control interfaces to run.
+    pub interfaces: Interfaces,
+    /// Brown-out resets counted since the settings were created.
+    pub brownouts: u32,
+}
+
+/// Control interfaces that can be turned off, to harden a deployment.
+///
+/// MQTT can be toggled at runtime. The serial console is only checked at boot, and holding the
+/// case button through boot enables every interface again.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Interfaces {
+    pub mqtt: bool,
+    pub serial: bool,
+}
+
+impl Default for Interfaces {
+    fn default() -> Self {
+        Interfaces {
+            mqtt: true,
+            serial: true,
+        }
+    }
+}
+
+/// Over-temperature limits, in Celsius.
+///
+/// The SSR locks when the temperature reaches `high`, and unlocks when it drops below `low`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TempLimits {
+    low: f32,
+    high: f32,
+}
+
+impl Default for TempLimits {
+    fn default() -> Self {
+        TempLimits {
+            low: 30.0,
+            high: 70.0,
+        }
+    }
+}
+
+impl TempLimits {
+    /// Returns an error unless the limits are finite, and `low` is below `high` by at least
+    /// `TEMP_LIMITS_MIN_GAP`.
+    pub fn new(low: f32, high: f32) -> Result<Self, SettingsError> {
+        if !low.is_finite() || !high.is_finite() {
+            return Err(SettingsError::InvalidLimits);
+        }
+        if high - low >= TEMP_LIMITS_MIN_GAP {
+            Ok(TempLimits { low, high })
+        } else {
+            Err(SettingsError::LimitsTooClose)
+        }
+    }
+
+    pub fn low(&self) -> f32 {
+        self.low
+    }
+
+    pub fn high(&self) -> f32 {
+        self.high
+    }
+}
+
+/// Corrects readings from a temperature sensor, as `raw * scale + offset` in Celsius.
+///
+/// Applied before readings are used for control or published, so the limits and setpoint
+/// compare against calibrated temperatures.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TempCalibration {
+    offset: f32,
+    scale: f32,
+}
+
+impl Default for TempCalibration {
+    fn default() -> Self {
+        TempCalibration {
+            offset: 0.0,
+            scale: 1.0,
+        }
+    }
+}
+
+impl TempCalibration {
+    /// Returns an error unless the offset and scale are within sane bounds.
+    pub fn new(offset: f32, scale: f32) -> Result<Self, SettingsError> {
+        // Written so that NaN values are also rejected.
+        if offset.abs() <= TEMP_CALIBRATION_MAX_OFFSET
+            && TEMP_CALIBRATION_SCALE_RANGE.contains(&scale)
+        {
+            Ok(TempCalibration { offset, scale })
+        } else {
+            Err(SettingsError::InvalidCalibration)
+        }
+    }
+
+    pub fn apply(&self, raw: f32) -> f32 {
+        raw * self.scale + self.offset
+    }
+
+    pub fn offset(&self) -> f32 {
+        self.offset
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+}
+
+/// Soft alarm thresholds, in Celsius. Either can be disabled.
+///
+/// Independent of the over-temperature limits: crossing a threshold is only notified, and never
+/// changes the SSR.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct TempAlarms {
+    low: Option<f32>,
+    high: Option<f32>,
+}
+
+impl TempAlarms {
+    /// Returns an error unless the thresholds are finite, and `low` is below `high` if both are set.
+    pub fn new(low: Option<f32>, high: Option<f32>) -> Result<Self, SettingsError> {
+        let finite = low.is_none_or(f32::is_finite) && high.is_none_or(f32::is_finite);
+        let ordered = match (low, high) {
+            (Some(low), Some(high)) => low < high,
+            _ => true,
+        };
+        if finite && ordered {
+            Ok(TempAlarms { low, high })
+        } else {
+            Err(SettingsError::InvalidAlarms)
+        }
+    }
+
+    pub fn low(&self) -> Option<f32> {
+        self.low
+    }
+
+    pub fn high(&self) -> Option<f32> {
+        self.high
+    }
+}
+
+/// Interval between MQTT pings, in seconds. Defaults to half the keepalive.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct MqttPingInterval(u16);
+
+impl Default for MqttPingInterval {
+    fn default() -> Self {
+        MqttPingInterval(MQTT_KEEP_ALIVE_SECS / 2)
+    }
+}
+
+impl MqttPingInterval {
+    /// Returns an error unless the interval is well within the keepalive.
+    pub fn new(secs: u16) -> Result<Self, SettingsError> {
+        if (MQTT_PING_INTERVAL_MIN..=MQTT_PING_INTERVAL_MAX).contains(&secs) {
+            Ok(MqttPingInterval(secs))
+        } else {
+            Err(SettingsError::InvalidPingInterval)
+        }
+    }
+
+    /// Clamped, as settings saved before the maximum was lowered can hold a longer interval.
+    pub fn secs(&self) -> u16 {
+        self.0.min(MQTT_PING_INTERVAL_MAX)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TempUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+}
+
+impl TempUnit {
+    /// Converts a temperature in Celsius to this unit.
+    pub fn convert(self, celsius: f32) -> f32 {
+        match self {
+            TempUnit::Celsius => celsius,
+            TempUnit::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            TempUnit::Celsius => "°C",
+            TempUnit::Fahrenheit => "°F",
+        }
+    }
+}
+
+impl FromStr for TempUnit {
+    type Err = SettingsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "c" | "C" => Ok(TempUnit::Celsius),
+            "f" | "F" => Ok(TempUnit::Fahrenheit),
+            _ => Err(SettingsError::InvalidValue),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Error)]
+pub enum SettingsError {
+    #[error("invalid setting value")]
+    InvalidValue,
+    #[error("the limits must be finite numbers")]
+    InvalidLimits,
+    #[error(
+        "the low limit must be at least {}°C below the high limit",
+        TEMP_LIMITS_MIN_GAP
+    )]
+    LimitsTooClose,
+    #[error(
+        "the calibration offset must be within ±{}°C, and the scale within {} to {}",
+        TEMP_CALIBRATION_MAX_OFFSET,
+        TEMP_CALIBRATION_SCALE_RANGE.start(),
+        TEMP_CALIBRATION_SCALE_RANGE.end()
+    )]
+    InvalidCalibration,
+    #[error("the alarm thresholds must be numbers, with low below high")]
+    InvalidAlarms,
+    #[error(
+        "the device name must be 1 to {} characters of a-z, A-Z, 0-9, '-' or '_'",
+        DEVICE_NAME_MAX_LEN
+    )]
+    InvalidDeviceName,
+    #[error(
+        "the mqtt ping interval must be {} to {} seconds",
+        MQTT_PING_INTERVAL_MIN,
+        MQTT_PING_INTERVAL_MAX
+    )]
+    InvalidPingInterval,
+    #[error("failed to serialize settings")]
+    Serialize,
+    #[error("not a settings export")]
+    InvalidExport,
+    #[error("settings export checksum mismatch")]
+    ChecksumMismatch,
+    #[error("failed to write settings to flash")]
+    Flash,
+}
+
+/// Loads the settings from flash, falling back to defaults if none are stored.
+pub fn init(memlog: SharedLogger) -> SharedSettings {
+    let settings = match load() {
+        // Stored settings get the same checks as an import, as flash can hold older or
+        // corrupted values.
+        Some(settings) => match settings.validate() {
+            Ok(()) => {
+                memlog.info("settings restored from flash");
+                settings
+            }
+            Err(error) => {
+                memlog.warn(format!(
+                    "stored settings rejected ({error}), using defaults"
+                ));
+                Settings::default()
+            }
+        },
+        None => {
+            memlog.info("no stored settings, using defaults");
+            Settings::default()
+        }
+    };
+
+    Box::leak(Box::new(Mutex::new(settings)))
+}
+
+impl Settings {
+    /// Writes the settings to flash.
+    pub fn save(&self) -> Result<(), SettingsError> {
+        let mut buffer = [0u8; SETTINGS_HEADER_SIZE + SETTINGS_MAX_SIZE];
+
+        let length = postcard::to_slice(self, &mut buffer[SETTINGS_HEADER_SIZE..])
+            .map_err(|_| SettingsError::Serialize)?
+            .len();
+        buffer[..SETTINGS_MAGIC.len()].copy_from_slice(&SETTINGS_MAGIC);
+        buffer[SETTINGS_MAGIC.len()..SETTINGS_HEADER_SIZE]
+            .copy_from_slice(&(length as u16).to_le_bytes());
+
+        FlashStorage::new()
+            .write(
+                SETTINGS_FLASH_OFFSET,
+                &buffer[..SETTINGS_HEADER_SIZE + length],
+            )
+            .map_err(|_| SettingsError::Flash)
+    }
+
+    /// Exports the settings as a hex string, to be imported on another device.
+    pub fn export(&self) -> Result<String, SettingsError> {
+        let mut buffer = [0u8; SETTINGS_MAX_SIZE];
+        let payload =
+            postcard::to_slice(self, &mut buffer).map_err(|_| SettingsError::Serialize)?;
+
+        let mut blob = Vec::from(SETTINGS_EXPORT_MAGIC);
+        blob.extend_from_slice(payload);
+        blob.extend(fletcher16(&blob).to_le_bytes());
+
+        Ok(blob.iter().map(|byte| format!("{byte:02x}")).collect())
+    }
+
+    /// Parses and validates settings exported with `export`.
+    ///
+    /// The saved heater state, the brown-out count and the device name are device-specific, and
+    /// are kept from these settings. The device name is the MQTT client ID, which two devices
+    /// can't share.
+    pub fn import(&self, export: &str) -> Result<Settings, SettingsError> {
+        if export.len() % 2 != 0 || !export.is_ascii() {
+            return Err(SettingsError::InvalidExport);
+        }
+        let blob = (0..export.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&export[index..index + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| SettingsError::InvalidExport)?;
+
+        let payload = blob
+            .strip_prefix(&SETTINGS_EXPORT_MAGIC)
+            .filter(|rest| rest.len() >= 2)
+            .ok_or(SettingsError::InvalidExport)?;
+        let (payload, checksum) = payload.split_at(payload.len() - 2);
+        if fletcher16(&blob[..blob.len() - 2]).to_le_bytes() != checksum {
+            return Err(SettingsError::ChecksumMismatch);
+        }
+
+        let mut imported: Settings =
+            postcard::from_bytes(payload).map_err(|_| SettingsError::InvalidExport)?;
+        imported.validate()?;
+        imported.saved_state = self.saved_state;
+        imported.brownouts = self.brownouts;
+        imported.device_name = self.device_name.clone();
+        Ok(imported)
+    }
+
+    /// Checks the values that can't be checked while deserializing.
+    fn validate(&self) -> Result<(), SettingsError> {
+        TempLimits::new(self.temp_limits.low, self.temp_limits.high)?;
+        TempCalibration::new(self.temp_calibration.offset, self.temp_calibration.scale)?;
+        TempAlarms::new(self.temp_alarms.low, self.temp_alarms.high)?;
+        MqttPingInterval::new(self.mqtt_ping_interval.secs())?;
+        if let Some(device_name) = &self.device_name {
+            Settings::default().set_device_name(device_name)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the device name, which is used as an MQTT topic level and client ID.
+    ///
+    /// Returns an error if the name is empty, too long, or not a valid topic level.
+    pub fn set_device_name(&mut self, device_name: &str) -> Result<(), SettingsError> {
+        let is_valid = !device_name.is_empty()
+            && device_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !is_valid {
+            return Err(SettingsError::InvalidDeviceName);
+        }
+
+        self.device_name = Some(
+            heapless::String::try_from(device_name)
+                .map_err(|_| SettingsError::InvalidDeviceName)?,
+        );
+        Ok(())
+    }
+}
+
+fn fletcher16(data: &[u8]) -> u16 {
+    let (mut sum1, mut sum2) = (0u16, 0u16);
+    for byte in data {
+        sum1 = (sum1 + *byte as u16) % 255;
+        sum2 = (sum2 + sum1) % 255;
+    }
+    (sum2 << 8) | sum1
+}
+
+/// Writes the serial console history to flash, oldest command first.
+///
+/// Keeps the newest commands that fit in `HISTORY_MAX_SIZE`.
+pub fn save_history(commands: &[String]) -> Result<(), SettingsError> {
+    // Each command takes a separator, bar the first.
+    let mut size = 0;
+    let kept = commands
+        .iter()
+        .rev()
+        .take_while(|command| {
+            size += command.len() + 1;
+            size <= HISTORY_MAX_SIZE + 1
+        })
+        .count();
+    let payload = commands[commands.len() - kept..].join("\n");
+
+    let mut record = Vec::from(HISTORY_MAGIC);
+    record.extend((payload.len() as u16).to_le_bytes());
+    record.extend_from_slice(payload.as_bytes());
+
+    FlashStorage::new()
+        .write(HISTORY_FLASH_OFFSET, &record)
+        .map_err(|_| SettingsError::Flash)
+}
+
+/// Reads the serial console history stored with `save_history`, oldest command first.
+///
+/// Returns no commands if none were stored.
+pub fn load_history() -> Vec<String> {
+    let mut flash = FlashStorage::new();
+
+    let mut header = [0u8; SETTINGS_HEADER_SIZE];
+    if flash.read(HISTORY_FLASH_OFFSET, &mut header).is_err()
+        || header[..HISTORY_MAGIC.len()] != HISTORY_MAGIC
+    {
+        return Vec::new();
+    }
+
+    let length = u16::from_le_bytes([header[4], header[5]]) as usize;
+    if length > HISTORY_MAX_SIZE {
+        return Vec::new();
+    }
+
+    let mut buffer = [0u8; HISTORY_MAX_SIZE];
+    let read = flash.read(
+        HISTORY_FLASH_OFFSET + SETTINGS_HEADER_SIZE as u32,
+        &mut buffer[..length],
+    );
+    match (read, core::str::from_utf8(&buffer[..length])) {
+        (Ok(()), Ok(payload)) if !payload.is_empty() => {
+            payload.split('\n').map(String::from).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn load() -> Option<Settings> {
+    let mut flash = FlashStorage::new();
+
+    let mut header = [0u8; SETTINGS_HEADER_SIZE];
+    flash.read(SETTINGS_FLASH_OFFSET, &mut header).ok()?;
+    if header[..SETTINGS_MAGIC.len()] != SETTINGS_MAGIC {
+        return None;
+    }
+
+    let length = u16::from_le_bytes([header[4], header[5]]) as usize;
+    if length > SETTINGS_MAX_SIZE {
+        return None;
+    }
+
+    let mut buffer = [0u8; SETTINGS_MAX_SIZE];
+    flash
+        .read(
+            SETTINGS_FLASH_OFFSET + SETTINGS_HEADER_SIZE as u32,
+            &mut buffer[..length],
+        )
+        .ok()?;
+
+    postcard::from_bytes(&buffer[..length]).ok()
+}
@@ -1,151 +1,82 @@
-use alloc::{boxed::Box, format, string::String};
-use core::ops::{Deref, DerefMut};
+use alloc::{boxed::Box, format};
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Instant, Timer};
-use thiserror::Error;
+use embassy_time::{Duration, Timer};
 
-use crate::{memlog, task::ssr_control::SsrDutyDynSender};
+use crate::{
+    memlog,
+    settings::SharedSettings,
+    task::ssr_control::{Duty, DutySource, DutyUpdate, SsrDutyDynSender, ramp_duty},
+};
+
+pub use heater_core::state::{HeaterControlState, HeaterMode, HeaterState, SavedState};
 
-// Remotes must check in periodically or the heater shuts off.
-pub const REMOTE_CHECKIN_INTERVAL: Duration = Duration::from_secs(60);
 // How often to check for expired remotes.
 pub const CHECKIN_EXPIRE_INTERVAL: Duration = Duration::from_secs(10);
+// The duty applied when a remote expires. Above zero, the heater is left in Manual mode at this
+// duty instead of Off, as a keep-warm or frost-protection floor.
+const REMOTE_EXPIRY_DUTY: Duty = Duty::saturating(0);
+// Ramp down to the expiry duty over this time, rather than cutting to it at once, to spare
+// high-power elements the transient. None cuts at once. Safety locks and faults always cut at once.
+const REMOTE_EXPIRY_RAMP: Option<Duration> = Some(Duration::from_secs(2));
+
+// Resume the last heater mode and duty after a reboot. Disabled by default, as turning a heater
+// back on unattended after a power loss is dangerous in some installations.
+const STATE_RESUME_ON_BOOT: bool = false;
+// How often to check the heater state for changes to persist. Limits flash wear.
+const STATE_PERSIST_INTERVAL: Duration = Duration::from_secs(30);
 
 pub type SharedState = &'static Mutex<NoopRawMutex, HeaterControlState>;
 
-#[derive(Clone, Debug, Default)]
-pub struct HeaterControlState {
-    duty: u8,
-    state: HeaterState,
-}
-
-#[derive(Clone, Debug, Default)]
-pub enum HeaterState {
-    #[default]
-    Off,
-    // The heater is being controlled by a remote.
-    Remote {
-        // An identifier for the remote that is actively controlling the heater.
-        remote_id: String,
-        // Automatically turn off the heater if a remote has not been seen for some time.
-        expires: embassy_time::Instant,
-    },
-    // The heater is being controlled manually.
-    Manual,
-}
-
-impl Deref for HeaterControlState {
-    type Target = HeaterState;
-
-    fn deref(&self) -> &Self::Target {
-        &self.state
-    }
-}
-impl DerefMut for HeaterControlState {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.state
-    }
-}
-
 pub fn init() -> SharedState {
     Box::leak(Box::new(Mutex::new(HeaterControlState::default())))
 }
 
-#[allow(dead_code)]
-impl HeaterControlState {
-    pub fn is_remote(&self) -> bool {
-        matches!(self.state, HeaterState::Remote { .. })
-    }
-
-    pub fn is_manual(&self) -> bool {
-        matches!(self.state, HeaterState::Manual)
-    }
-
-    pub fn is_off(&self) -> bool {
-        matches!(self.state, HeaterState::Off)
-    }
-
-    /// Returns the ID of the currently controlling remote, if any.
-    pub fn remote_id(&self) -> Option<&str> {
-        if let HeaterState::Remote { remote_id, .. } = &self.state {
-            Some(remote_id.as_str())
-        } else {
-            None
-        }
+/// Restores the heater state saved before the last reboot, if enabled.
+pub async fn resume(
+    ssrcontrol_duty_sender: SsrDutyDynSender,
+    memlog: memlog::SharedLogger,
+    state: SharedState,
+    settings: SharedSettings,
+) {
+    if !STATE_RESUME_ON_BOOT {
+        return;
     }
 
-    /// Transition to Off.
-    ///
-    /// This transition is always possible.
-    pub fn transition_to_off(&mut self) {
-        self.state = HeaterState::Off
+    let saved = settings.lock().await.saved_state;
+    let mut state = state.lock().await;
+    if let Some(duty) = state.restore(saved) {
+        ssrcontrol_duty_sender.send(DutyUpdate::new(duty, DutySource::Resume));
     }
+    memlog.info(format!("resumed heater state: {:?}", saved.mode()));
+}
 
-    /// Transition to Manual and set a duty cycle.
-    ///
-    /// This transition is always possible.
-    pub fn transition_to_manual(&mut self, heater_duty: u8) {
-        self.duty = heater_duty;
-        self.state = HeaterState::Manual;
+// Periodically saves the heater state to flash when it changes, to be resumed on boot.
+#[embassy_executor::task]
+pub async fn persist_state(
+    memlog: memlog::SharedLogger,
+    state: SharedState,
+    settings: SharedSettings,
+) {
+    if !STATE_RESUME_ON_BOOT {
+        return;
     }
 
-    /// Updates the duty cycle set by a remote.
-    ///
-    /// Returns an error if the requesting remote is not the active remote,
-    /// whether because it has failed to check in on time.
-    pub fn remote_update_duty(
-        &mut self,
-        remote_id: impl Into<String>,
-        heater_duty: u8,
-    ) -> Result<(), StateError> {
-        match &mut self.state {
-            HeaterState::Off | HeaterState::Manual => {
-                // Set the mode to remote, record the remote identifier.
-                self.state = HeaterState::Remote {
-                    remote_id: remote_id.into(),
-                    expires: Instant::now() + REMOTE_CHECKIN_INTERVAL,
-                };
-                Ok(())
-            }
-
-            HeaterState::Remote {
-                remote_id: current_remote,
-                expires,
-            } => {
-                // See if the requesting remote is the one controlling the heater.
-                let remote_id = remote_id.into();
-                if *current_remote != remote_id {
-                    return Err(StateError::RemoteMismatch);
-                }
-
-                // See if the expiry time has elapsed.
-                // We use checked_duration_since because if `expires` is in the future, a regular duration
-                // calculation would underflow since Duration is unsigned.
-                if Instant::now().checked_duration_since(*expires).is_some() {
-                    return Err(StateError::RemoteExpired);
-                }
-
-                // Update the recorded duty.
-                self.duty = heater_duty;
-
-                // Set a new expiry time.
-                *expires = Instant::now() + REMOTE_CHECKIN_INTERVAL;
-
-                Ok(())
+    loop {
+        Timer::after(STATE_PERSIST_INTERVAL).await;
+
+        let saved = state.lock().await.to_saved();
+        let mut settings = settings.lock().await;
+        if settings.saved_state != saved {
+            settings.saved_state = saved;
+            if let Err(error) = settings.save() {
+                memlog.warn(format!("failed to persist heater state: {error}"));
             }
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Error)]
-pub enum StateError {
-    #[error("the heater is being controlled by another remote")]
-    RemoteMismatch,
-    #[error("the remote failed to check in and has expired")]
-    RemoteExpired,
-}
-
-// Periodically checks if a remote has expired, and sets the heater duty to zero.
+// Periodically checks if a remote has expired, and brings the heater duty down to
+// `REMOTE_EXPIRY_DUTY`.
 #[embassy_executor::task]
 pub async fn expire_remote(
     ssrcontrol_duty_sender: SsrDutyDynSender,
@@ -155,14 +86,35 @@ pub async fn expire_remote(
     loop {
         Timer::after(CHECKIN_EXPIRE_INTERVAL).await;
 
-        let mut state = state.lock().await;
-        if let HeaterState::Remote { remote_id, expires } = &state.state {
-            let remote_id = remote_id.clone();
-
-            if Instant::now().checked_duration_since(*expires).is_some() {
-                ssrcontrol_duty_sender.send(0);
-                state.transition_to_off();
-                memlog.warn(format!("remote {remote_id} expired, duty set to 0"));
+        // Take control from the expired remote, then release the lock before any ramp.
+        let expired = state.lock().await.expire_remote(REMOTE_EXPIRY_DUTY);
+        let Some((remote_id, duty)) = expired else {
+            continue;
+        };
+
+        match REMOTE_EXPIRY_RAMP {
+            Some(ramp) if duty > REMOTE_EXPIRY_DUTY => {
+                memlog.warn(format!(
+                    "remote {remote_id} expired, ramping duty from {duty} to {REMOTE_EXPIRY_DUTY} over {}ms",
+                    ramp.as_millis()
+                ));
+                ramp_duty(
+                    &ssrcontrol_duty_sender,
+                    duty,
+                    REMOTE_EXPIRY_DUTY,
+                    ramp,
+                    DutySource::RemoteExpiry,
+                )
+                .await;
+            }
+            _ => {
+                ssrcontrol_duty_sender.send(DutyUpdate::new(
+                    REMOTE_EXPIRY_DUTY,
+                    DutySource::RemoteExpiry,
+                ));
+                memlog.warn(format!(
+                    "remote {remote_id} expired, duty cut to {REMOTE_EXPIRY_DUTY}"
+                ));
             }
         }
     }
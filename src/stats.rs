@@ -0,0 +1,116 @@
+//! Runtime counters for diagnostics, reported by the `stats` serial command.
+use alloc::{boxed::Box, vec::Vec};
+use core::cell::{Cell, RefCell};
+use embassy_sync::{blocking_mutex::raw::RawMutex, pubsub::PubSubChannel, watch::Watch};
+
+use crate::task::mqtt::BrokerProbe;
+
+pub type SharedStats = &'static Stats;
+
+/// A monotonic event counter.
+#[derive(Default)]
+pub struct Counter(Cell<u32>);
+
+impl Counter {
+    pub fn increment(&self) {
+        self.0.set(self.0.get().saturating_add(1));
+    }
+
+    pub fn add(&self, count: u32) {
+        self.0.set(self.0.get().saturating_add(count));
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.get()
+    }
+}
+
+/// Watch channels only hold the latest value, so a slow receiver silently misses updates.
+/// A receiver that observed fewer values than were sent has missed some.
+#[derive(Default)]
+pub struct Stats {
+    pub temp_sent: Counter,
+    pub temp_observed_mqtt: Counter,
+    pub temp_observed_thermostat: Counter,
+    pub net_sent: Counter,
+    pub net_observed_mqtt: Counter,
+    // Log records sent are counted by the logger itself.
+    pub log_observed_mqtt: Counter,
+    /// SSR commands missed by lagging subscribers.
+    pub ssr_command_lagged: Counter,
+    /// The last reading from the primary temperature sensor before calibration, in Celsius.
+    pub temp_raw: Cell<Option<f32>>,
+    /// The last broker reachability probe, run with `mqtt probe`.
+    pub broker_probe: Cell<Option<BrokerProbe>>,
+    /// Probes for the watcher and subscriber slots of the channels registered at init.
+    slots: RefCell<Vec<(&'static str, Box<dyn Fn() -> SlotUsage>)>>,
+}
+
+impl Stats {
+    /// Registers a channel's slots, to be reported under `name`.
+    pub fn register_slots(&self, name: &'static str, probe: impl Fn() -> SlotUsage + 'static) {
+        self.slots.borrow_mut().push((name, Box::new(probe)));
+    }
+
+    /// The current slot usage of every registered channel.
+    pub fn slots(&self) -> Vec<(&'static str, SlotUsage)> {
+        self.slots
+            .borrow()
+            .iter()
+            .map(|(name, probe)| (*name, probe()))
+            .collect()
+    }
+}
+
+/// Slots claimed out of those allocated at compile time, for a watch or pubsub channel.
+///
+/// Claiming one more than the capacity with `unwrap()` panics, so this shows the headroom left.
+#[derive(Clone, Copy, Debug)]
+pub struct SlotUsage {
+    pub claimed: usize,
+    pub capacity: usize,
+}
+
+// The channels don't expose their counts. Instead, claim every free slot, count them, and
+// release them all again as the probes are dropped.
+
+/// Counts the receivers claimed on a watch.
+pub fn watch_slots<M: RawMutex, T: Clone, const N: usize>(watch: &Watch<M, T, N>) -> SlotUsage {
+    let free: Vec<_> = core::iter::from_fn(|| watch.dyn_receiver())
+        .take(N)
+        .collect();
+    SlotUsage {
+        claimed: N - free.len(),
+        capacity: N,
+    }
+}
+
+/// Counts the subscribers claimed on a pubsub channel.
+pub fn subscriber_slots<M: RawMutex, T: Clone, const CAP: usize, const S: usize, const P: usize>(
+    channel: &PubSubChannel<M, T, CAP, S, P>,
+) -> SlotUsage {
+    let free: Vec<_> = core::iter::from_fn(|| channel.dyn_subscriber().ok())
+        .take(S)
+        .collect();
+    SlotUsage {
+        claimed: S - free.len(),
+        capacity: S,
+    }
+}
+
+/// Counts the publishers claimed on a pubsub channel.
+pub fn publisher_slots<M: RawMutex, T: Clone, const CAP: usize, const S: usize, const P: usize>(
+    channel: &PubSubChannel<M, T, CAP, S, P>,
+) -> SlotUsage {
+    let free: Vec<_> = core::iter::from_fn(|| channel.dyn_publisher().ok())
+        .take(P)
+        .collect();
+    SlotUsage {
+        claimed: P - free.len(),
+        capacity: P,
+    }
+}
+
+pub fn init() -> SharedStats {
+    Box::leak(Box::new(Stats::default()))
+}
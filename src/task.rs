@@ -1,11 +1,19 @@
+pub mod gpio_monitor;
+pub mod heap_monitor;
+pub mod interlock;
 pub mod mqtt;
 pub mod net;
 pub mod net_monitor;
 pub mod serial_console;
 pub mod ssr_control;
 pub mod temp_sensor;
+pub mod thermostat;
 pub mod wifi;
 
+pub use gpio_monitor::gpio_monitor;
+pub use heap_monitor::heap_monitor;
+pub use interlock::interlock;
 pub use net_monitor::net_monitor;
 pub use serial_console::serial_console;
 pub use temp_sensor::temp_sensor;
+pub use thermostat::thermostat;
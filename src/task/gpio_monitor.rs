@@ -0,0 +1,77 @@
+use crate::{board::EXPANSION_PIN_COUNT, memlog::SharedLogger};
+use alloc::{boxed::Box, format};
+use embassy_futures::select::select_array;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, watch};
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::{self, Pin};
+
+/// How long to let a line settle after an edge before reading it.
+const GPIO_DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// The debounced level of a monitored input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpioLevel {
+    /// The GPIO number.
+    pub pin: u8,
+    pub high: bool,
+}
+
+/// The levels of every monitored input, in the order of `BoardPins::expansion`.
+pub type GpioLevels = [GpioLevel; EXPANSION_PIN_COUNT];
+
+pub type GpioInputWatch<const W: usize> = &'static watch::Watch<NoopRawMutex, GpioLevels, W>;
+pub type GpioInputDynSender = watch::DynSender<'static, GpioLevels>;
+pub type GpioInputDynReceiver = watch::DynReceiver<'static, GpioLevels>;
+
+/// Takes a const that sets the maximum number of watchers.
+pub fn init<const WATCHERS: usize>() -> GpioInputWatch<WATCHERS> {
+    Box::leak(Box::new(watch::Watch::new()))
+}
+
+// Watches the expansion pins as generic inputs, and shares their levels as they change.
+//
+// The inputs are pulled up, for switches and sensors that pull the line to GND.
+#[embassy_executor::task]
+pub async fn gpio_monitor(
+    pins: [gpio::AnyPin<'static>; EXPANSION_PIN_COUNT],
+    gpio_sender: GpioInputDynSender,
+    memlog: SharedLogger,
+) {
+    let numbers = pins.each_ref().map(|pin| pin.number());
+    let mut inputs = pins
+        .map(|pin| gpio::Input::new(pin, gpio::InputConfig::default().with_pull(gpio::Pull::Up)));
+
+    let mut levels = read_levels(&numbers, &inputs);
+    gpio_sender.send(levels);
+
+    loop {
+        // Wait for an edge, unless a level already changed since the last read.
+        if read_levels(&numbers, &inputs) == levels {
+            select_array(inputs.each_mut().map(|input| input.wait_for_any_edge())).await;
+        }
+
+        // Take the levels once settled. A bounce that returned to the last level is ignored.
+        Timer::after(GPIO_DEBOUNCE).await;
+        let new_levels = read_levels(&numbers, &inputs);
+        if new_levels == levels {
+            continue;
+        }
+
+        for level in new_levels.iter().filter(|level| !levels.contains(level)) {
+            let name = if level.high { "high" } else { "low" };
+            memlog.info(format!("gpio: gpio{} {name}", level.pin));
+        }
+        levels = new_levels;
+        gpio_sender.send(levels);
+    }
+}
+
+fn read_levels(
+    numbers: &[u8; EXPANSION_PIN_COUNT],
+    inputs: &[gpio::Input<'static>; EXPANSION_PIN_COUNT],
+) -> GpioLevels {
+    core::array::from_fn(|index| GpioLevel {
+        pin: numbers[index],
+        high: inputs[index].is_high(),
+    })
+}
@@ -0,0 +1,75 @@
+use crate::memlog::SharedLogger;
+use alloc::{boxed::Box, format};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, watch};
+use embassy_time::{Duration, Timer};
+
+/// How often to sample the heap usage.
+const HEAP_MONITOR_INTERVAL: Duration = Duration::from_secs(30);
+/// Warn when the free heap drops below this many bytes.
+const HEAP_FREE_WARN_THRESHOLD: usize = 8 * 1024;
+/// Below this many bytes free, non-critical logs and publishes are shed, so that the heap left
+/// goes to SSR control and the safety checks.
+const HEAP_FREE_DEGRADE_THRESHOLD: usize = 4 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStatus {
+    pub used: usize,
+    pub free: usize,
+    /// The lowest free heap seen since boot, as of the last sample.
+    pub min_free: usize,
+}
+
+pub type HeapStatusWatch<const W: usize> = &'static watch::Watch<NoopRawMutex, HeapStatus, W>;
+pub type HeapStatusDynSender = watch::DynSender<'static, HeapStatus>;
+pub type HeapStatusDynReceiver = watch::DynReceiver<'static, HeapStatus>;
+
+/// Whether the free heap is low enough that non-critical work should be shed.
+///
+/// Reads the allocator directly, so it's current between samples too.
+pub fn is_degraded() -> bool {
+    esp_alloc::HEAP.free() < HEAP_FREE_DEGRADE_THRESHOLD
+}
+
+/// Takes a const that sets the maximum number of watchers.
+pub fn init<const WATCHERS: usize>() -> HeapStatusWatch<WATCHERS> {
+    Box::leak(Box::new(watch::Watch::new()))
+}
+
+// Periodically samples the heap usage, and warns when running low.
+#[embassy_executor::task]
+pub async fn heap_monitor(heapstatus_sender: HeapStatusDynSender, memlog: SharedLogger) {
+    let mut min_free = usize::MAX;
+    let mut is_low = false;
+    let mut is_degraded = false;
+
+    loop {
+        let used = esp_alloc::HEAP.used();
+        let free = esp_alloc::HEAP.free();
+        min_free = min_free.min(free);
+
+        // Warn once when crossing the threshold.
+        if !is_low && free < HEAP_FREE_WARN_THRESHOLD {
+            memlog.warn(format!("heap: low on memory, {free} bytes free"));
+        }
+        is_low = free < HEAP_FREE_WARN_THRESHOLD;
+
+        // Log once when degradation starts and ends. No formatting, to not allocate.
+        let degraded = free < HEAP_FREE_DEGRADE_THRESHOLD;
+        if degraded != is_degraded {
+            if degraded {
+                memlog.warn("heap: degraded, dropping non-critical logs and publishes");
+            } else {
+                memlog.warn("heap: recovered, no longer degraded");
+            }
+        }
+        is_degraded = degraded;
+
+        heapstatus_sender.send(HeapStatus {
+            used,
+            free,
+            min_free,
+        });
+
+        Timer::after(HEAP_MONITOR_INTERVAL).await;
+    }
+}
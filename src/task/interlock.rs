@@ -0,0 +1,64 @@
+use crate::{
+    memlog::SharedLogger,
+    task::ssr_control::{SsrCommand, SsrCommandPublisher},
+};
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio;
+
+/// How long to let the line settle after an edge before reading it.
+const INTERLOCK_DEBOUNCE: Duration = Duration::from_millis(50);
+
+// Latches the SSR off while a hardware interlock, such as a thermal fuse or a door switch, is
+// open. Once it closes, the SSR stays off until an operator unlocks it.
+//
+// The interlock closes the line to GND, and the input is pulled up, so a broken wire reads as open.
+#[embassy_executor::task]
+pub async fn interlock(
+    interlock_pin: gpio::AnyPin<'static>,
+    ssrcontrol_command_sender: SsrCommandPublisher,
+    memlog: SharedLogger,
+) {
+    let mut input = gpio::Input::new(
+        interlock_pin,
+        gpio::InputConfig::default().with_pull(gpio::Pull::Up),
+    );
+
+    // Report the state at boot either way, as the SSR control may still hold an earlier latch.
+    let mut open = input.is_high();
+    if open {
+        memlog.error("interlock: open, ssr latched off");
+        ssrcontrol_command_sender
+            .publish(SsrCommand::InterlockOpen)
+            .await;
+    } else {
+        ssrcontrol_command_sender
+            .publish(SsrCommand::InterlockClosed)
+            .await;
+    }
+
+    loop {
+        // Wait for an edge, unless the level already changed since the last read.
+        if input.is_high() == open {
+            input.wait_for_any_edge().await;
+        }
+
+        // Take the level once settled. A bounce that returned to the last level is ignored.
+        Timer::after(INTERLOCK_DEBOUNCE).await;
+        if input.is_high() == open {
+            continue;
+        }
+
+        open = !open;
+        if open {
+            memlog.error("interlock: opened, ssr latched off");
+            ssrcontrol_command_sender
+                .publish(SsrCommand::InterlockOpen)
+                .await;
+        } else {
+            memlog.warn("interlock: closed, unlock the ssr to resume");
+            ssrcontrol_command_sender
+                .publish(SsrCommand::InterlockClosed)
+                .await;
+        }
+    }
+}
@@ -1,55 +1,276 @@
+#![allow(clippy::too_many_arguments)]
 use crate::{
-    futures::{Either8, select8},
-    memlog::SharedLogger,
-    state::SharedState,
+    ESP_APP_DESC,
+    memlog::{Level, SharedLogger, format_milliseconds_to_hms},
+    settings::{SharedSettings, TempUnit},
+    state::{HeaterMode, HeaterState, SharedState},
+    stats::SharedStats,
     task::{
+        gpio_monitor::GpioInputDynReceiver,
+        heap_monitor::{self, HeapStatusDynReceiver},
         net_monitor::NetStatusDynReceiver,
-        ssr_control::{SsrCommandSubscriber, SsrDutyDynReceiver, SsrDutyDynSender},
-        temp_sensor::TempSensorDynReceiver,
+        ssr_control::{
+            Duty, DutyError, DutySource, DutyUpdate, LockReason, SsrAlertDynReceiver, SsrCommand,
+            SsrCommandRelay, SsrCommandSubscriber, SsrDutyDynReceiver, SsrDutyLimiter, SsrStatus,
+            SsrStatusDynReceiver, SsrZoneDutyDynSender, applied_duty, parse_zone, set_zone_duty,
+        },
+        temp_sensor::{TempAlarmDynReceiver, TempData, TempSensorDynReceiver, TempSensorReading},
     },
 };
 use alloc::{
+    boxed::Box,
     format,
     string::{String, ToString},
+    vec::Vec,
 };
-use const_format::concatcp;
-use embassy_net::{IpAddress, IpEndpoint, dns::DnsQueryType, tcp::TcpSocket};
-use embassy_sync::pubsub::WaitResult;
-use embassy_time::{Duration, Timer, with_timeout};
+use core::{cell::RefCell, fmt::Write};
+use embassy_futures::select;
+use embassy_net::{
+    IpAddress, IpEndpoint,
+    dns::DnsQueryType,
+    icmp::{
+        PacketMetadata,
+        ping::{PingManager, PingParams},
+    },
+    tcp::{self, TcpSocket},
+};
+use embassy_sync::{
+    blocking_mutex::raw::NoopRawMutex, channel::Channel, pubsub::WaitResult, signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use heater_core::select::{Either10, select10};
 use mountain_mqtt::{
     client::{
         Client, ClientError, ClientNoQueue, ClientReceivedEvent, ConnectionSettings, EventHandler,
         EventHandlerError,
     },
     data::{
-        property::{Property, PublishProperty},
+        property::{Property, PublishProperty, UserProperty},
         quality_of_service::QualityOfService,
         string_pair::StringPair,
     },
     embedded_io_async::ConnectionEmbedded,
+    error::{PacketReadError, PacketWriteError},
     packets::connect::Will,
 };
+use serde::Serialize;
 
-const MQTT_PING_INTERVAL: Duration = Duration::from_secs(20);
-const MQTT_DUTY_TIMEOUT: Duration = Duration::from_secs(60);
+// Incoming messages are read as soon as they arrive. This is a backup, in case a wakeup is missed.
+const MQTT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+// When the duty, temperature and SSR status are published: as they change, periodically, or both.
+// See `PublishPolicy`.
+const MQTT_DUTY_PUBLISH: PublishPolicy = PublishPolicy::Both(Duration::from_secs(60));
+const MQTT_TEMP_PUBLISH: PublishPolicy = PublishPolicy::OnChange;
+const MQTT_SSR_STATUS_PUBLISH: PublishPolicy = PublishPolicy::OnChange;
+// Duty and temperature changes are published right away if large enough,
+// otherwise at most once per interval, coalescing small changes.
+const MQTT_DUTY_PUBLISH_DELTA: u8 = 5;
+const MQTT_DUTY_PUBLISH_INTERVAL: Duration = Duration::from_secs(10);
+const MQTT_TEMP_PUBLISH_DELTA: f32 = 0.5;
+const MQTT_TEMP_PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+// Decimal places of the temperature published on `temp/case`. The full precision is then
+// published on `temp/case/precise` as well. None publishes the full precision on `temp/case` only.
+const MQTT_TEMP_DECIMALS: Option<usize> = Some(1);
+// QoS of the confirmations published in response to commands. Qos1 gives remotes a reliable
+// confirmation, at the cost of more broker traffic.
+const MQTT_CONFIRM_QOS: QualityOfService = QualityOfService::Qos0;
+// Commands received but not yet applied. More are dropped with a warning.
+const MQTT_REQUEST_QUEUE_LEN: usize = 4;
+// A heartbeat shows the device is alive and processing, even while nothing else changes.
+const MQTT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+// Reconnect to the broker after this many consecutive failed publishes.
+const MQTT_PUBLISH_MAX_FAILURES: u32 = 5;
 const MQTT_SERVER_ADDR: &str = "broker.abu";
+// How long to wait for the broker to answer a ping.
+const MQTT_BROKER_PING_TIMEOUT: Duration = Duration::from_secs(2);
+// How long to wait for the broker to accept a TCP connection, when probing its reachability.
+const MQTT_BROKER_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+// Resolve the broker address again after this long, in case it changed.
+const MQTT_DNS_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
 const MQTT_PORT: u16 = 1883;
 const MQTT_TIMEOUT_MS: u32 = 5000;
+// TCP-level liveness checks on the broker connection, so that a connection dropped without a
+// FIN (the broker rebooting, or a NAT forgetting it) is reaped rather than holding the socket
+// until the next publish fails. The keepalive sends an empty segment while the connection is
+// idle, and the timeout aborts it once nothing, not even an ACK, was received for that long.
+// The timeout must exceed the keepalive, or an idle connection times out. None disables either.
+const MQTT_TCP_KEEP_ALIVE: Option<Duration> = Some(Duration::from_secs(20));
+const MQTT_TCP_TIMEOUT: Option<Duration> = Some(Duration::from_secs(60));
 const MQTT_PROPERTIES: usize = 16;
+// Holds a whole MQTT packet, in either direction.
+const MQTT_BUFFER_SIZE: usize = 2048;
+// Room left in a packet for the fixed header, packet id and properties, besides topic and payload.
+const MQTT_PACKET_OVERHEAD: usize = 64;
+// Log records below this level are not published.
+const MQTT_LOG_MIN_LEVEL: Level = Level::Info;
+// Most messages a requested log dump is split into. The rest of the log, the oldest records,
+// is left out.
+const MQTT_LOG_DUMP_MAX_CHUNKS: usize = 8;
+// Buffer size for JSON payloads.
+const MQTT_JSON_MAX_LEN: usize = 256;
 const MQTT_HEATER_TOPIC_ROOT: &str = "devices/heater";
 use crate::config::MQTT_CLIENT_ID;
 use crate::config::MQTT_TOPIC_DEVICE_NAME;
 
-macro_rules! topic_heater {
-    ($TAIL:expr) => {
-        concatcp!(
-            MQTT_HEATER_TOPIC_ROOT,
-            '/',
-            MQTT_TOPIC_DEVICE_NAME,
-            '/',
-            $TAIL
-        )
-    };
+/// The topics under a heater's namespace, built at connect time from the device name.
+struct Topics {
+    status: String,
+    version: String,
+    boot: String,
+    heartbeat: String,
+    uptime: String,
+    duty: String,
+    duty_source: String,
+    lock: String,
+    mode: String,
+    temp_case: String,
+    temp_case_precise: String,
+    net: String,
+    log: String,
+    log_dump: String,
+    ssr: String,
+    ssr_status: String,
+    alert: String,
+    alarm: String,
+    heap: String,
+    gpio: String,
+    /// The topics commands are received on.
+    commands: [(String, MqttCommand); MqttCommand::ALL.len()],
+}
+
+impl Topics {
+    fn new(device_name: &str) -> Self {
+        let topic = |tail: &str| format!("{MQTT_HEATER_TOPIC_ROOT}/{device_name}/{tail}");
+        Topics {
+            status: topic("status"),
+            version: topic("version"),
+            boot: topic("boot"),
+            heartbeat: topic("heartbeat"),
+            uptime: topic("uptime"),
+            duty: topic("duty"),
+            duty_source: topic("duty/source"),
+            lock: topic("lock"),
+            mode: topic("mode"),
+            temp_case: topic("temp/case"),
+            temp_case_precise: topic("temp/case/precise"),
+            net: topic("net"),
+            log: topic("log"),
+            log_dump: topic("log/dump"),
+            ssr: topic("ssr"),
+            ssr_status: topic("ssr/status"),
+            alert: topic("alert"),
+            alarm: topic("alarm"),
+            heap: topic("heap"),
+            gpio: topic("gpio"),
+            commands: MqttCommand::ALL.map(|command| (topic(command.topic_tail()), command)),
+        }
+    }
+}
+
+/// The commands accepted over MQTT, each subscribed to on its own topic.
+///
+/// Retained messages on these topics are ignored, only live commands take effect. While a remote
+/// is in control, only that remote can lock, unlock or change modes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MqttCommand {
+    /// Sets the heater duty, payload `0`-`100`.
+    Duty,
+    /// Locks the SSR off, any payload.
+    Lock,
+    /// Releases a lock set by an operator, any payload. Does not clear a fault.
+    Unlock,
+    /// Switches the heater mode, payload `manual` or `thermostat`.
+    Mode,
+    /// Publishes the stored log to the `log/dump` topic, any payload.
+    LogRequest,
+}
+
+impl MqttCommand {
+    const ALL: [MqttCommand; 5] = [
+        MqttCommand::Duty,
+        MqttCommand::Lock,
+        MqttCommand::Unlock,
+        MqttCommand::Mode,
+        MqttCommand::LogRequest,
+    ];
+
+    fn topic_tail(self) -> &'static str {
+        match self {
+            MqttCommand::Duty => "duty/set",
+            MqttCommand::Lock => "lock/set",
+            MqttCommand::Unlock => "unlock/set",
+            MqttCommand::Mode => "mode/set",
+            MqttCommand::LogRequest => "log/request",
+        }
+    }
+}
+
+/// Heater modes that can be selected over MQTT.
+#[derive(Clone, Copy, Debug)]
+enum MqttMode {
+    Manual,
+    Thermostat,
+}
+
+/// A validated command, passed from the event handler to the run loop to apply and confirm.
+///
+/// Duty updates are applied by the handler directly, and confirmed through the duty watch.
+/// Duty updates for an extra zone are applied here, and only logged.
+#[derive(Debug)]
+struct MqttRequest {
+    action: MqttAction,
+    /// Set if the command came from a remote.
+    remote_id: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum MqttAction {
+    Lock,
+    Unlock,
+    Mode(MqttMode),
+    LogDump,
+    /// Sets the duty of an extra zone.
+    ZoneDuty(usize, Duty),
+}
+
+type MqttRequestChannel = &'static Channel<NoopRawMutex, MqttRequest, MQTT_REQUEST_QUEUE_LEN>;
+
+/// Builds the properties that tag a confirmation with the remote that issued the command,
+/// so that the remote can correlate it.
+fn remote_properties(remote_id: Option<&str>) -> heapless::Vec<PublishProperty<'_>, 1> {
+    let mut properties = heapless::Vec::new();
+    if let Some(remote_id) = remote_id {
+        let _ = properties.push(PublishProperty::UserProperty(UserProperty::new(
+            StringPair::new("remote", remote_id),
+        )));
+    }
+    properties
+}
+
+/// The firmware version, published retained on connect.
+#[derive(Serialize)]
+struct VersionReport<'a> {
+    project_name: &'a str,
+    version: &'a str,
+    date: &'a str,
+    time: &'a str,
+}
+
+/// Why the device reset, published retained once per boot.
+#[derive(Serialize)]
+struct BootReport {
+    reset_reason: &'static str,
+    uptime_secs: u64,
+    brownouts: u32,
+}
+
+/// The last duty change, published retained. See `duty_source_payload`.
+#[derive(Serialize)]
+struct DutySourceReport {
+    duty: Duty,
+    applied: Duty,
+    source: &'static str,
+    uptime_secs: u64,
 }
 
 struct MqttDelay;
@@ -59,27 +280,329 @@ impl mountain_mqtt::client::Delay for MqttDelay {
     }
 }
 
-type MqttClient<'a> =
-    ClientNoQueue<'a, ConnectionEmbedded<TcpSocket<'a>>, MqttDelay, MqttHandler, MQTT_PROPERTIES>;
+/// Tells the MQTT client that it was enabled or disabled in the settings.
+pub type MqttToggleSignal = &'static Signal<NoopRawMutex, ()>;
+
+pub fn init_toggle() -> MqttToggleSignal {
+    Box::leak(Box::new(Signal::new()))
+}
+
+/// Waits for the MQTT interface to be enabled, if it is disabled in the settings.
+async fn wait_enabled(
+    toggle_signal: MqttToggleSignal,
+    memlog: SharedLogger,
+    settings: SharedSettings,
+) {
+    if !settings.lock().await.interfaces.mqtt {
+        memlog.info("mqtt disabled");
+        while !settings.lock().await.interfaces.mqtt {
+            toggle_signal.wait().await;
+        }
+        memlog.info("mqtt enabled");
+    }
+}
+
+/// Runs a future while discarding SSR commands, for while the client is offline.
+///
+/// Commands are otherwise only read once connected. Left unread, they would fill the channel and
+/// block its publishers, the over-temperature lock among them.
+async fn discard_commands_while<F: Future>(
+    ssrcontrol_command_subscriber: &mut SsrCommandSubscriber,
+    future: F,
+) -> F::Output {
+    let discard = async {
+        loop {
+            ssrcontrol_command_subscriber.next_message().await;
+        }
+    };
+    match select::select(future, discard).await {
+        select::Either::First(output) => output,
+        select::Either::Second(never) => never,
+    }
+}
+
+/// Hands an operator command over to the relay, which publishes it without evicting a queued
+/// command. Returns whether it was accepted, as the relay only holds a few.
+fn relay_command(
+    ssrcontrol_command_relay: SsrCommandRelay,
+    command: SsrCommand,
+    memlog: SharedLogger,
+) -> bool {
+    let accepted = ssrcontrol_command_relay.try_send(command).is_ok();
+    if !accepted {
+        memlog.warn(format!(
+            "mqtt {} command dropped, too many commands pending",
+            command.name()
+        ));
+    }
+    accepted
+}
+
+/// The TCP connection to the broker, shared between the MQTT client and the run loop.
+///
+/// mountain-mqtt can only check for incoming packets when polled, and takes ownership of its
+/// connection. Sharing the socket lets the run loop wait for it to become readable, and poll
+/// the client right away.
+struct SharedSocket<'s, 'a>(&'s RefCell<TcpSocket<'a>>);
+
+impl embedded_io_async::ErrorType for SharedSocket<'_, '_> {
+    type Error = tcp::Error;
+}
+
+// The run loop only waits on the socket while the client is idle, so borrows never overlap.
+#[allow(clippy::await_holding_refcell_ref)]
+impl embedded_io_async::Read for SharedSocket<'_, '_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0.borrow_mut().read(buf).await
+    }
+}
+
+#[allow(clippy::await_holding_refcell_ref)]
+impl embedded_io_async::Write for SharedSocket<'_, '_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.borrow_mut().write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().flush().await
+    }
+}
+
+impl embedded_io::ReadReady for SharedSocket<'_, '_> {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        self.0.borrow_mut().read_ready()
+    }
+}
+
+/// Resolves once the socket has data to read, or has been closed.
+#[allow(clippy::await_holding_refcell_ref)]
+async fn wait_read_ready(socket: &RefCell<TcpSocket<'_>>) {
+    socket.borrow().wait_read_ready().await
+}
+
+/// Decodes the total length of the packet starting at `buf`, from its fixed header.
+///
+/// Returns `None` if the header is incomplete or malformed.
+fn packet_length(buf: &[u8]) -> Option<usize> {
+    // The remaining length is a variable byte integer of up to 4 bytes, after the first byte.
+    let mut remaining: usize = 0;
+    for (index, byte) in buf.iter().skip(1).take(4).enumerate() {
+        remaining |= ((byte & 0x7f) as usize) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some(1 + index + 1 + remaining);
+        }
+    }
+    None
+}
+
+/// Discards the next incoming packet if it is too large for the MQTT buffer.
+///
+/// mountain-mqtt fails on such a packet after reading only its header, leaving the rest on the
+/// socket. Peeking at the header first lets us drop the whole packet and stay in sync with the
+/// broker. Returns the length of the skipped packet, if any.
+#[allow(clippy::await_holding_refcell_ref)]
+async fn skip_oversized_packet(
+    socket: &RefCell<TcpSocket<'_>>,
+) -> Result<Option<usize>, tcp::Error> {
+    let mut socket = socket.borrow_mut();
+    // Don't wait for data, as the poll timer also gets here.
+    if !socket.can_recv() {
+        return Ok(None);
+    }
+    let length = socket.read_with(|buf| (0, packet_length(buf))).await?;
+
+    match length {
+        Some(length) if length > MQTT_BUFFER_SIZE => {
+            let mut remaining = length;
+            while remaining > 0 {
+                remaining -= socket
+                    .read_with(|buf| {
+                        let skipped = buf.len().min(remaining);
+                        (skipped, skipped)
+                    })
+                    .await?;
+            }
+            Ok(Some(length))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The largest payload that fits in a packet to `topic`.
+fn payload_capacity(topic: &str) -> usize {
+    MQTT_BUFFER_SIZE.saturating_sub(MQTT_PACKET_OVERHEAD + topic.len())
+}
+
+/// Splits the stored log into payloads of at most `max_len` bytes, one record per line, newest
+/// first. Stops at `MQTT_LOG_DUMP_MAX_CHUNKS`. An empty log gives a single empty payload.
+fn log_dump_chunks(memlog: SharedLogger, max_len: usize) -> Vec<String> {
+    let mut chunks = Vec::from([String::new()]);
+    // Formatted up front, as the log can't stay borrowed across the awaits to publish.
+    for record in memlog.records().iter() {
+        let line = truncate_payload(format!("{record}"), max_len);
+        let chunk = chunks.last_mut().unwrap(); // never empty
+        if chunk.is_empty() {
+            chunk.push_str(&line);
+        } else if chunk.len() + 1 + line.len() <= max_len {
+            chunk.push('\n');
+            chunk.push_str(&line);
+        } else if chunks.len() < MQTT_LOG_DUMP_MAX_CHUNKS {
+            chunks.push(line);
+        } else {
+            break;
+        }
+    }
+    chunks
+}
+
+/// Shortens `payload` to at most `max_len` bytes, marking the cut with an ellipsis.
+fn truncate_payload(mut payload: String, max_len: usize) -> String {
+    const ELLIPSIS: char = '…';
+
+    if payload.len() > max_len {
+        let mut end = max_len.saturating_sub(ELLIPSIS.len_utf8());
+        while !payload.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.truncate(end);
+        payload.push(ELLIPSIS);
+    }
+    payload
+}
+
+type MqttClient<'s, 'a> = ClientNoQueue<
+    's,
+    ConnectionEmbedded<SharedSocket<'s, 'a>>,
+    MqttDelay,
+    MqttHandler,
+    MQTT_PROPERTIES,
+>;
+
+/// Resolves all of the broker's IPv4 addresses.
+pub async fn resolve_broker(
+    stack: embassy_net::Stack<'static>,
+) -> Result<heapless::Vec<IpAddress, { smoltcp::config::DNS_MAX_RESULT_COUNT }>, &'static str> {
+    match stack.dns_query(MQTT_SERVER_ADDR, DnsQueryType::A).await {
+        Ok(dns_result) if dns_result.is_empty() => {
+            Err("empty dns response to broker address query")
+        }
+        Ok(dns_result) => Ok(dns_result),
+        Err(_) => Err("failed to resolve broker address from dns"),
+    }
+}
 
-async fn connect_to_broker<'a>(
+/// Resolves the broker and pings its first address, returning the address and round-trip time.
+pub async fn ping_broker(
     stack: embassy_net::Stack<'static>,
+) -> Result<(IpAddress, Duration), String> {
+    let broker_addrs = resolve_broker(stack).await?;
+    let broker_addr = broker_addrs[0];
+
+    let mut rx_meta = [PacketMetadata::EMPTY];
+    let mut rx_buffer = [0u8; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY];
+    let mut tx_buffer = [0u8; 64];
+    let mut ping_manager = PingManager::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    let mut ping_params = PingParams::new(broker_addr);
+    ping_params
+        .set_count(1)
+        .set_timeout(MQTT_BROKER_PING_TIMEOUT);
+    match ping_manager.ping(&ping_params).await {
+        Ok(round_trip) => Ok((broker_addr, round_trip)),
+        Err(error) => Err(format!("no ping reply from {broker_addr}: {error:?}")),
+    }
+}
+
+/// The outcome of a TCP connection to the broker, independent of the MQTT session.
+#[derive(Clone, Copy, Debug)]
+pub struct BrokerProbe {
+    /// The address probed, unless the broker could not be resolved.
+    pub addr: Option<IpAddress>,
+    /// How long the connection took to establish, or why it failed.
+    pub result: Result<Duration, &'static str>,
+    pub at: Instant,
+}
+
+impl core::fmt::Display for BrokerProbe {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match (self.addr, self.result) {
+            (Some(addr), Ok(latency)) => write!(
+                f,
+                "{addr}:{MQTT_PORT} reachable, connected in {}ms",
+                latency.as_millis()
+            ),
+            (Some(addr), Err(error)) => write!(f, "{addr}:{MQTT_PORT} unreachable, {error}"),
+            (None, result) => write!(f, "{}", result.err().unwrap_or("not resolved")),
+        }
+    }
+}
+
+/// Resolves the broker and opens a TCP connection to its first address, then drops it.
+///
+/// Tells DNS and network problems apart from MQTT ones, as no MQTT packets are exchanged.
+pub async fn probe_broker(stack: embassy_net::Stack<'static>) -> BrokerProbe {
+    let broker_addr = match resolve_broker(stack).await {
+        Ok(broker_addrs) => broker_addrs[0],
+        Err(error) => {
+            return BrokerProbe {
+                addr: None,
+                result: Err(error),
+                at: Instant::now(),
+            };
+        }
+    };
+
+    let mut rx_buffer = [0u8; 64];
+    let mut tx_buffer = [0u8; 64];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+
+    let started_at = Instant::now();
+    let connect = socket.connect(IpEndpoint::new(broker_addr, MQTT_PORT));
+    let result = match with_timeout(MQTT_BROKER_PROBE_TIMEOUT, connect).await {
+        Ok(Ok(())) => Ok(started_at.elapsed()),
+        Ok(Err(tcp::ConnectError::ConnectionReset)) => Err("connection refused"),
+        Ok(Err(tcp::ConnectError::NoRoute)) => Err("no route to host"),
+        Ok(Err(tcp::ConnectError::InvalidState)) => Err("socket in use"),
+        Ok(Err(tcp::ConnectError::TimedOut)) | Err(_) => Err("timed out"),
+    };
+
+    // Reset the connection rather than leave it half-open on the broker.
+    socket.abort();
+    let _ = with_timeout(MQTT_BROKER_PROBE_TIMEOUT, socket.flush()).await;
+
+    BrokerProbe {
+        addr: Some(broker_addr),
+        result,
+        at: Instant::now(),
+    }
+}
+
+#[allow(clippy::await_holding_refcell_ref)]
+async fn connect_to_broker<'s, 'a>(
+    socket: &'s RefCell<TcpSocket<'a>>,
     broker_addr: IpAddress,
-    rx_buffer: &'a mut [u8],
-    tx_buffer: &'a mut [u8],
-    mqtt_buffer: &'a mut [u8],
+    mqtt_buffer: &'s mut [u8],
     delay: MqttDelay,
     event_handler: MqttHandler,
-) -> Result<MqttClient<'a>, String> {
+    client_id: &str,
+    topics: &Topics,
+) -> Result<MqttClient<'s, 'a>, String> {
     // Open a TCP connection to the broker.
-    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
     socket
+        .borrow_mut()
         .connect(IpEndpoint::new(broker_addr, MQTT_PORT))
         .await
         .map_err(|err| format!("{err:?}"))?;
 
     // Create an MQTT client.
-    let mqtt_conn = ConnectionEmbedded::new(socket);
+    let mqtt_conn = ConnectionEmbedded::new(SharedSocket(socket));
 
     let mut mqtt_client = ClientNoQueue::new(
         mqtt_conn,
@@ -102,17 +625,14 @@ async fn connect_to_broker<'a>(
     let will = Will::new(
         QualityOfService::Qos1,
         true,
-        topic_heater!("status"),
+        &topics.status,
         "offline".as_bytes(),
         heapless::Vec::<_, 0>::new(),
     );
 
     // Open the MQTT connection.
     mqtt_client
-        .connect_with_will(
-            &ConnectionSettings::unauthenticated(MQTT_CLIENT_ID),
-            Some(will),
-        )
+        .connect_with_will(&ConnectionSettings::unauthenticated(client_id), Some(will))
         .await
         .map_err(|err| format!("{err:?}"))?;
 
@@ -134,61 +654,143 @@ async fn connect_to_broker<'a>(
 #[embassy_executor::task]
 pub async fn run(
     stack: embassy_net::Stack<'static>,
-    ssrcontrol_duty_sender: SsrDutyDynSender,
+    reset_reason: &'static str,
+    toggle_signal: MqttToggleSignal,
+    ssrcontrol_duty_limiter: SsrDutyLimiter,
     mut ssrcontrol_duty_receiver: SsrDutyDynReceiver,
+    ssrcontrol_zone_sender: SsrZoneDutyDynSender,
     mut netstatus_receiver: NetStatusDynReceiver,
     mut tempsensor_receiver: TempSensorDynReceiver,
+    mut tempalarm_receiver: TempAlarmDynReceiver,
     mut ssrcontrol_command_subscriber: SsrCommandSubscriber,
+    ssrcontrol_command_relay: SsrCommandRelay,
+    mut ssrcontrol_status_receiver: SsrStatusDynReceiver,
+    mut ssrcontrol_alert_receiver: SsrAlertDynReceiver,
+    mut heapstatus_receiver: HeapStatusDynReceiver,
+    mut gpioinput_receiver: GpioInputDynReceiver,
     memlog: SharedLogger,
     state: SharedState,
+    settings: SharedSettings,
+    stats: SharedStats,
 ) {
-    let broker_addr = 'dns: loop {
-        match stack.dns_query(MQTT_SERVER_ADDR, DnsQueryType::A).await {
-            Ok(mut dns_result) => match dns_result.pop() {
-                Some(addr) => break 'dns addr,
-                None => memlog.warn("empty dns response to broker address query"),
-            },
-            Err(_) => memlog.warn("failed to resolve broker address from dns"),
+    discard_commands_while(
+        &mut ssrcontrol_command_subscriber,
+        wait_enabled(toggle_signal, memlog, settings),
+    )
+    .await;
+
+    let mut broker_addrs = 'dns: loop {
+        match discard_commands_while(&mut ssrcontrol_command_subscriber, resolve_broker(stack))
+            .await
+        {
+            Ok(broker_addrs) => break 'dns broker_addrs,
+            Err(error) => memlog.warn(error),
         };
 
         // Retry DNS request every 10 seconds.
-        Timer::after_secs(10).await;
+        discard_commands_while(&mut ssrcontrol_command_subscriber, Timer::after_secs(10)).await;
     };
+    let mut broker_resolved_at = Instant::now();
+    // The broker address to connect to, rotated on failure.
+    let mut broker_index = 0;
 
     let mut rx_buffer = [0u8; 1024];
     let mut tx_buffer = [0u8; 1024];
-    let mut mqtt_buffer = [0u8; 2048];
+    let mut mqtt_buffer = [0u8; MQTT_BUFFER_SIZE];
+
+    // Commands from the event handler, kept across connections.
+    let requests: MqttRequestChannel = Box::leak(Box::new(Channel::new()));
 
-    // Enable log watching and get a receiver.
-    memlog.enable_watch();
-    let mut logwatch_receiver = memlog.watch().unwrap();
+    // The boot message goes out once, on the first connection after a reset.
+    let mut boot_published = false;
+    // Counts heartbeats since boot, across connections.
+    let mut heartbeat_count: u32 = 0;
+
+    // Enable log queueing and get the receiver. Records are forwarded in order, so a burst of
+    // errors isn't coalesced to its last record.
+    memlog.enable_queue(MQTT_LOG_MIN_LEVEL);
+    let logqueue_receiver = memlog.queue().unwrap();
 
     // We continue this loop if the mqtt client is disconnected.
     'connect: loop {
+        // Stay offline while disabled at runtime.
+        discard_commands_while(
+            &mut ssrcontrol_command_subscriber,
+            wait_enabled(toggle_signal, memlog, settings),
+        )
+        .await;
+
+        // A device name set at runtime replaces both the compile-time topic name and client ID.
+        // Changes take effect on the next connection.
+        let device_name = settings.lock().await.device_name.clone();
+        let (client_id, topics) = match &device_name {
+            Some(device_name) => (device_name.as_str(), Topics::new(device_name)),
+            None => (MQTT_CLIENT_ID, Topics::new(MQTT_TOPIC_DEVICE_NAME)),
+        };
+
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_keep_alive(MQTT_TCP_KEEP_ALIVE);
+        socket.set_timeout(MQTT_TCP_TIMEOUT);
+        let socket = RefCell::new(socket);
+
         // Loop, attempting to reconnect
         let mut mqtt_client = 'client_connect: loop {
+            // Keep the previous addresses if DNS fails.
+            if Instant::now() >= broker_resolved_at + MQTT_DNS_REFRESH_INTERVAL {
+                match discard_commands_while(
+                    &mut ssrcontrol_command_subscriber,
+                    resolve_broker(stack),
+                )
+                .await
+                {
+                    Ok(new_broker_addrs) => {
+                        broker_addrs = new_broker_addrs;
+                        broker_index = 0;
+                    }
+                    Err(error) => memlog.warn(error),
+                }
+                broker_resolved_at = Instant::now();
+            }
+            let broker_addr = broker_addrs[broker_index];
+
             let delay = MqttDelay;
             let event_handler = MqttHandler {
-                ssrcontrol_duty_sender: ssrcontrol_duty_sender.clone(),
+                ssrcontrol_duty_limiter,
+                commands: topics.commands.clone(),
+                requests,
                 memlog,
                 state,
             };
 
-            match connect_to_broker(
-                stack,
+            let connect = connect_to_broker(
+                &socket,
                 broker_addr,
-                &mut rx_buffer,
-                &mut tx_buffer,
                 &mut mqtt_buffer,
                 delay,
                 event_handler,
-            )
-            .await
-            {
+                client_id,
+                &topics,
+            );
+            match discard_commands_while(&mut ssrcontrol_command_subscriber, connect).await {
                 Ok(client) => break 'client_connect client,
                 Err(error) => {
-                    memlog.warn(format!("failed to connect to mqtt broker: {error}"));
-                    Timer::after_secs(10).await;
+                    memlog.warn(format!(
+                        "failed to connect to mqtt broker at {broker_addr}: {error}"
+                    ));
+                    // Drop any half-open connection before retrying.
+                    socket.borrow_mut().abort();
+
+                    // Try the next address. Once all have failed, resolve the broker again.
+                    broker_index += 1;
+                    if broker_index >= broker_addrs.len() {
+                        broker_index = 0;
+                        broker_resolved_at = Instant::MIN;
+                    }
+                    discard_commands_while(
+                        &mut ssrcontrol_command_subscriber,
+                        Timer::after_secs(10),
+                    )
+                    .await;
                     continue 'client_connect;
                 }
             }
@@ -197,7 +799,7 @@ pub async fn run(
         // Publish an 'online' status.
         if mqtt_client
             .publish(
-                topic_heater!("status"),
+                &topics.status,
                 "online".as_bytes(),
                 QualityOfService::Qos1,
                 true,
@@ -206,142 +808,757 @@ pub async fn run(
             .is_err()
         {
             // Something went wrong, retry the connection.
-            Timer::after_secs(10).await;
+            discard_commands_while(&mut ssrcontrol_command_subscriber, Timer::after_secs(10)).await;
             continue 'connect;
         }
 
-        // Subscribe to duty cycle updates.
-        if mqtt_client
-            .subscribe(topic_heater!("duty/set"), QualityOfService::Qos1)
+        // Publish the firmware version.
+        let version = VersionReport {
+            project_name: ESP_APP_DESC.project_name(),
+            version: ESP_APP_DESC.version(),
+            date: ESP_APP_DESC.date(),
+            time: ESP_APP_DESC.time(),
+        };
+        let published = match serde_json_core::to_string::<_, MQTT_JSON_MAX_LEN>(&version) {
+            Ok(version) => {
+                mqtt_client
+                    .publish(
+                        &topics.version,
+                        version.as_bytes(),
+                        QualityOfService::Qos1,
+                        true,
+                    )
+                    .await
+            }
+            // Only if the payload outgrows the buffer.
+            Err(_) => Ok(()),
+        };
+        if published.is_err() {
+            // Something went wrong, retry the connection.
+            discard_commands_while(&mut ssrcontrol_command_subscriber, Timer::after_secs(10)).await;
+            continue 'connect;
+        }
+
+        // Publish the heater mode, which is otherwise only published as it changes.
+        let mut mode_published = None;
+        if publish_mode(&mut mqtt_client, &topics, state, None, &mut mode_published)
             .await
             .is_err()
         {
             // Something went wrong, retry the connection.
-            Timer::after_secs(10).await;
+            discard_commands_while(&mut ssrcontrol_command_subscriber, Timer::after_secs(10)).await;
             continue 'connect;
         }
 
+        // Publish why the device reset, and when it came up.
+        if !boot_published {
+            let boot = BootReport {
+                reset_reason,
+                uptime_secs: Instant::now().as_secs(),
+                brownouts: settings.lock().await.brownouts,
+            };
+            let published = match serde_json_core::to_string::<_, MQTT_JSON_MAX_LEN>(&boot) {
+                Ok(boot) => {
+                    mqtt_client
+                        .publish(&topics.boot, boot.as_bytes(), QualityOfService::Qos1, true)
+                        .await
+                }
+                // Only if the payload outgrows the buffer.
+                Err(_) => Ok(()),
+            };
+            if published.is_err() {
+                // Something went wrong, retry the connection.
+                discard_commands_while(&mut ssrcontrol_command_subscriber, Timer::after_secs(10))
+                    .await;
+                continue 'connect;
+            }
+            boot_published = true;
+        }
+
+        // Subscribe to the command topics.
+        for (topic, _command) in &topics.commands {
+            if mqtt_client
+                .subscribe(topic, QualityOfService::Qos1)
+                .await
+                .is_err()
+            {
+                // Something went wrong, retry the connection.
+                discard_commands_while(&mut ssrcontrol_command_subscriber, Timer::after_secs(10))
+                    .await;
+                continue 'connect;
+            }
+        }
+
+        // Consecutive failed publishes, reset on any successful exchange with the broker.
+        let mut publish_failures: u32 = 0;
+
         // We continue this loop if the mqtt client throws an error but did not disconnect.
         'main: loop {
             let catch: Result<(), ClientError> = async {
-                let mut ping_fut = Timer::after(MQTT_PING_INTERVAL);
-                let mut duty_periodic_fut = Timer::after(MQTT_DUTY_TIMEOUT);
-                // Poor API design of mountain-mqtt forces us to poll periodically.
-                let mut poll_fut = Timer::after_secs(1);
+                // The interval can change at runtime, and applies from the next ping.
+                let ping_interval = settings.lock().await.mqtt_ping_interval.secs();
+                let mut ping_fut = Timer::after_secs(ping_interval as u64);
+                let mut duty_schedule = PublishSchedule::new(MQTT_DUTY_PUBLISH);
+                let mut temp_schedule = PublishSchedule::new(MQTT_TEMP_PUBLISH);
+                let mut ssr_status_schedule = PublishSchedule::new(MQTT_SSR_STATUS_PUBLISH);
+                let mut poll_fut = Timer::after(MQTT_POLL_INTERVAL);
+                let mut heartbeat_fut = Timer::after(MQTT_HEARTBEAT_INTERVAL);
+                // The last published duty and temperature, and when, for throttling.
+                let mut duty_published: Option<(f32, Instant)> = None;
+                // Set while a duty change is held back, until the schedule flushes it.
+                let mut duty_held = false;
+                let mut temp_published: Option<(f32, Instant)> = None;
 
                 '_select: loop {
+                    // These are recreated on every pass, and are all cancel-safe: a watch keeps
+                    // its latest value, the command channel and the log queue keep unread
+                    // messages, and waiting on the socket consumes nothing. The timers above are
+                    // held across passes, and only reset explicitly by the arms below. The
+                    // publish schedules keep their due instants instead, from which their timers
+                    // are recreated.
+                    let periodic_fut = select::select3(
+                        duty_schedule.due(),
+                        temp_schedule.due(),
+                        ssr_status_schedule.due(),
+                    );
                     let duty_fut = ssrcontrol_duty_receiver.changed();
                     let temp_fut = tempsensor_receiver.changed();
                     let net_fut = netstatus_receiver.changed();
-                    let log_fut = logwatch_receiver.changed();
+                    let log_fut = logqueue_receiver.receive();
                     let ssrcmd_fut = ssrcontrol_command_subscriber.next_message();
+                    let ssrstatus_fut = select::select3(
+                        ssrcontrol_status_receiver.changed(),
+                        ssrcontrol_alert_receiver.changed(),
+                        tempalarm_receiver.changed(),
+                    );
+                    let monitor_fut = select::select(
+                        heapstatus_receiver.changed(),
+                        gpioinput_receiver.changed(),
+                    );
+                    let timer_fut =
+                        select::select3(&mut ping_fut, &mut heartbeat_fut, toggle_signal.wait());
+                    let incoming_fut = select::select3(
+                        requests.receive(),
+                        wait_read_ready(&socket),
+                        &mut poll_fut,
+                    );
 
-                    match select8(
+                    let publish_result = match select10(
                         duty_fut,
-                        &mut duty_periodic_fut,
+                        periodic_fut,
                         temp_fut,
                         net_fut,
                         log_fut,
                         ssrcmd_fut,
-                        &mut ping_fut,
-                        &mut poll_fut,
+                        ssrstatus_fut,
+                        monitor_fut,
+                        timer_fut,
+                        incoming_fut,
                     )
                     .await
                     {
-                        // Publish duty updates.
-                        Either8::First(duty) => {
-                            mqtt_client
-                                .publish(
-                                    topic_heater!("duty"),
-                                    duty.to_string().as_bytes(),
-                                    QualityOfService::Qos0,
+                        // Publish duty updates, and retain where the last one came from.
+                        // A remote's updates are always confirmed, so they're never held back.
+                        Either10::First(update)
+                            if update.source != DutySource::Remote
+                                && (!duty_schedule.on_change()
+                                    || !should_publish(
+                                        duty_published,
+                                        update.duty.percent() as f32,
+                                        MQTT_DUTY_PUBLISH_DELTA as f32,
+                                        MQTT_DUTY_PUBLISH_INTERVAL,
+                                    )) =>
+                        {
+                            // Held back. The latest duty is published once the interval is up,
+                            // unless the duty is only published periodically.
+                            if let (true, Some((_, published_at))) =
+                                (duty_schedule.on_change(), duty_published)
+                            {
+                                duty_schedule.hold_until(published_at + MQTT_DUTY_PUBLISH_INTERVAL);
+                                duty_held = true;
+                            }
+                            Ok(())
+                        }
+                        Either10::First(update) => {
+                            duty_schedule.published();
+                            duty_published = Some((update.duty.percent() as f32, Instant::now()));
+                            duty_held = false;
+
+                            let source =
+                                duty_source_payload(&update, tempsensor_receiver.try_get());
+                            // Tag the confirmation of a remote's update with its identifier,
+                            // so that the remote can correlate it.
+                            let remote_id = match update.source {
+                                DutySource::Remote => {
+                                    state.lock().await.remote_id().map(String::from)
+                                }
+                                _ => None,
+                            };
+                            let properties = remote_properties(remote_id.as_deref());
+
+                            match mqtt_client
+                                .publish_with_properties(
+                                    &topics.duty,
+                                    update.duty.to_string().as_bytes(),
+                                    MQTT_CONFIRM_QOS,
                                     false,
+                                    properties,
                                 )
-                                .await?;
+                                .await
+                            {
+                                Ok(()) => {
+                                    mqtt_client
+                                        .publish(
+                                            &topics.duty_source,
+                                            source.as_bytes(),
+                                            QualityOfService::Qos1,
+                                            true,
+                                        )
+                                        .await?;
+                                    // A duty change usually comes with a change of mode.
+                                    publish_mode(
+                                        &mut mqtt_client,
+                                        &topics,
+                                        state,
+                                        remote_id.as_deref(),
+                                        &mut mode_published,
+                                    )
+                                    .await
+                                }
+                                Err(error) => Err(error),
+                            }
+                        }
 
-                            // Reset the duty periodic timer.
-                            duty_periodic_fut = Timer::after(MQTT_DUTY_TIMEOUT);
+                        // Publish the current duty if no updates were issued recently, or a change
+                        // that was held back.
+                        Either10::Second(select::Either3::First(_due)) => {
+                            duty_schedule.published();
+                            match ssrcontrol_duty_receiver.try_get() {
+                                // A held-back change also updates the retained source.
+                                Some(update) if duty_held => {
+                                    duty_published =
+                                        Some((update.duty.percent() as f32, Instant::now()));
+                                    duty_held = false;
+                                    let source =
+                                        duty_source_payload(&update, tempsensor_receiver.try_get());
+                                    mqtt_client
+                                        .publish(
+                                            &topics.duty,
+                                            update.duty.to_string().as_bytes(),
+                                            MQTT_CONFIRM_QOS,
+                                            false,
+                                        )
+                                        .await?;
+                                    mqtt_client
+                                        .publish(
+                                            &topics.duty_source,
+                                            source.as_bytes(),
+                                            QualityOfService::Qos1,
+                                            true,
+                                        )
+                                        .await
+                                }
+                                Some(update) => {
+                                    duty_published =
+                                        Some((update.duty.percent() as f32, Instant::now()));
+                                    mqtt_client
+                                        .publish(
+                                            &topics.duty,
+                                            update.duty.to_string().as_bytes(),
+                                            QualityOfService::Qos0,
+                                            false,
+                                        )
+                                        .await
+                                }
+                                None => Ok(()),
+                            }
                         }
 
-                        // Publish the current duty if no updates were issued recently.
-                        Either8::Second(_timeout) => {
-                            if let Some(duty) = ssrcontrol_duty_receiver.try_get() {
+                        // Publish the current temperature on schedule.
+                        Either10::Second(select::Either3::Second(_due)) => {
+                            temp_schedule.published();
+                            match tempsensor_receiver.try_get() {
+                                Some(Ok(data)) => {
+                                    temp_published = Some((data.temperature, Instant::now()));
+                                    let unit = settings.lock().await.temp_unit;
+                                    publish_temperature(&mut mqtt_client, &topics, &data, unit)
+                                        .await
+                                }
+                                _ => Ok(()),
+                            }
+                        }
+
+                        // Publish the SSR status on schedule.
+                        Either10::Second(select::Either3::Third(_due)) => {
+                            ssr_status_schedule.published();
+                            match ssrcontrol_status_receiver.try_get() {
+                                Some(status) => {
+                                    mqtt_client
+                                        .publish(
+                                            &topics.ssr_status,
+                                            format!("{status:?}").as_bytes(),
+                                            QualityOfService::Qos1,
+                                            true,
+                                        )
+                                        .await
+                                }
+                                None => Ok(()),
+                            }
+                        }
+
+                        // Publish case temperature sensor readings, in the configured unit.
+                        Either10::Third(temp) => {
+                            stats.temp_observed_mqtt.increment();
+                            match temp {
+                                Ok(data)
+                                    if temp_schedule.on_change()
+                                        && should_publish(
+                                            temp_published,
+                                            data.temperature,
+                                            MQTT_TEMP_PUBLISH_DELTA,
+                                            MQTT_TEMP_PUBLISH_INTERVAL,
+                                        ) =>
+                                {
+                                    temp_schedule.published();
+                                    temp_published = Some((data.temperature, Instant::now()));
+                                    let unit = settings.lock().await.temp_unit;
+                                    publish_temperature(&mut mqtt_client, &topics, &data, unit)
+                                        .await
+                                }
+                                // Errors, and changes held back by the throttle or the policy.
+                                _ => Ok(()),
+                            }
+                        }
+
+                        // Publish network status updates.
+                        Either10::Fourth(net) => {
+                            stats.net_observed_mqtt.increment();
+                            match serde_json_core::to_string::<_, MQTT_JSON_MAX_LEN>(&net) {
+                                Ok(json) => {
+                                    mqtt_client
+                                        .publish(
+                                            &topics.net,
+                                            json.as_bytes(),
+                                            QualityOfService::Qos0,
+                                            false,
+                                        )
+                                        .await
+                                }
+                                // Only if the payload outgrows the buffer.
+                                Err(_) => Ok(()),
+                            }
+                        }
+
+                        // Publish logs. Dropped while the heap is low.
+                        Either10::Fifth(log) => {
+                            stats.log_observed_mqtt.increment();
+                            if heap_monitor::is_degraded() {
+                                Ok(())
+                            } else {
+                                // Long records are cut short, rather than failing to publish.
+                                let log = truncate_payload(
+                                    format!("{log}"),
+                                    payload_capacity(&topics.log),
+                                );
                                 mqtt_client
                                     .publish(
-                                        topic_heater!("duty"),
-                                        duty.to_string().as_bytes(),
+                                        &topics.log,
+                                        log.as_bytes(),
                                         QualityOfService::Qos0,
                                         false,
                                     )
-                                    .await?;
+                                    .await
                             }
-
-                            // Reset the duty periodic timer.
-                            duty_periodic_fut = Timer::after(MQTT_DUTY_TIMEOUT);
                         }
 
-                        // Publish case temperature sensor readings.
-                        Either8::Third(temp) => {
-                            if let Ok(data) = temp {
+                        // Publish SSR commands.
+                        Either10::Sixth(ssr_cmd) => match ssr_cmd {
+                            WaitResult::Message(cmd) => {
+                                let cmd = format!(
+                                    r#"{{"command":"{}","reason":"{}"}}"#,
+                                    cmd.name(),
+                                    cmd.reason()
+                                );
                                 mqtt_client
                                     .publish(
-                                        topic_heater!("temp/case"),
-                                        data.temperature.to_string().as_bytes(),
+                                        &topics.ssr,
+                                        cmd.as_bytes(),
                                         QualityOfService::Qos0,
                                         false,
                                     )
-                                    .await?;
+                                    .await
+                            }
+                            WaitResult::Lagged(missed) => {
+                                stats.ssr_command_lagged.add(missed as u32);
+                                Ok(())
                             }
+                        },
+
+                        // Publish the SSR lock and fault status, retained.
+                        // A fault overrides the mode, which is published below as it changes.
+                        Either10::Seventh(select::Either3::First(_status))
+                            if !ssr_status_schedule.on_change() =>
+                        {
+                            Ok(())
+                        }
+                        Either10::Seventh(select::Either3::First(status)) => {
+                            ssr_status_schedule.published();
+                            mqtt_client
+                                .publish(
+                                    &topics.ssr_status,
+                                    format!("{status:?}").as_bytes(),
+                                    QualityOfService::Qos1,
+                                    true,
+                                )
+                                .await
                         }
 
-                        // Publish network status updates.
-                        Either8::Fourth(net) => {
+                        // Publish alerts when a safety mechanism overrides the commanded duty.
+                        Either10::Seventh(select::Either3::Second(alert)) => {
+                            let alert = format!(
+                                r#"{{"reason":"{}","commanded":{},"applied":{}}}"#,
+                                alert.reason.description(),
+                                alert.commanded,
+                                alert.applied
+                            );
+                            mqtt_client
+                                .publish(
+                                    &topics.alert,
+                                    alert.as_bytes(),
+                                    QualityOfService::Qos1,
+                                    false,
+                                )
+                                .await
+                        }
+
+                        // Publish soft temperature alarms as they're raised and cleared.
+                        // Temperatures are in Celsius, and a disabled threshold is null.
+                        Either10::Seventh(select::Either3::Third(alarm)) => {
+                            let threshold = match alarm.threshold {
+                                Some(threshold) => threshold.to_string(),
+                                None => "null".into(),
+                            };
+                            let alarm = format!(
+                                r#"{{"alarm":"{}","active":{},"temperature":{},"threshold":{}}}"#,
+                                alarm.kind.name(),
+                                alarm.active,
+                                alarm.temperature,
+                                threshold
+                            );
+                            mqtt_client
+                                .publish(
+                                    &topics.alarm,
+                                    alarm.as_bytes(),
+                                    QualityOfService::Qos1,
+                                    false,
+                                )
+                                .await
+                        }
+
+                        // Publish heap usage samples. Formatted without allocating, as this
+                        // matters most when the heap runs low.
+                        Either10::Eighth(select::Either::First(heap)) => {
+                            let mut payload = heapless::String::<MQTT_JSON_MAX_LEN>::new();
+                            let _ = write!(
+                                payload,
+                                r#"{{"used":{},"free":{},"min_free":{}}}"#,
+                                heap.used, heap.free, heap.min_free
+                            );
                             mqtt_client
                                 .publish(
-                                    topic_heater!("net"),
-                                    format!("{net:?}").as_bytes(),
+                                    &topics.heap,
+                                    payload.as_bytes(),
                                     QualityOfService::Qos0,
                                     false,
                                 )
-                                .await?;
+                                .await
                         }
 
-                        // Publish logs.
-                        Either8::Fifth(log) => {
+                        // Publish the levels of the expansion inputs, retained.
+                        // E.g. `{"gpio2":"high","gpio4":"low"}`.
+                        Either10::Eighth(select::Either::Second(levels)) => {
+                            let mut payload = heapless::String::<MQTT_JSON_MAX_LEN>::new();
+                            let _ = payload.push('{');
+                            for (index, level) in levels.iter().enumerate() {
+                                let separator = if index > 0 { "," } else { "" };
+                                let name = if level.high { "high" } else { "low" };
+                                let _ =
+                                    write!(payload, r#"{separator}"gpio{}":"{name}""#, level.pin);
+                            }
+                            let _ = payload.push('}');
                             mqtt_client
                                 .publish(
-                                    topic_heater!("log"),
-                                    format!("{log}").as_bytes(),
+                                    &topics.gpio,
+                                    payload.as_bytes(),
+                                    QualityOfService::Qos0,
+                                    true,
+                                )
+                                .await
+                        }
+
+                        // Periodically send a ping to the server.
+                        Either10::Ninth(select::Either3::First(_ping)) => {
+                            mqtt_client.send_ping().await?;
+                            let ping_interval = settings.lock().await.mqtt_ping_interval.secs();
+                            ping_fut = Timer::after_secs(ping_interval as u64);
+                            Ok(())
+                        }
+
+                        // While the heap is low, publish a minimal heartbeat without
+                        // allocating, and skip the state times and uptime.
+                        Either10::Ninth(select::Either3::Second(_heartbeat))
+                            if heap_monitor::is_degraded() =>
+                        {
+                            heartbeat_fut = Timer::after(MQTT_HEARTBEAT_INTERVAL);
+                            heartbeat_count = heartbeat_count.wrapping_add(1);
+
+                            let mut payload = heapless::String::<MQTT_JSON_MAX_LEN>::new();
+                            let _ = write!(
+                                payload,
+                                r#"{{"uptime_secs":{},"count":{},"degraded":true}}"#,
+                                Instant::now().as_secs(),
+                                heartbeat_count
+                            );
+                            mqtt_client
+                                .publish(
+                                    &topics.heartbeat,
+                                    payload.as_bytes(),
                                     QualityOfService::Qos0,
                                     false,
                                 )
-                                .await?;
+                                .await
                         }
 
-                        // Publish SSR commands.
-                        Either8::Sixth(ssr_cmd) => {
-                            if let WaitResult::Message(cmd) = ssr_cmd {
-                                mqtt_client
+                        // Periodically publish a heartbeat.
+                        Either10::Ninth(select::Either3::Second(_heartbeat)) => {
+                            heartbeat_fut = Timer::after(MQTT_HEARTBEAT_INTERVAL);
+                            heartbeat_count = heartbeat_count.wrapping_add(1);
+
+                            // Include the time spent in each heater state, in seconds.
+                            let state_times = HeaterState::NAMES
+                                .iter()
+                                .zip(state.lock().await.state_times())
+                                .map(|(name, time)| format!(r#""{name}":{}"#, time.as_secs()))
+                                .collect::<Vec<_>>()
+                                .join(",");
+                            let heartbeat = format!(
+                                r#"{{"uptime_secs":{},"count":{},"state_secs":{{{}}}}}"#,
+                                Instant::now().as_secs(),
+                                heartbeat_count,
+                                state_times
+                            );
+                            // Human-readable uptime goes out on the same schedule.
+                            let uptime = format_milliseconds_to_hms(Instant::now().as_millis());
+                            match mqtt_client
+                                .publish(
+                                    &topics.heartbeat,
+                                    heartbeat.as_bytes(),
+                                    QualityOfService::Qos0,
+                                    false,
+                                )
+                                .await
+                            {
+                                Ok(()) => {
+                                    mqtt_client
+                                        .publish(
+                                            &topics.uptime,
+                                            uptime.as_bytes(),
+                                            QualityOfService::Qos0,
+                                            false,
+                                        )
+                                        .await
+                                }
+                                Err(error) => Err(error),
+                            }
+                        }
+
+                        // Go offline if disabled at runtime.
+                        Either10::Ninth(select::Either3::Third(())) => {
+                            if settings.lock().await.interfaces.mqtt {
+                                Ok(())
+                            } else {
+                                // A clean disconnect doesn't trigger the LWT, so mark the
+                                // client offline first. Errors don't matter, we're leaving.
+                                let _ = mqtt_client
                                     .publish(
-                                        topic_heater!("ssr"),
-                                        format!("{cmd:?}").as_bytes(),
-                                        QualityOfService::Qos0,
-                                        false,
+                                        &topics.status,
+                                        "offline".as_bytes(),
+                                        QualityOfService::Qos1,
+                                        true,
                                     )
-                                    .await?;
+                                    .await;
+                                let _ = mqtt_client.disconnect().await;
+                                socket.borrow_mut().close();
+                                return Ok(());
                             }
                         }
 
-                        // Periodically send a ping to the server.
-                        Either8::Seventh(_ping) => {
-                            mqtt_client.send_ping().await?;
-                            ping_fut = Timer::after_secs(10);
+                        // Publish the stored log on request, split over as many messages as it
+                        // takes. Each is tagged with its part, as "chunk:<n>/<total>".
+                        Either10::Tenth(select::Either3::First(MqttRequest {
+                            action: MqttAction::LogDump,
+                            remote_id,
+                        })) => {
+                            if heap_monitor::is_degraded() {
+                                memlog.warn("mqtt log dump skipped, heap low");
+                                Ok(())
+                            } else {
+                                let chunks =
+                                    log_dump_chunks(memlog, payload_capacity(&topics.log_dump));
+                                let mut result = Ok(());
+                                for (index, chunk) in chunks.iter().enumerate() {
+                                    let part = format!("{}/{}", index + 1, chunks.len());
+                                    let mut properties = heapless::Vec::<_, 2>::new();
+                                    let _ = properties.push(PublishProperty::UserProperty(
+                                        UserProperty::new(StringPair::new("chunk", &part)),
+                                    ));
+                                    if let Some(remote_id) = &remote_id {
+                                        let _ = properties.push(PublishProperty::UserProperty(
+                                            UserProperty::new(StringPair::new("remote", remote_id)),
+                                        ));
+                                    }
+
+                                    result = mqtt_client
+                                        .publish_with_properties(
+                                            &topics.log_dump,
+                                            chunk.as_bytes(),
+                                            MQTT_CONFIRM_QOS,
+                                            false,
+                                            properties,
+                                        )
+                                        .await;
+                                    if result.is_err() {
+                                        break;
+                                    }
+                                }
+                                result
+                            }
+                        }
+
+                        // Apply the other commands received by the event handler, and confirm them.
+                        Either10::Tenth(select::Either3::First(request)) => {
+                            let confirmation = match request.action {
+                                // Hand over to the relay, as this loop also drains a subscriber
+                                // of the command channel and can't wait on it.
+                                MqttAction::Lock => relay_command(
+                                    ssrcontrol_command_relay,
+                                    SsrCommand::Lock(LockReason::Manual),
+                                    memlog,
+                                )
+                                .then_some((&topics.lock, "locked")),
+                                MqttAction::Unlock => relay_command(
+                                    ssrcontrol_command_relay,
+                                    SsrCommand::Unlock(LockReason::Manual),
+                                    memlog,
+                                )
+                                .then_some((&topics.lock, "unlocked")),
+                                MqttAction::Mode(mode) => {
+                                    let result = {
+                                        let mut state = state.lock().await;
+                                        match mode {
+                                            MqttMode::Manual => {
+                                                let duty = state.duty();
+                                                state.transition_to_manual(duty);
+                                                Ok(())
+                                            }
+                                            MqttMode::Thermostat => {
+                                                state.transition_to_thermostat()
+                                            }
+                                        }
+                                    };
+                                    match result {
+                                        // Confirmed with the mode report, which is retained.
+                                        Ok(()) => {
+                                            publish_mode(
+                                                &mut mqtt_client,
+                                                &topics,
+                                                state,
+                                                request.remote_id.as_deref(),
+                                                &mut mode_published,
+                                            )
+                                            .await?;
+                                            None
+                                        }
+                                        Err(error) => {
+                                            memlog.warn(format!("mqtt mode not set: {error}"));
+                                            None
+                                        }
+                                    }
+                                }
+                                // Handled above, and answered with the dump itself.
+                                MqttAction::LogDump => None,
+                                MqttAction::ZoneDuty(zone, duty) => {
+                                    match set_zone_duty(&ssrcontrol_zone_sender, zone, duty) {
+                                        Ok(()) => memlog.info(format!(
+                                            "ssr: zone {zone} duty set to {duty}% over mqtt"
+                                        )),
+                                        Err(error) => {
+                                            memlog.warn(format!("mqtt zone duty not set: {error}"))
+                                        }
+                                    }
+                                    None
+                                }
+                            };
+                            match confirmation {
+                                Some((topic, value)) => {
+                                    mqtt_client
+                                        .publish_with_properties(
+                                            topic,
+                                            value.as_bytes(),
+                                            MQTT_CONFIRM_QOS,
+                                            false,
+                                            remote_properties(request.remote_id.as_deref()),
+                                        )
+                                        .await
+                                }
+                                None => Ok(()),
+                            }
                         }
 
-                        // Periodic poll for MQTT messages.
-                        Either8::Eighth(_timeout) => {
+                        // Handle incoming MQTT messages as soon as they arrive.
+                        Either10::Tenth(_incoming) => {
+                            // Skip packets the client would fail on, such as a large retained message.
+                            match skip_oversized_packet(&socket).await {
+                                Ok(Some(length)) => memlog.warn(format!(
+                                    "skipped incoming mqtt packet of {length} bytes, larger than the {MQTT_BUFFER_SIZE} byte buffer"
+                                )),
+                                Ok(None) => (),
+                                // The socket was closed, which the client reports below.
+                                Err(_) => (),
+                            }
                             mqtt_client.poll(false).await?;
-                            poll_fut = Timer::after_secs(1);
+                            poll_fut = Timer::after(MQTT_POLL_INTERVAL);
+                            Ok(())
+                        }
+                    };
+
+                    // Catch up on transitions that had nothing else to report them, such as a mode
+                    // change on the console with its duty change held back, or a remote expiring.
+                    let mode = state.lock().await.mode();
+                    let publish_result = match publish_result {
+                        Ok(()) if mode_published.as_ref() != Some(&mode) => {
+                            publish_mode(&mut mqtt_client, &topics, state, None, &mut mode_published)
+                                .await
+                        }
+                        result => result,
+                    };
+
+                    // A failed publish loses only that message. Keep streaming other events
+                    // through a brief broker hiccup, and only give up if failures persist.
+                    // Note: failures are not logged here, as the log is itself published.
+                    match publish_result {
+                        Ok(()) => publish_failures = 0,
+                        Err(error @ ClientError::Disconnected(_)) => return Err(error),
+                        // The payload can't fit in the buffer, and would fail again on a retry.
+                        // The broker is fine, so this doesn't count as a failure.
+                        Err(ClientError::PacketWrite(PacketWriteError::Overflow)) => {
+                            memlog.warn("mqtt publish dropped, payload larger than the buffer");
+                        }
+                        Err(error) => {
+                            publish_failures += 1;
+                            if publish_failures >= MQTT_PUBLISH_MAX_FAILURES {
+                                return Err(error);
+                            }
                         }
                     }
                 } // 'select loop
@@ -353,22 +1570,233 @@ pub async fn run(
                     memlog.info(format!("mqtt client disconnected: {reason}"));
                     continue 'connect;
                 }
+                // The rest of the packet is still on the socket, so the stream is out of sync.
+                Err(ClientError::PacketRead(PacketReadError::PacketTooLargeForBuffer)) => {
+                    memlog.warn("incoming mqtt packet larger than the buffer, reconnecting");
+                    continue 'connect;
+                }
+                Err(error) if publish_failures >= MQTT_PUBLISH_MAX_FAILURES => {
+                    memlog.warn(format!(
+                        "mqtt publish failed {publish_failures} times in a row, reconnecting: {error}"
+                    ));
+                    continue 'connect;
+                }
+                // The broker closed the connection. The socket stays ready to read at EOF, so
+                // retrying on it would spin. Start over with a new one.
+                Err(error) if !socket.borrow().may_recv() => {
+                    memlog.info(format!("mqtt connection closed by the broker: {error}"));
+                    continue 'connect;
+                }
                 Err(error) => {
                     memlog.info(format!("mqtt client error: {error}"));
                     continue 'main;
                 }
-                Ok(()) => (),
+                // Only returned once disabled at runtime.
+                Ok(()) => continue 'connect,
             }
         } // 'main loop
     } // 'connect loop
 }
 
+/// The retained report of the last duty change, with the duty after derating and its source.
+fn duty_source_payload(
+    update: &DutyUpdate,
+    reading: Option<TempSensorReading>,
+) -> heapless::String<MQTT_JSON_MAX_LEN> {
+    let report = DutySourceReport {
+        duty: update.duty,
+        applied: applied_duty(update.duty, reading),
+        source: update.source.name(),
+        uptime_secs: update.at.as_secs(),
+    };
+    // The fields are all short, so this always fits.
+    serde_json_core::to_string(&report).unwrap_or_default()
+}
+
+/// Formats a temperature with a number of decimal places, or with full precision if None.
+fn format_temperature(temperature: f32, decimals: Option<usize>) -> String {
+    match decimals {
+        Some(decimals) => format!("{temperature:.decimals$}"),
+        None => temperature.to_string(),
+    }
+}
+
+/// Publishes the heater mode, retained, as named by `HeaterMode`, and records it in `published`.
+/// The reason for a fault is on the `ssr/status` topic.
+async fn publish_mode(
+    mqtt_client: &mut MqttClient<'_, '_>,
+    topics: &Topics,
+    state: SharedState,
+    remote_id: Option<&str>,
+    published: &mut Option<HeaterMode>,
+) -> Result<(), ClientError> {
+    let mode = state.lock().await.mode();
+    mqtt_client
+        .publish_with_properties(
+            &topics.mode,
+            mode.to_string().as_bytes(),
+            QualityOfService::Qos1,
+            true,
+            remote_properties(remote_id),
+        )
+        .await?;
+    *published = Some(mode);
+    Ok(())
+}
+
+/// Publishes a temperature reading in the given unit, tagging the last good reading if stale.
+///
+/// Rounded to `MQTT_TEMP_DECIMALS`, with the full precision on a separate topic if so.
+async fn publish_temperature(
+    mqtt_client: &mut MqttClient<'_, '_>,
+    topics: &Topics,
+    data: &TempData,
+    unit: TempUnit,
+) -> Result<(), ClientError> {
+    let stale_secs = data.stale.map(|age| age.as_secs().to_string());
+    // Properties can't be cloned, so build them anew for each topic.
+    let properties = || {
+        let mut properties = heapless::Vec::<_, 2>::new();
+        let _ = properties.push(PublishProperty::UserProperty(UserProperty::new(
+            StringPair::new("unit", unit.symbol()),
+        )));
+        if let Some(stale_secs) = &stale_secs {
+            let _ = properties.push(PublishProperty::UserProperty(UserProperty::new(
+                StringPair::new("stale", stale_secs),
+            )));
+        }
+        properties
+    };
+
+    let temperature = unit.convert(data.temperature);
+    mqtt_client
+        .publish_with_properties(
+            &topics.temp_case,
+            format_temperature(temperature, MQTT_TEMP_DECIMALS).as_bytes(),
+            QualityOfService::Qos0,
+            false,
+            properties(),
+        )
+        .await?;
+    if MQTT_TEMP_DECIMALS.is_none() {
+        return Ok(());
+    }
+
+    mqtt_client
+        .publish_with_properties(
+            &topics.temp_case_precise,
+            format_temperature(temperature, None).as_bytes(),
+            QualityOfService::Qos0,
+            false,
+            properties(),
+        )
+        .await
+}
+
+/// When a topic is published.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PublishPolicy {
+    /// As the value changes, subject to any throttling on the topic.
+    OnChange,
+    /// At this interval, regardless of changes.
+    Periodic(Duration),
+    /// As the value changes, and also at this interval after the last publish.
+    Both(Duration),
+}
+
+impl PublishPolicy {
+    fn on_change(self) -> bool {
+        matches!(self, PublishPolicy::OnChange | PublishPolicy::Both(_))
+    }
+
+    fn period(self) -> Option<Duration> {
+        match self {
+            PublishPolicy::OnChange => None,
+            PublishPolicy::Periodic(period) | PublishPolicy::Both(period) => Some(period),
+        }
+    }
+}
+
+/// Tracks when a topic is next due to be published, following its `PublishPolicy`.
+struct PublishSchedule {
+    policy: PublishPolicy,
+    due: Instant,
+}
+
+impl PublishSchedule {
+    fn new(policy: PublishPolicy) -> Self {
+        let mut schedule = PublishSchedule {
+            policy,
+            due: Instant::MAX,
+        };
+        schedule.published();
+        schedule
+    }
+
+    /// Whether changes are published as they happen.
+    fn on_change(&self) -> bool {
+        self.policy.on_change()
+    }
+
+    /// Completes when a publish is due. Never completes for change-driven topics with nothing
+    /// held back.
+    fn due(&self) -> Timer {
+        Timer::at(self.due)
+    }
+
+    /// Brings the next publish forward, to flush a change that was held back.
+    fn hold_until(&mut self, at: Instant) {
+        self.due = self.due.min(at);
+    }
+
+    /// Records a publish, and schedules the next periodic one.
+    fn published(&mut self) {
+        self.due = match self.policy.period() {
+            Some(period) => Instant::now() + period,
+            None => Instant::MAX,
+        };
+    }
+}
+
+/// Whether a changed value should be published now, rather than held back to coalesce changes.
+fn should_publish(
+    published: Option<(f32, Instant)>,
+    value: f32,
+    delta: f32,
+    interval: Duration,
+) -> bool {
+    match published {
+        None => true,
+        Some((published_value, published_at)) => {
+            (value - published_value).abs() >= delta || Instant::now() >= published_at + interval
+        }
+    }
+}
+
 struct MqttHandler {
-    ssrcontrol_duty_sender: SsrDutyDynSender,
+    ssrcontrol_duty_limiter: SsrDutyLimiter,
+    commands: [(String, MqttCommand); MqttCommand::ALL.len()],
+    requests: MqttRequestChannel,
     memlog: SharedLogger,
     state: SharedState,
 }
 
+impl MqttHandler {
+    /// Rejects a command while a remote other than the sender holds control.
+    async fn check_control(&self, control_remote: Option<&str>) -> Result<(), EventHandlerError> {
+        let state = self.state.lock().await;
+        match state.remote_id() {
+            Some(remote_id) if control_remote != Some(remote_id) => {
+                self.memlog.warn(format!(
+                    "mqtt command ignored, remote '{remote_id}' is in control"
+                ));
+                Err(EventHandlerError::UnexpectedApplicationMessage)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 impl<const P: usize> EventHandler<P> for MqttHandler {
     async fn handle_event(
         &mut self,
@@ -378,44 +1806,129 @@ impl<const P: usize> EventHandler<P> for MqttHandler {
             return Ok(());
         };
 
-        // Receive SSR duty updates and set the heater duty cycle.
-        if message.topic_name.eq(topic_heater!("duty/set")) {
-            let duty_str = core::str::from_utf8(message.payload)?;
+        let command = self
+            .commands
+            .iter()
+            .find(|(topic, _)| message.topic_name == topic.as_str())
+            .map(|(_, command)| *command);
 
-            let duty: u8 = duty_str
-                .parse()
-                .map_err(|_| EventHandlerError::InvalidApplicationMessage)?;
+        // A retained command is replayed by the broker on every subscription, which would
+        // re-drive the heater with a stale value on each reconnect. Only act on live commands.
+        if command.is_some() && message.retain {
+            self.memlog.warn(format!(
+                "ignored retained command on {}",
+                message.topic_name
+            ));
+            return Ok(());
+        }
 
-            if !((0..=100).contains(&duty)) {
-                return Err(EventHandlerError::UnexpectedApplicationMessage);
-            }
+        // Is there a UserProperty "remote:<id>" indicating that the sender is a remote?
+        let control_remote = find_user_property(&message.properties, "remote", None)
+            .map(|property| property.value());
+
+        let action = match command {
+            // Receive SSR duty updates and set the heater duty cycle.
+            Some(MqttCommand::Duty) => 'duty: {
+                let duty_str = core::str::from_utf8(message.payload)?;
+
+                let duty: Duty = duty_str.parse().map_err(|error| match error {
+                    DutyError::Invalid => EventHandlerError::InvalidApplicationMessage,
+                    DutyError::OutOfRange => EventHandlerError::UnexpectedApplicationMessage,
+                })?;
+
+                // A UserProperty "zone:<n>" sets an extra zone. These are set directly, the heater
+                // state and remotes only cover the main zone.
+                let zone = find_user_property(&message.properties, "zone", None)
+                    .map(|property| parse_zone(property.value()))
+                    .transpose()
+                    .map_err(|_| EventHandlerError::InvalidApplicationMessage)?;
+                if let Some(zone @ 1..) = zone {
+                    break 'duty MqttAction::ZoneDuty(zone, duty);
+                }
 
-            // Is there a UserProperty "remote:<id>" indicating that the duty setter is a remote?
-            let control_remote = find_user_property(&message.properties, "remote", None)
-                .map(|property| property.value());
+                let source = if control_remote.is_some() {
+                    DutySource::Remote
+                } else {
+                    DutySource::Mqtt
+                };
 
-            if let Some(remote_id) = control_remote {
-                // The duty sender is a remote.
-                let state_result = self.state.lock().await.remote_update_duty(remote_id, duty);
+                // A remote can cap its own duty with a UserProperty "max_duty:<duty>" as it
+                // takes control, e.g. a scheduler that should never run the heater flat out.
+                let max_duty = find_user_property(&message.properties, "max_duty", None)
+                    .map(|property| property.value().parse::<Duty>())
+                    .transpose()
+                    .map_err(|_| EventHandlerError::InvalidApplicationMessage)?;
+
+                let state_result = {
+                    let mut state = self.state.lock().await;
+                    match control_remote {
+                        // The duty sender is a remote. Its duty may be clamped to its limit.
+                        Some(remote_id) => state.remote_update_duty(remote_id, duty, max_duty),
+                        // No remote indicator means the duty setting is "manual".
+                        None => state.manual_update_duty(duty).map(|()| duty),
+                    }
+                };
+
+                // Rejected if another source is in control.
+                let applied = match state_result {
+                    Ok(applied) => applied,
+                    Err(error) => {
+                        self.memlog.warn(format!("state error: {error}"));
+                        return Err(EventHandlerError::UnexpectedApplicationMessage);
+                    }
+                };
+                if applied < duty {
+                    self.memlog.info(format!(
+                        "remote duty {duty}% clamped to its limit of {applied}%"
+                    ));
+                }
 
-                if let Err(error) = state_result {
-                    self.memlog.warn(format!("state error: {error}"));
+                self.ssrcontrol_duty_limiter.write(applied, source);
+                return Ok(());
+            }
+            Some(MqttCommand::Lock) => {
+                self.check_control(control_remote).await?;
+                MqttAction::Lock
+            }
+            Some(MqttCommand::Unlock) => {
+                self.check_control(control_remote).await?;
+                // Only releases a manual lock. Faults are cleared on the console.
+                let status = self.state.lock().await.ssr_status();
+                if status != SsrStatus::Locked(LockReason::Manual) {
+                    self.memlog
+                        .warn("mqtt unlock ignored, the ssr is not locked by an operator");
                     return Err(EventHandlerError::UnexpectedApplicationMessage);
                 }
-            } else {
-                // No remote indicator means the duty setting is "manual".
-                self.state.lock().await.transition_to_manual(duty);
+                MqttAction::Unlock
             }
+            Some(MqttCommand::LogRequest) => MqttAction::LogDump,
+            Some(MqttCommand::Mode) => {
+                let mode = match core::str::from_utf8(message.payload)?.trim() {
+                    "manual" => MqttMode::Manual,
+                    "thermostat" => MqttMode::Thermostat,
+                    _ => return Err(EventHandlerError::InvalidApplicationMessage),
+                };
+                self.check_control(control_remote).await?;
+                MqttAction::Mode(mode)
+            }
+            None => {
+                // Unrecognized topics.
+                self.memlog
+                    .warn(format!("unexpected topic: {}", message.topic_name));
 
-            self.ssrcontrol_duty_sender.send(duty);
-            return Ok(());
-        }
-
-        // Unrecognized topics.
-        self.memlog
-            .warn(format!("unexpected topic: {}", message.topic_name));
+                // Note: we deliberately do not error on an unexpected topic.
+                return Ok(());
+            }
+        };
 
-        // Note: we deliberately do not error on an unexpected topic.
+        // The run loop applies the command and publishes the confirmation.
+        let request = MqttRequest {
+            action,
+            remote_id: control_remote.map(String::from),
+        };
+        if self.requests.try_send(request).is_err() {
+            self.memlog.warn("mqtt command queue full, command dropped");
+        }
 
         Ok(())
     }
@@ -4,7 +4,8 @@ use esp_hal::rng::Rng;
 use esp_wifi::wifi;
 
 /// Maximum number of sockets to allocate memory for.
-const NET_SOCKETS: usize = 3;
+// DHCP, DNS, the MQTT connection, and an ICMP socket for pinging the broker.
+const NET_SOCKETS: usize = 4;
 use crate::config::NET_CONFIG;
 
 pub async fn init(
@@ -1,15 +1,79 @@
-use alloc::boxed::Box;
+use crate::{memlog::SharedLogger, stats::SharedStats, task::wifi::WifiReconnectSignal};
+use alloc::{boxed::Box, format};
 use embassy_net as net;
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, watch};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
+use serde::{Serialize, Serializer, ser::SerializeStruct};
 
 /// How often to check for changes in the network status.
 const NET_MONITOR_INTERVAL: Duration = Duration::from_secs(5);
+/// Reconnect the WiFi if the network has been down (no link or no IPv4 address) for this long.
+/// Doubles after each reconnect that fails to bring the network back, up to `NET_DOWN_TIMEOUT_MAX`.
+const NET_DOWN_TIMEOUT: Duration = Duration::from_secs(120);
+const NET_DOWN_TIMEOUT_MAX: Duration = Duration::from_secs(1800);
+/// Reboot if this many WiFi reconnects in a row fail to bring the network back.
+/// Only if the network was up since boot, so that a missing access point doesn't reboot forever.
+const NET_MAX_RECONNECTS: u32 = 3;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NetworkStatus {
     link_up: bool,
     ip_config: Option<embassy_net::StaticConfigV4>,
+    /// Only set if an IPv6 address is configured in `NET_CONFIG`.
+    ip_config_v6: Option<embassy_net::StaticConfigV6>,
+}
+
+/// Serializes as `{"link_up":true,"address":"192.168.1.10","prefix_len":24,"gateway":"192.168.1.1",
+/// "address_v6":"2001:db8::10","prefix_len_v6":64}`, with nulls when there is no IP configuration.
+impl Serialize for NetworkStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("NetworkStatus", 6)?;
+        state.serialize_field("link_up", &self.link_up)?;
+        state.serialize_field(
+            "address",
+            &self
+                .ip_config
+                .as_ref()
+                .map(|config| config.address.address()),
+        )?;
+        state.serialize_field(
+            "prefix_len",
+            &self
+                .ip_config
+                .as_ref()
+                .map(|config| config.address.prefix_len()),
+        )?;
+        state.serialize_field(
+            "gateway",
+            &self.ip_config.as_ref().and_then(|config| config.gateway),
+        )?;
+        state.serialize_field(
+            "address_v6",
+            &self
+                .ip_config_v6
+                .as_ref()
+                .map(|config| config.address.address()),
+        )?;
+        state.serialize_field(
+            "prefix_len_v6",
+            &self
+                .ip_config_v6
+                .as_ref()
+                .map(|config| config.address.prefix_len()),
+        )?;
+        state.end()
+    }
+}
+
+impl NetworkStatus {
+    pub fn link_up(&self) -> bool {
+        self.link_up
+    }
+
+    /// The IPv4 address, if one is configured.
+    pub fn ipv4_address(&self) -> Option<embassy_net::Ipv4Cidr> {
+        self.ip_config.as_ref().map(|config| config.address)
+    }
 }
 
 pub type NetStatusWatch<const W: usize> = &'static watch::Watch<NoopRawMutex, NetworkStatus, W>;
@@ -22,25 +86,69 @@ pub fn init<const WATCHERS: usize>() -> NetStatusWatch<WATCHERS> {
 }
 
 // Monitors the network interface and signals changes.
+// Reconnects the WiFi if the network stays down, backing off between attempts, and reboots as a
+// last resort.
 #[embassy_executor::task]
-pub async fn net_monitor(stack: net::Stack<'static>, netstatus_sender: NetStatusDynSender) {
+pub async fn net_monitor(
+    stack: net::Stack<'static>,
+    netstatus_sender: NetStatusDynSender,
+    wifi_reconnect_signal: WifiReconnectSignal,
+    memlog: SharedLogger,
+    stats: SharedStats,
+) {
     let mut status = NetworkStatus {
         link_up: false,
         ip_config: None,
+        ip_config_v6: None,
     };
 
+    // When the network went down, or was last reconnected while down.
+    let mut down_since = Instant::now();
+    let mut reconnects: u32 = 0;
+    // A reboot can't help if the network never came up since the last one.
+    let mut was_up = false;
+
     loop {
         Timer::after(NET_MONITOR_INTERVAL).await;
 
         let new_status = NetworkStatus {
             link_up: stack.is_link_up(),
             ip_config: stack.config_v4(),
+            ip_config_v6: stack.config_v6(),
         };
 
         // Notify if changed.
         if status != new_status {
             netstatus_sender.send(new_status.clone());
+            stats.net_sent.increment();
             status = new_status;
         }
+
+        if status.link_up && status.ip_config.is_some() {
+            down_since = Instant::now();
+            reconnects = 0;
+            was_up = true;
+        } else if down_since.elapsed() >= down_timeout(reconnects) {
+            if was_up && reconnects >= NET_MAX_RECONNECTS {
+                memlog.error(format!(
+                    "net: still down after {reconnects} wifi reconnects, rebooting"
+                ));
+                esp_hal::system::software_reset();
+            }
+
+            reconnects += 1;
+            memlog.warn(format!(
+                "net: down for {}s, reconnecting wifi (attempt {reconnects})",
+                down_since.elapsed().as_secs()
+            ));
+            wifi_reconnect_signal.signal(());
+            down_since = Instant::now();
+        }
     }
 }
+
+/// How long to wait for the network after `reconnects` failed WiFi reconnects.
+fn down_timeout(reconnects: u32) -> Duration {
+    // Capped well past the maximum, to keep the multiplication in range.
+    (NET_DOWN_TIMEOUT * (1 << reconnects.min(8))).min(NET_DOWN_TIMEOUT_MAX)
+}
@@ -1,19 +1,61 @@
 #![allow(clippy::too_many_arguments)]
-use super::{net_monitor::NetStatusDynReceiver, temp_sensor::TempSensorDynReceiver};
+use super::{
+    heap_monitor::HeapStatusDynReceiver,
+    mqtt::{self, MqttToggleSignal},
+    net_monitor::NetStatusDynReceiver,
+    temp_sensor::{TempMeasureSignal, TempSensorDynReceiver, format_reading},
+    thermostat,
+    wifi::WifiReconnectSignal,
+};
 use crate::{
-    ESP_APP_DESC,
-    memlog::SharedLogger,
-    state::SharedState,
-    task::ssr_control::{SsrCommand, SsrCommandPublisher, SsrDutyDynReceiver, SsrDutyDynSender},
+    ESP_APP_DESC, board,
+    config::MQTT_TOPIC_DEVICE_NAME,
+    futures::get_or_wait,
+    memlog::{Level, LogFormat, SharedLogger, format_milliseconds_to_hms},
+    settings::{
+        self, MQTT_KEEP_ALIVE_SECS, MqttPingInterval, SharedSettings, TempAlarms, TempCalibration,
+        TempLimits, TempUnit,
+    },
+    state::{HeaterMode, HeaterState, SharedState},
+    stats::SharedStats,
+    task::ssr_control::{
+        Duty, DutySource, DutyUpdate, LockReason, MAINS_CYCLES_PER_STEP, MAINS_FREQUENCY_HZ,
+        PATTERN_STEP_DURATION, SsrCommand, SsrCommandPublisher, SsrDutyDynReceiver,
+        SsrDutyDynSender, SsrDutyLimiter, SsrPatternDynReceiver, SsrStatus, SsrStatusDynReceiver,
+        SsrZoneDutyDynReceiver, SsrZoneDutyDynSender, applied_duty, derating_cap, parse_zone,
+        set_zone_duty,
+    },
 };
-use alloc::{format, string::String};
+use alloc::{format, string::String, vec::Vec};
 use core::cell::LazyCell;
 use embassy_futures::select;
-use embassy_time::{Duration, Timer};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal, watch};
+use embassy_time::{Duration, Instant, Timer, with_timeout};
 use esp_hal::{Async, gpio, uart};
+use heater_core::pattern::{format_pattern, pattern_duty};
+use noline::error::NolineError;
 
+// Longest command line, in bytes. Sized for 'config import' with a settings export.
+const COMMAND_INPUT_BUFFER_SIZE: usize = 256;
 // Number of bytes to allocate to keep a history of commands.
 const COMMAND_HISTORY_BUFFER_SIZE: usize = 1000; // in bytes
+// How often to save the command history to flash, if it changed, so that it survives a reboot.
+// Limits flash wear. None keeps the history in memory only.
+const COMMAND_HISTORY_PERSIST_INTERVAL: Option<Duration> = Some(Duration::from_secs(60));
+// Commands left out of the saved history. Settings imports would crowd out everything else.
+const COMMAND_HISTORY_EXCLUDED: &[&str] = &["config import"];
+// How long the self-test keeps the SSR on for.
+const SELFTEST_SSR_PULSE: Duration = Duration::from_secs(1);
+// How long the self-test waits for the operator to answer or press the button.
+const SELFTEST_OPERATOR_TIMEOUT: Duration = Duration::from_secs(10);
+// How long to wait for the network to come back after a WiFi reconnect.
+const NET_RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// How long to wait for an on-demand temperature measurement.
+const TEMP_NOW_TIMEOUT: Duration = Duration::from_secs(3);
+// How long a read waits for the first value, if none was published yet since boot.
+const READ_FIRST_VALUE_TIMEOUT: Duration = Duration::from_secs(3);
+// Pause after an RX error before discarding input, to let a burst of line noise pass.
+const RX_ERROR_BACKOFF: Duration = Duration::from_millis(100);
 const SERIAL_MOTD: LazyCell<String> = LazyCell::new(|| {
     format!(
         "\r\n{} {}\r\nbuilt on {} {}\r\n",
@@ -44,13 +86,26 @@ pub async fn serial_console(
     peripheral_uart: uart::AnyUart<'static>,
     pin_uart_rx: gpio::AnyPin<'static>,
     pin_uart_tx: gpio::AnyPin<'static>,
-    mut ssrcontrol_duty_sender: SsrDutyDynSender,
+    pin_button: gpio::AnyPin<'static>,
+    stack: embassy_net::Stack<'static>,
+    wifi_reconnect_signal: WifiReconnectSignal,
+    mqtt_toggle_signal: MqttToggleSignal,
+    ssrcontrol_duty_limiter: SsrDutyLimiter,
+    ssrcontrol_duty_sender: SsrDutyDynSender,
     mut ssrcontrol_duty_receiver: SsrDutyDynReceiver,
+    ssrcontrol_zone_sender: SsrZoneDutyDynSender,
+    mut ssrcontrol_zone_receiver: SsrZoneDutyDynReceiver,
     mut ssrcontrol_command_publisher: SsrCommandPublisher,
+    mut ssrcontrol_status_receiver: SsrStatusDynReceiver,
+    mut ssrcontrol_pattern_receiver: SsrPatternDynReceiver,
+    mut heapstatus_receiver: HeapStatusDynReceiver,
     mut netstatus_receiver: NetStatusDynReceiver,
     mut tempsensor_receiver: TempSensorDynReceiver,
+    tempsensor_measure_signal: TempMeasureSignal,
     memlog: SharedLogger,
     state: SharedState,
+    settings: SharedSettings,
+    stats: SharedStats,
 ) {
     // UART setup. When in loopback mode, ensure TX is configured first (#2914).
     let mut uart = uart::Uart::new(peripheral_uart, uart::Config::default())
@@ -59,8 +114,14 @@ pub async fn serial_console(
         .with_rx(pin_uart_rx)
         .into_async();
 
+    // The case button pulls the line to GND when pressed.
+    let mut button = gpio::Input::new(
+        pin_button,
+        gpio::InputConfig::default().with_pull(gpio::Pull::Up),
+    );
+
     // Line editor setup.
-    let mut input_buffer = [0u8; 100]; // Commands are short, could be smaller
+    let mut input_buffer = [0u8; COMMAND_INPUT_BUFFER_SIZE];
     let mut history_buffer = [0u8; COMMAND_HISTORY_BUFFER_SIZE];
     // let mut editor = noline::builder::EditorBuilder::new_unbounded()
     let mut editor = noline::builder::EditorBuilder::from_slice(&mut input_buffer)
@@ -69,53 +130,300 @@ pub async fn serial_console(
         .await
         .unwrap(); // always returns Ok()
 
+    // Restore the command history from before the last reboot.
+    if COMMAND_HISTORY_PERSIST_INTERVAL.is_some() {
+        let history = settings::load_history();
+        editor.load_history(history.iter().map(String::as_str));
+    }
+    // The history as of the last command, left for `persist_history` to save once due.
+    let history_pending: Signal<NoopRawMutex, Vec<String>> = Signal::new();
+
+    let console = async {
+        loop {
+            // Try block to catch UART errors.
+            let catch: Result<(), uart::TxError> = async {
+                // Write the MOTD out. This only happens on first connect and after a UART error.
+                uart.write_all_async(SERIAL_MOTD.as_bytes()).await?;
+
+                let prompt = "> ";
+                loop {
+                    match editor.readline(prompt, &mut uart).await {
+                        Ok(line) => {
+                            cli_parser(
+                                line,
+                                &mut uart,
+                                &mut button,
+                                stack,
+                                wifi_reconnect_signal,
+                                mqtt_toggle_signal,
+                                ssrcontrol_duty_limiter,
+                                &ssrcontrol_duty_sender,
+                                &mut ssrcontrol_duty_receiver,
+                                &ssrcontrol_zone_sender,
+                                &mut ssrcontrol_zone_receiver,
+                                &mut ssrcontrol_command_publisher,
+                                &mut ssrcontrol_status_receiver,
+                                &mut ssrcontrol_pattern_receiver,
+                                &mut heapstatus_receiver,
+                                &mut netstatus_receiver,
+                                &mut tempsensor_receiver,
+                                tempsensor_measure_signal,
+                                memlog,
+                                state,
+                                settings,
+                                stats,
+                            )
+                            .await?;
+
+                            if COMMAND_HISTORY_PERSIST_INTERVAL.is_some() {
+                                history_pending.signal(
+                                    editor
+                                        .get_history()
+                                        .filter_map(|entry| {
+                                            String::from_utf8(
+                                                entry.into_iter().map(|(_, byte)| *byte).collect(),
+                                            )
+                                            .ok()
+                                        })
+                                        .filter(|command| {
+                                            !COMMAND_HISTORY_EXCLUDED
+                                                .iter()
+                                                .any(|excluded| command.starts_with(excluded))
+                                        })
+                                        .collect(),
+                                );
+                            }
+                        }
+                        // Ctrl-C and Ctrl-D abort the current line, go back to the prompt.
+                        Err(NolineError::Aborted) => uart.write_all_async(b"\r\n").await?,
+                        // TX errors can't happen, so this is noise or a framing/overrun error on RX.
+                        // Drop the garbled input and go back to the prompt.
+                        Err(editor_error) => {
+                            recover_rx(&mut uart, memlog, format!("{editor_error:?}")).await;
+                            uart.write_all_async(b"\r\n").await?;
+                        }
+                    }
+                }
+            }
+            .await;
+
+            if let Err(tx_error) = catch {
+                // Push the UART error to the memlog.
+                memlog.warn(format!("uart error: {}", tx_error));
+            }
+
+            // Pause before trying the UART again after an error.
+            Timer::after(Duration::from_secs(1)).await;
+        } // loop
+    };
+
+    // Saved on a timer, so that the last commands before an idle spell are saved too.
+    match COMMAND_HISTORY_PERSIST_INTERVAL {
+        Some(interval) => {
+            select::select(console, persist_history(&history_pending, interval, memlog)).await;
+        }
+        None => console.await,
+    }
+}
+
+/// Saves the command history to flash as it changes, at most once per `interval`.
+async fn persist_history(
+    history_pending: &Signal<NoopRawMutex, Vec<String>>,
+    interval: Duration,
+    memlog: SharedLogger,
+) {
+    let mut saved_at = Instant::now();
     loop {
-        // Try block to catch UART errors.
-        let catch: Result<(), uart::TxError> = async {
-            // Write the MOTD out.
-            uart.write_all_async(SERIAL_MOTD.as_bytes()).await?;
-
-            let prompt = "> ";
-            // Note: Ctrl-C and Ctrl-D break the readline while loop.
-            while let Ok(line) = editor.readline(prompt, &mut uart).await {
-                cli_parser(
-                    line,
-                    &mut uart,
-                    &mut ssrcontrol_duty_sender,
-                    &mut ssrcontrol_duty_receiver,
-                    &mut ssrcontrol_command_publisher,
-                    &mut netstatus_receiver,
-                    &mut tempsensor_receiver,
-                    memlog,
-                    state,
-                )
-                .await?;
+        let history = history_pending.wait().await;
+        Timer::at(saved_at + interval).await;
+        // Commands issued while waiting are saved too.
+        let history = history_pending.try_take().unwrap_or(history);
+
+        if let Err(error) = settings::save_history(&history) {
+            memlog.warn(format!("failed to save the serial history: {error}"));
+        }
+        saved_at = Instant::now();
+    }
+}
+
+/// Logs an RX error and discards whatever is left in the RX FIFO.
+async fn recover_rx(
+    uart: &mut uart::Uart<'static, Async>,
+    memlog: SharedLogger,
+    error: impl core::fmt::Display,
+) {
+    memlog.warn(format!("serial console rx error, input discarded: {error}"));
+    Timer::after(RX_ERROR_BACKOFF).await;
+
+    // Bounded, in case a noisy line keeps refilling the FIFO.
+    let mut discard = [0u8; 32];
+    for _ in 0..8 {
+        if !uart.read_ready() {
+            break;
+        }
+        // A read that fails clears the error, and the next one drains the FIFO.
+        let _ = uart.read_buffered(&mut discard);
+    }
+}
+
+/// Prints each new value from a watch until the user interrupts with Ctrl-C or Ctrl-D.
+async fn watch_until_interrupt<T: Clone>(
+    uart: &mut uart::Uart<'static, Async>,
+    receiver: &mut watch::DynReceiver<'static, T>,
+    memlog: SharedLogger,
+    format_value: impl Fn(&T) -> String,
+) -> Result<(), uart::TxError> {
+    let mut buf = [0u8; 1];
+    loop {
+        let wait_for_value = receiver.changed();
+        let wait_for_input = uart.read_async(&mut buf);
+        match select::select(wait_for_value, wait_for_input).await {
+            select::Either::First(value) => {
+                let formatted = format!("{}\r\n", format_value(&value));
+                uart.write_all_async(formatted.as_bytes()).await?;
             }
+            select::Either::Second(bytes_read) => match bytes_read {
+                // Accept a Ctrl-C or Ctrl-D to interrupt (ASCII End of Text, End of Transmission)
+                Ok(1) if (buf[0] == 0x03) | (buf[0] == 0x04) => return Ok(()),
+                Ok(_) => (),
+                Err(rx_error) => recover_rx(uart, memlog, rx_error).await,
+            },
+        };
+    }
+}
 
-            Ok(())
+/// Checks each subsystem in turn, and returns a pass/fail report.
+///
+/// Asks before pulsing the SSR, which only happens if the heater is off.
+async fn selftest(
+    uart: &mut uart::Uart<'static, Async>,
+    button: &mut gpio::Input<'static>,
+    stack: embassy_net::Stack<'static>,
+    ssrcontrol_duty_sender: &SsrDutyDynSender,
+    ssrcontrol_status_receiver: &mut SsrStatusDynReceiver,
+    netstatus_receiver: &mut NetStatusDynReceiver,
+    tempsensor_receiver: &mut TempSensorDynReceiver,
+    tempsensor_measure_signal: TempMeasureSignal,
+    memlog: SharedLogger,
+    state: SharedState,
+) -> Result<String, uart::TxError> {
+    // Pulse the SSR. The state isn't held across the prompt or the pulse, as the safety tasks
+    // wait on it to lock the SSR, so the heater is checked again just before pulsing.
+    let ssr_result = match ssr_selftest_blocker(state, ssrcontrol_status_receiver).await {
+        Some(blocker) => blocker,
+        None => {
+            uart.write_all_async(b"Pulse the SSR on for 1s? [y/N] ")
+                .await?;
+            let mut buf = [0u8; 1];
+            let answer = with_timeout(SELFTEST_OPERATOR_TIMEOUT, uart.read_async(&mut buf));
+            let confirmed = matches!(answer.await, Ok(Ok(1)) if buf[0] == b'y' || buf[0] == b'Y');
+            uart.write_all_async(b"\r\n").await?;
+
+            if !confirmed {
+                "skipped".into()
+            } else if let Some(blocker) =
+                ssr_selftest_blocker(state, ssrcontrol_status_receiver).await
+            {
+                blocker
+            } else {
+                ssrcontrol_duty_sender.send(DutyUpdate::new(Duty::FULL, DutySource::SelfTest));
+                Timer::after(SELFTEST_SSR_PULSE).await;
+                // Leave a duty set by another source during the pulse in place.
+                let pulsing = ssrcontrol_duty_sender
+                    .try_get()
+                    .is_some_and(|update| update.source == DutySource::SelfTest);
+                if pulsing {
+                    ssrcontrol_duty_sender.send(DutyUpdate::new(Duty::OFF, DutySource::SelfTest));
+                }
+                memlog.info("selftest: ssr pulsed");
+                "pass, pulsed".into()
+            }
         }
-        .await;
+    };
+
+    // Take a fresh temperature reading.
+    tempsensor_receiver.try_changed();
+    tempsensor_measure_signal.signal(());
+    let temp_result = match with_timeout(TEMP_NOW_TIMEOUT, tempsensor_receiver.changed()).await {
+        Ok(Ok(data)) if data.is_fresh() => format!("pass, {}°C", data.temperature),
+        Ok(Ok(_stale)) => "fail, sensor read error".into(),
+        Ok(Err(error)) => format!("fail, {error:?}"),
+        Err(_timeout) => "fail, no reading".into(),
+    };
 
-        if let Err(tx_error) = catch {
-            // Push the UART error to the memlog.
-            memlog.warn(format!("uart error: {}", tx_error));
+    let wifi_result = match netstatus_receiver.try_get() {
+        Some(status) if !status.link_up() => "fail, link down".into(),
+        Some(status) => match status.ipv4_address() {
+            Some(address) => format!("pass, {address}"),
+            None => "fail, no IPv4 address".into(),
+        },
+        None => "fail, no status yet".into(),
+    };
+
+    let broker_result = match mqtt::ping_broker(stack).await {
+        Ok((broker_addr, round_trip)) => {
+            format!("pass, {broker_addr} in {}ms", round_trip.as_millis())
         }
+        Err(error) => format!("fail, {error}"),
+    };
+
+    uart.write_all_async(b"Press the case button...\r\n")
+        .await?;
+    let button_result =
+        match with_timeout(SELFTEST_OPERATOR_TIMEOUT, button.wait_for_falling_edge()).await {
+            Ok(()) => "pass",
+            Err(_timeout) => "fail, not pressed",
+        };
 
-        // Pause before trying the UART again after an error.
-        Timer::after(Duration::from_secs(1)).await;
-    } // loop
+    Ok(format!(
+        "SSR: {ssr_result}\r\n\
+         Temp sensor: {temp_result}\r\n\
+         WiFi: {wifi_result}\r\n\
+         MQTT broker: {broker_result}\r\n\
+         Button: {button_result}"
+    ))
+}
+
+/// Why the self-test can't pulse the SSR now, if it can't: the heater is on, or the SSR is locked
+/// or faulted.
+async fn ssr_selftest_blocker(
+    state: SharedState,
+    ssrcontrol_status_receiver: &mut SsrStatusDynReceiver,
+) -> Option<String> {
+    if !state.lock().await.is_off() {
+        return Some("skipped, the heater must be off".into());
+    }
+    match ssrcontrol_status_receiver.try_get() {
+        Some(status @ (SsrStatus::Locked(_) | SsrStatus::Fault(_))) => {
+            Some(format!("fail, {status:?}"))
+        }
+        _ => None,
+    }
 }
 
 async fn cli_parser(
     line: &str,
     uart: &mut uart::Uart<'static, Async>,
-    ssrcontrol_duty_sender: &mut SsrDutyDynSender,
+    button: &mut gpio::Input<'static>,
+    stack: embassy_net::Stack<'static>,
+    wifi_reconnect_signal: WifiReconnectSignal,
+    mqtt_toggle_signal: MqttToggleSignal,
+    ssrcontrol_duty_limiter: SsrDutyLimiter,
+    ssrcontrol_duty_sender: &SsrDutyDynSender,
     ssrcontrol_duty_receiver: &mut SsrDutyDynReceiver,
+    ssrcontrol_zone_sender: &SsrZoneDutyDynSender,
+    ssrcontrol_zone_receiver: &mut SsrZoneDutyDynReceiver,
     ssrcontrol_command_publisher: &mut SsrCommandPublisher,
+    ssrcontrol_status_receiver: &mut SsrStatusDynReceiver,
+    ssrcontrol_pattern_receiver: &mut SsrPatternDynReceiver,
+    heapstatus_receiver: &mut HeapStatusDynReceiver,
     netstatus_receiver: &mut NetStatusDynReceiver,
     tempsensor_receiver: &mut TempSensorDynReceiver,
+    tempsensor_measure_signal: TempMeasureSignal,
     memlog: SharedLogger,
     state: SharedState,
+    settings: SharedSettings,
+    stats: SharedStats,
 ) -> Result<(), uart::TxError> {
     // Get the command from the first argument.
     let mut chunks = line.split_whitespace();
@@ -123,138 +431,723 @@ async fn cli_parser(
         //
         // Help message.
         (Some("help"), None) => {
-            "ssr\r\n\
-             · pwm <duty>\r\n\
+            "stop\r\n\
+             selftest\r\n\
+             mode\r\n\
+             ssr\r\n\
+             · pwm <duty> [<zone>]\r\n\
              · command/{lock,unlock}\r\n\
+             · status\r\n\
+             · watch\r\n\
+             · pattern\r\n\
              temp\r\n\
              · read\r\n\
+             · now\r\n\
              · watch\r\n\
+             · unit {c,f}\r\n\
+             · limits <low> <high>\r\n\
+             · cal <offset> [<scale>]\r\n\
+             · alarm <low|-> <high|->\r\n\
+             · setpoint <celsius>\r\n\
+             · mode {manual,thermostat}\r\n\
              net\r\n\
              · read\r\n\
+             · info\r\n\
              · watch\r\n\
+             · reconnect\r\n\
+             mqtt\r\n\
+             · name <name>\r\n\
+             · ping <seconds>\r\n\
+             · probe\r\n\
+             · {enable,disable}\r\n\
              log\r\n\
              · read\r\n\
+             · watch\r\n\
              · clear\r\n\
+             · level {trace,debug,info,warn,error}\r\n\
+             · format {text,json}\r\n\
+             · info\r\n\
+             · capacity <chars>\r\n\
+             config\r\n\
+             · export\r\n\
+             · import <export>\r\n\
+             · serial disable\r\n\
+             stats\r\n\
+             uptime\r\n\
              help"
         }
 
+        //
+        // Stop the heater, whatever controls it.
+        (Some("stop"), None) => {
+            // Hold the state while stopping, so a remote update can't slip in between.
+            let mut state = state.lock().await;
+            state.transition_to_off();
+            ssrcontrol_duty_limiter.cancel();
+            ssrcontrol_duty_sender.send(DutyUpdate::new(Duty::OFF, DutySource::Stop));
+            ssrcontrol_zone_sender.send([Duty::OFF; board::SSR_EXTRA_ZONES]);
+            memlog.error("STOP requested on serial console, heater off");
+            "Heater stopped"
+        }
+
+        //
+        // Check the hardware and connectivity.
+        (Some("selftest"), None) => {
+            &selftest(
+                uart,
+                button,
+                stack,
+                ssrcontrol_duty_sender,
+                ssrcontrol_status_receiver,
+                netstatus_receiver,
+                tempsensor_receiver,
+                tempsensor_measure_signal,
+                memlog,
+                state,
+            )
+            .await?
+        }
+
+        //
+        // What the heater is doing, and who controls it.
+        (Some("mode"), None) => match state.lock().await.mode() {
+            HeaterMode::Fault(reason) => &format!(
+                "Mode: fault, {}, use 'ssr command unlock' to clear",
+                reason.description()
+            ),
+            mode => &format!("Mode: {mode}"),
+        },
+
         //
         // SSR control.
-        (Some("ssr"), Some("pwm")) => match chunks.next() {
-            Some(duty_str) => match duty_str.parse::<u8>() {
-                Ok(duty) => {
-                    if (0..=100).contains(&duty) {
-                        state.lock().await.transition_to_manual(duty);
-                        ssrcontrol_duty_sender.send(duty);
-                        "Relay duty set"
-                    } else {
-                        "Relay duty value must be between 0 and 100"
+        (Some("ssr"), Some("pwm")) => match (chunks.next(), chunks.next().map(parse_zone)) {
+            (Some(_), Some(Err(error))) => &format!("Relay duty not set: {error}"),
+            (Some(duty_str), zone) => match (duty_str.parse::<Duty>(), zone) {
+                // Extra zones are set directly, the heater state only covers the main one.
+                (Ok(duty), Some(Ok(zone))) if zone > 0 => {
+                    match set_zone_duty(ssrcontrol_zone_sender, zone, duty) {
+                        Ok(()) => {
+                            memlog.info(format!("ssr: zone {zone} duty set to {duty}% on serial"));
+                            "Relay duty set"
+                        }
+                        Err(error) => &format!("Relay duty not set: {error}"),
                     }
                 }
-                Err(_parse_error) => "Failed to parse relay duty value.",
+                // Only if no other source is in control, so they don't fight over the duty.
+                (Ok(duty), _) => match state.lock().await.manual_update_duty(duty) {
+                    Ok(()) => {
+                        ssrcontrol_duty_limiter.write(duty, DutySource::Serial);
+                        "Relay duty set"
+                    }
+                    Err(error) => &format!("Relay duty not set: {error}"),
+                },
+                (Err(error), _) => &format!("Relay duty not set: {error}"),
             },
-            None => {
-                let duty = ssrcontrol_duty_receiver.try_get();
+            (None, _) => {
+                let duty = get_or_wait(ssrcontrol_duty_receiver, READ_FIRST_VALUE_TIMEOUT)
+                    .await
+                    .map(|update| update.duty.percent());
                 &format!("{:?}", duty)
             }
         },
         (Some("ssr"), Some("command")) => match chunks.next() {
             Some("lock") => {
-                ssrcontrol_command_publisher.publish(SsrCommand::Lock).await;
+                ssrcontrol_command_publisher
+                    .publish(SsrCommand::Lock(LockReason::Manual))
+                    .await;
                 "SSR lock command sent"
             }
             Some("unlock") => {
                 ssrcontrol_command_publisher
-                    .publish(SsrCommand::Unlock)
+                    .publish(SsrCommand::ManualUnlock)
                     .await;
                 "SSR unlock command sent"
             }
             _ => "Relay command required",
         },
+        (Some("ssr"), Some("status")) => {
+            let status = match ssrcontrol_status_receiver.try_get() {
+                Some(SsrStatus::Fault(reason)) => format!(
+                    "Fault: {}, use 'ssr command unlock' to clear",
+                    reason.description()
+                ),
+                Some(SsrStatus::Locked(reason)) => format!("Locked: {}", reason.description()),
+                status => format!("{:?}", status),
+            };
+            // The duty curve, derating, and locks can apply a different duty than the one
+            // commanded. The pattern in use has the duty in effect, if one was applied yet.
+            let reading = tempsensor_receiver.try_get();
+            let pattern = ssrcontrol_pattern_receiver.try_get();
+            let mut duty = match ssrcontrol_duty_receiver.try_get() {
+                Some(update) => format!(
+                    "{}% commanded by {}, {}% applied",
+                    update.duty,
+                    update.source.name(),
+                    match &pattern {
+                        Some(pattern) => pattern_duty(pattern),
+                        None => applied_duty(update.duty, reading.clone()),
+                    }
+                ),
+                None => "none".into(),
+            };
+            if let Some(zone_duties) = ssrcontrol_zone_receiver.try_get() {
+                for (zone, zone_duty) in zone_duties.iter().enumerate() {
+                    duty += &format!("\r\nZone {}: {}% commanded", zone + 1, zone_duty);
+                }
+            }
+            let cap = match reading {
+                Some(Ok(data)) => derating_cap(data.temperature),
+                _ => Duty::FULL,
+            };
+            if cap < Duty::FULL {
+                &format!("{status}\r\nDuty: {duty}\r\nDerated to at most {cap}%")
+            } else {
+                &format!("{status}\r\nDuty: {duty}")
+            }
+        }
+        (Some("ssr"), Some("watch")) => {
+            watch_until_interrupt(uart, ssrcontrol_duty_receiver, memlog, |update| {
+                format!("{} ({})", update.duty, update.source.name())
+            })
+            .await?;
+            ""
+        }
+        (Some("ssr"), Some("pattern")) => match ssrcontrol_pattern_receiver.try_get() {
+            Some(pattern) => &format!(
+                "{}\r\n{}ms steps, {} cycles each at {}Hz",
+                format_pattern(&pattern),
+                PATTERN_STEP_DURATION.as_millis(),
+                MAINS_CYCLES_PER_STEP,
+                MAINS_FREQUENCY_HZ
+            ),
+            None => "No pattern applied yet",
+        },
         (Some("ssr"), Some(_)) => "Invalid subcommand for 'ssr'",
         (Some("ssr"), None) => "Subcommand required for 'ssr'",
 
         //
         // Temp sensor.
         (Some("temp"), Some("read")) => {
-            let sensor_result = tempsensor_receiver.try_get();
-            &format!("{:?}", sensor_result)
+            let unit = settings.lock().await.temp_unit;
+            match get_or_wait(tempsensor_receiver, READ_FIRST_VALUE_TIMEOUT).await {
+                Some(sensor_result) => &format_reading(&sensor_result, unit),
+                None => "No temperature reading yet",
+            }
+        }
+        (Some("temp"), Some("now")) => {
+            let unit = settings.lock().await.temp_unit;
+            // Skip the last reading, and wait for the one we request.
+            // A measurement already under way when the request arrives is taken instead.
+            tempsensor_receiver.try_changed();
+            tempsensor_measure_signal.signal(());
+            match with_timeout(TEMP_NOW_TIMEOUT, tempsensor_receiver.changed()).await {
+                Ok(sensor_result) => &format_reading(&sensor_result, unit),
+                Err(_timeout) => "Timed out waiting for a measurement",
+            }
         }
         (Some("temp"), Some("watch")) => {
-            let mut buf = [0u8; 1];
-            'watch_loop: loop {
-                // Watch for changes in the temperature sensor until the user interrupts.
-                let wait_for_sensor = tempsensor_receiver.changed();
-                let wait_for_input = uart.read_async(&mut buf);
-                match select::select(wait_for_sensor, wait_for_input).await {
-                    select::Either::First(sensor_result) => {
-                        let formatted = format!("{:?}\r\n", sensor_result);
-                        uart.write_all_async(formatted.as_bytes()).await?;
+            let unit = settings.lock().await.temp_unit;
+            watch_until_interrupt(uart, tempsensor_receiver, memlog, |sensor_result| {
+                format_reading(sensor_result, unit)
+            })
+            .await?;
+            ""
+        }
+        (Some("temp"), Some("unit")) => match chunks.next() {
+            Some(unit_str) => match unit_str.parse::<TempUnit>() {
+                Ok(unit) => {
+                    let mut settings = settings.lock().await;
+                    settings.temp_unit = unit;
+                    match settings.save() {
+                        Ok(()) => "Temperature unit set",
+                        Err(error) => &format!("Temperature unit set but not saved: {error}"),
                     }
-                    select::Either::Second(bytes_read) => {
-                        // Accept a Ctrl-C or Ctrl-D to interrupt (ASCII End of Text, End of Transmission)
-                        if let Ok(1) = bytes_read {
-                            if (buf[0] == 0x03) | (buf[0] == 0x04) {
-                                break 'watch_loop;
+                }
+                Err(_parse_error) => "Temperature unit must be 'c' or 'f'",
+            },
+            None => settings.lock().await.temp_unit.symbol(),
+        },
+        (Some("temp"), Some("limits")) => match (chunks.next(), chunks.next()) {
+            (Some(low_str), Some(high_str)) => {
+                match (low_str.parse::<f32>(), high_str.parse::<f32>()) {
+                    (Ok(low), Ok(high)) => match TempLimits::new(low, high) {
+                        Ok(temp_limits) => {
+                            let mut settings = settings.lock().await;
+                            settings.temp_limits = temp_limits;
+                            match settings.save() {
+                                Ok(()) => "Temperature limits set",
+                                Err(error) => {
+                                    &format!("Temperature limits set but not saved: {error}")
+                                }
                             }
                         }
-                    }
+                        Err(error) => &format!("Invalid temperature limits: {error}"),
+                    },
+                    _ => "Failed to parse temperature limits.",
+                }
+            }
+            (Some(_), None) => "Both low and high limits are required",
+            _ => {
+                let temp_limits = settings.lock().await.temp_limits;
+                &format!(
+                    "Lock at {}°C, unlock below {}°C",
+                    temp_limits.high(),
+                    temp_limits.low()
+                )
+            }
+        },
+        (Some("temp"), Some("cal")) => match (chunks.next(), chunks.next()) {
+            (Some(offset_str), scale_str) => {
+                let scale = scale_str.map_or(Ok(1.0), str::parse::<f32>);
+                match (offset_str.parse::<f32>(), scale) {
+                    (Ok(offset), Ok(scale)) => match TempCalibration::new(offset, scale) {
+                        Ok(temp_calibration) => {
+                            let mut settings = settings.lock().await;
+                            settings.temp_calibration = temp_calibration;
+                            match settings.save() {
+                                Ok(()) => "Temperature calibration set",
+                                Err(error) => {
+                                    &format!("Temperature calibration set but not saved: {error}")
+                                }
+                            }
+                        }
+                        Err(error) => &format!("Invalid temperature calibration: {error}"),
+                    },
+                    _ => "Failed to parse temperature calibration.",
+                }
+            }
+            (None, _) => {
+                let temp_calibration = settings.lock().await.temp_calibration;
+                let raw = match stats.temp_raw.get() {
+                    Some(raw) => format!("{raw}°C"),
+                    None => "none yet".into(),
                 };
+                &format!(
+                    "Offset {}°C, scale {}, last raw reading {raw}",
+                    temp_calibration.offset(),
+                    temp_calibration.scale()
+                )
             }
-            ""
-        }
+        },
+        (Some("temp"), Some("alarm")) => match (chunks.next(), chunks.next()) {
+            (Some(low_str), Some(high_str)) => {
+                // A dash disables the threshold.
+                let parse = |value: &str| match value {
+                    "-" => Ok(None),
+                    value => value.parse::<f32>().map(Some),
+                };
+                match (parse(low_str), parse(high_str)) {
+                    (Ok(low), Ok(high)) => match TempAlarms::new(low, high) {
+                        Ok(temp_alarms) => {
+                            let mut settings = settings.lock().await;
+                            settings.temp_alarms = temp_alarms;
+                            match settings.save() {
+                                Ok(()) => "Temperature alarms set",
+                                Err(error) => {
+                                    &format!("Temperature alarms set but not saved: {error}")
+                                }
+                            }
+                        }
+                        Err(error) => &format!("Invalid temperature alarms: {error}"),
+                    },
+                    _ => "Failed to parse temperature alarms.",
+                }
+            }
+            (Some(_), None) => "Both low and high thresholds are required, '-' to disable",
+            _ => {
+                let temp_alarms = settings.lock().await.temp_alarms;
+                let format_threshold = |threshold: Option<f32>| match threshold {
+                    Some(threshold) => format!("{threshold}°C"),
+                    None => "disabled".into(),
+                };
+                &format!(
+                    "Low alarm {}, high alarm {}",
+                    format_threshold(temp_alarms.low()),
+                    format_threshold(temp_alarms.high())
+                )
+            }
+        },
+        (Some("temp"), Some("setpoint")) => match chunks.next() {
+            Some(setpoint_str) => match setpoint_str.parse::<f32>() {
+                Ok(setpoint) => {
+                    if (thermostat::SETPOINT_MIN..=thermostat::SETPOINT_MAX).contains(&setpoint) {
+                        state.lock().await.set_setpoint(setpoint);
+                        "Thermostat setpoint set"
+                    } else {
+                        &format!(
+                            "Setpoint must be between {}°C and {}°C",
+                            thermostat::SETPOINT_MIN,
+                            thermostat::SETPOINT_MAX
+                        )
+                    }
+                }
+                Err(_parse_error) => "Failed to parse setpoint value.",
+            },
+            None => {
+                let setpoint = state.lock().await.setpoint();
+                match (setpoint, tempsensor_receiver.try_get()) {
+                    (None, _) => "No setpoint set",
+                    (Some(setpoint), Some(Ok(data))) => &format!(
+                        "Setpoint {}°C, error {}°C",
+                        setpoint,
+                        setpoint - data.temperature
+                    ),
+                    (Some(setpoint), _) => {
+                        &format!("Setpoint {}°C, no temperature reading", setpoint)
+                    }
+                }
+            }
+        },
+        (Some("temp"), Some("mode")) => match chunks.next() {
+            Some("manual") => {
+                let mut state = state.lock().await;
+                let duty = state.duty();
+                state.transition_to_manual(duty);
+                "Manual mode set"
+            }
+            Some("thermostat") => match state.lock().await.transition_to_thermostat() {
+                Ok(()) => "Thermostat mode set",
+                Err(error) => &format!("Failed to set thermostat mode: {error}"),
+            },
+            _ => "Mode must be 'manual' or 'thermostat'",
+        },
         (Some("temp"), Some(_)) => "Invalid subcommand for 'temp'",
         (Some("temp"), None) => "Subcommand required for 'temp'",
 
         //
         // Network status.
         (Some("net"), Some("read")) => {
-            let net_status = netstatus_receiver.try_get();
+            let net_status = get_or_wait(netstatus_receiver, READ_FIRST_VALUE_TIMEOUT).await;
             &format!("{:?}", net_status)
         }
+        (Some("net"), Some("info")) => {
+            let link = if stack.is_link_up() { "up" } else { "down" };
+            let ip_info = match stack.config_v4() {
+                Some(config) => {
+                    let gateway = match config.gateway {
+                        Some(gateway) => format!("{gateway}"),
+                        None => "none".into(),
+                    };
+                    let dns_servers = if config.dns_servers.is_empty() {
+                        "none".into()
+                    } else {
+                        config
+                            .dns_servers
+                            .iter()
+                            .map(|server| format!("{server}"))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    };
+                    format!(
+                        "Address: {}\r\n\
+                         Netmask: {}\r\n\
+                         Gateway: {gateway}\r\n\
+                         DNS servers: {dns_servers}",
+                        config.address,
+                        config.address.netmask()
+                    )
+                }
+                None => "Address: none yet, waiting for DHCP".into(),
+            };
+            &format!(
+                "MAC: {}\r\n\
+                 Link: {link}\r\n\
+                 {ip_info}",
+                stack.hardware_address()
+            )
+        }
         (Some("net"), Some("watch")) => {
-            let mut buf = [0u8; 1];
-            'watch_loop: loop {
-                let wait_for_status = netstatus_receiver.changed();
-                let wait_for_input = uart.read_async(&mut buf);
-                match select::select(wait_for_status, wait_for_input).await {
-                    select::Either::First(status_result) => {
-                        let formatted = format!("{:?}\r\n", status_result);
-                        uart.write_all_async(formatted.as_bytes()).await?;
-                    }
-                    select::Either::Second(bytes_read) => {
-                        // Accept a Ctrl-C or Ctrl-D to interrupt (ASCII End of Text, End of Transmission)
-                        if let Ok(1) = bytes_read {
-                            if (buf[0] == 0x03) | (buf[0] == 0x04) {
-                                break 'watch_loop;
-                            }
-                        }
+            watch_until_interrupt(uart, netstatus_receiver, memlog, |status| {
+                format!("{:?}", status)
+            })
+            .await?;
+            ""
+        }
+        (Some("net"), Some("reconnect")) => {
+            // Wait for the network to go down, then come back up with an address.
+            netstatus_receiver.try_changed();
+            wifi_reconnect_signal.signal(());
+            let reconnected = with_timeout(NET_RECONNECT_TIMEOUT, async {
+                let mut went_down = false;
+                loop {
+                    let status = netstatus_receiver.changed().await;
+                    match status.ipv4_address() {
+                        Some(address) if status.link_up() && went_down => return address,
+                        Some(_) if status.link_up() => (),
+                        _ => went_down = true,
                     }
-                };
+                }
+            })
+            .await;
+            match reconnected {
+                Ok(address) => &format!("Reconnected, address {address}"),
+                Err(_timeout) => &format!(
+                    "Timed out waiting for the network, currently: {:?}",
+                    netstatus_receiver.try_get()
+                ),
             }
-            ""
         }
         (Some("net"), Some(_)) => "Invalid subcommand for 'net'",
         (Some("net"), None) => "Subcommand required for 'net'",
 
+        //
+        // MQTT settings.
+        (Some("mqtt"), Some("name")) => match chunks.next() {
+            Some(device_name) => {
+                let mut settings = settings.lock().await;
+                match settings.set_device_name(device_name) {
+                    Ok(()) => match settings.save() {
+                        Ok(()) => "Device name set, takes effect on the next MQTT connection",
+                        Err(error) => &format!("Device name set but not saved: {error}"),
+                    },
+                    Err(error) => &format!("Invalid device name: {error}"),
+                }
+            }
+            None => match &settings.lock().await.device_name {
+                Some(device_name) => &format!("{device_name}"),
+                None => &format!("{MQTT_TOPIC_DEVICE_NAME} (default)"),
+            },
+        },
+        (Some("mqtt"), Some("ping")) => match chunks.next() {
+            Some(secs_str) => match secs_str.parse::<u16>().map(MqttPingInterval::new) {
+                Ok(Ok(ping_interval)) => {
+                    let mut settings = settings.lock().await;
+                    settings.mqtt_ping_interval = ping_interval;
+                    match settings.save() {
+                        Ok(()) => "MQTT ping interval set",
+                        Err(error) => &format!("MQTT ping interval set but not saved: {error}"),
+                    }
+                }
+                Ok(Err(error)) => &format!("Invalid ping interval: {error}"),
+                Err(_parse_error) => "Failed to parse ping interval.",
+            },
+            None => &format!(
+                "{}s, keepalive {MQTT_KEEP_ALIVE_SECS}s",
+                settings.lock().await.mqtt_ping_interval.secs()
+            ),
+        },
+        (Some("mqtt"), Some("probe")) => {
+            let probe = mqtt::probe_broker(stack).await;
+            stats.broker_probe.set(Some(probe));
+            &format!("{probe}")
+        }
+        (Some("mqtt"), Some(toggle @ ("enable" | "disable"))) => {
+            let enable = toggle == "enable";
+            let mut settings = settings.lock().await;
+            settings.interfaces.mqtt = enable;
+            mqtt_toggle_signal.signal(());
+            match (enable, settings.save()) {
+                (true, Ok(())) => "MQTT enabled",
+                (false, Ok(())) => "MQTT disabled",
+                (_, Err(error)) => &format!("MQTT {toggle}d but not saved: {error}"),
+            }
+        }
+        (Some("mqtt"), Some(_)) => "Invalid subcommand for 'mqtt'",
+        (Some("mqtt"), None) => "Subcommand required for 'mqtt'",
+
         //
         // Log control.
         (Some("log"), Some("read")) => {
-            //
-            &memlog
-                .records()
-                .iter()
-                .rev()
-                .map(|record| format!("{}\r\n", record))
-                .collect::<String>()
+            // Write one record at a time, rather than building a copy of the whole log.
+            // Stop at the records logged while writing, and skip those evicted meanwhile.
+            let end = memlog.stored();
+            let log_format = memlog.format();
+            let mut seq = 0;
+            while let Some((record_seq, record)) = memlog.record_from(seq) {
+                if record_seq >= end {
+                    break;
+                }
+                uart.write_all_async(format!("{}\r\n", record.format(log_format)).as_bytes())
+                    .await?;
+                seq = record_seq + 1;
+            }
+            ""
+        }
+        (Some("log"), Some("watch")) => {
+            // The receiver is released when done, freeing the watcher for the next time.
+            memlog.enable_watch();
+            match memlog.watch() {
+                Some(mut logwatch_receiver) => {
+                    let log_format = memlog.format();
+                    watch_until_interrupt(uart, &mut logwatch_receiver, memlog, |record| {
+                        record.format(log_format)
+                    })
+                    .await?;
+                    ""
+                }
+                None => "No log watchers available",
+            }
         }
         (Some("log"), Some("clear")) => {
             memlog.clear();
             "Logs cleared"
         }
+        (Some("log"), Some("level")) => match chunks.next() {
+            Some(level_str) => match level_str.parse::<Level>() {
+                Ok(level) => {
+                    memlog.set_level(level);
+                    "Log level set"
+                }
+                Err(error) => &format!("{error}"),
+            },
+            None => &format!("{:?}", memlog.level()),
+        },
+        (Some("log"), Some("format")) => match chunks.next() {
+            Some(format_str) => match format_str.parse::<LogFormat>() {
+                Ok(log_format) => {
+                    memlog.set_format(log_format);
+                    "Log format set"
+                }
+                Err(error) => &format!("{error}"),
+            },
+            None => &format!("{:?}", memlog.format()),
+        },
+        (Some("log"), Some("info")) => {
+            let usage = memlog.usage();
+            &format!(
+                "Capacity: {} chars\r\n\
+                 Utilization: {} chars ({}%)\r\n\
+                 Records: {}\r\n\
+                 Evicted since boot: {}",
+                usage.capacity,
+                usage.utilization,
+                usage.utilization * 100 / usage.capacity,
+                usage.records,
+                usage.evicted
+            )
+        }
+        (Some("log"), Some("capacity")) => match chunks.next() {
+            Some(capacity_str) => match capacity_str.parse::<usize>() {
+                Ok(capacity) => match memlog.set_capacity(capacity) {
+                    Ok(()) => "Log capacity set",
+                    Err(error) => &format!("{error}"),
+                },
+                Err(_parse_error) => "Failed to parse log capacity.",
+            },
+            None => &format!("{} chars", memlog.usage().capacity),
+        },
         (Some("log"), Some(_)) => "Invalid subcommand for 'log'",
         (Some("log"), None) => "Subcommand required for 'log'",
 
+        //
+        // Settings export and import, to clone settings across devices.
+        (Some("config"), Some("export")) => match settings.lock().await.export() {
+            Ok(export) => &format!("{export}"),
+            Err(error) => &format!("Failed to export settings: {error}"),
+        },
+        (Some("config"), Some("serial")) => match chunks.next() {
+            Some("disable") => {
+                let mut settings = settings.lock().await;
+                settings.interfaces.serial = false;
+                match settings.save() {
+                    Ok(()) => {
+                        "Serial console disabled from the next boot. \
+                         Hold the case button through boot to enable it again."
+                    }
+                    Err(error) => {
+                        // Only takes effect once saved, so don't let a later save pick it up.
+                        settings.interfaces.serial = true;
+                        &format!("Serial console not disabled: {error}")
+                    }
+                }
+            }
+            _ => "Subcommand must be 'disable'",
+        },
+        (Some("config"), Some("import")) => match chunks.next() {
+            Some(export) => {
+                let mut settings = settings.lock().await;
+                match settings.import(export) {
+                    Ok(imported) => {
+                        *settings = imported;
+                        match settings.save() {
+                            Ok(()) => "Settings imported",
+                            Err(error) => &format!("Settings imported but not saved: {error}"),
+                        }
+                    }
+                    Err(error) => &format!("Settings not imported: {error}"),
+                }
+            }
+            None => "Settings export required",
+        },
+        (Some("config"), Some(_)) => "Invalid subcommand for 'config'",
+        (Some("config"), None) => "Subcommand required for 'config'",
+
+        //
+        // Time since boot.
+        (Some("uptime"), None) => &format!(
+            "Uptime: {}",
+            format_milliseconds_to_hms(Instant::now().as_millis())
+        ),
+
+        //
+        // Runtime statistics.
+        (Some("stats"), None) => {
+            let last_duty = match ssrcontrol_duty_receiver.try_get() {
+                Some(update) => format!(
+                    "{}% from {}, {}s ago",
+                    update.duty,
+                    update.source.name(),
+                    (Instant::now() - update.at).as_secs()
+                ),
+                None => "none".into(),
+            };
+            let log_counts = Level::ALL
+                .iter()
+                .zip(memlog.counts())
+                .map(|(level, count)| format!(" {level}={count}"))
+                .collect::<String>();
+            let heap = match heapstatus_receiver.try_get() {
+                Some(heap) => format!(
+                    "{} used, {} free, {} min free",
+                    heap.used, heap.free, heap.min_free
+                ),
+                None => "no sample yet".into(),
+            };
+            let state_times = HeaterState::NAMES
+                .iter()
+                .zip(state.lock().await.state_times())
+                .map(|(name, time)| format!(" {name}={}s", time.as_secs()))
+                .collect::<String>();
+            let slots = stats
+                .slots()
+                .iter()
+                .map(|(name, usage)| format!("\r\n· {name}: {}/{}", usage.claimed, usage.capacity))
+                .collect::<String>();
+            let broker_probe = match stats.broker_probe.get() {
+                Some(probe) => format!("{probe}, {}s ago", probe.at.elapsed().as_secs()),
+                None => "not run, see 'mqtt probe'".into(),
+            };
+            let (ping_interval, brownouts) = {
+                let settings = settings.lock().await;
+                (settings.mqtt_ping_interval.secs(), settings.brownouts)
+            };
+            &format!(
+                "Last duty change: {last_duty}\r\n\
+                 Time in state:{state_times}\r\n\
+                 Heap: {heap}\r\n\
+                 Brown-out resets: {brownouts}\r\n\
+                 MQTT ping interval: {ping_interval}s, keepalive {MQTT_KEEP_ALIVE_SECS}s\r\n\
+                 MQTT broker probe: {broker_probe}\r\n\
+                 Log records:{log_counts}\r\n\
+                 Temp watch: sent {}, mqtt saw {}, thermostat saw {}\r\n\
+                 Net watch: sent {}, mqtt saw {}\r\n\
+                 Log watch: sent {}\r\n\
+                 Log queue: sent {}, mqtt saw {}, dropped when full {}\r\n\
+                 SSR commands missed by lagging subscribers: {}\r\n\
+                 Slots claimed:{slots}",
+                stats.temp_sent.get(),
+                stats.temp_observed_mqtt.get(),
+                stats.temp_observed_thermostat.get(),
+                stats.net_sent.get(),
+                stats.net_observed_mqtt.get(),
+                memlog.watch_sent(),
+                memlog.queue_sent(),
+                stats.log_observed_mqtt.get(),
+                memlog.queue_dropped(),
+                stats.ssr_command_lagged.get(),
+            )
+        }
+
         //
         //
         (None, None) => "Please enter a command",
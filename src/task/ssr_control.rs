@@ -1,141 +1,467 @@
-use alloc::boxed::Box;
-use embassy_sync::{blocking_mutex::raw::NoopRawMutex, pubsub, watch};
-use embassy_time::{Duration, Timer};
+use crate::{
+    board,
+    memlog::SharedLogger,
+    state::SharedState,
+    stats::SharedStats,
+    task::temp_sensor::{TempSensorDynReceiver, TempSensorReading},
+};
+use alloc::{boxed::Box, format};
+use core::cell::Cell;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel, pubsub, signal::Signal, watch};
+use embassy_time::{Duration, Instant, Timer};
 use esp_hal::gpio;
+use heater_core::pattern::{Pattern, TOTAL_STEPS};
+use thiserror::Error;
 
+pub use heater_core::ssr::{
+    AlertReason, Duty, DutyError, FaultReason, LockReason, SsrAlert, SsrCommand, SsrController,
+    SsrStatus,
+};
+
+/// Where a duty cycle change originated.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum SsrCommand {
-    /// Sets the SSR duty to zero and locks it from being updated.
-    Lock,
-    /// Unlocks the SSR duty, allowing it to be updated.
-    /// Remains set to zero until an update.
-    Unlock,
+pub enum DutySource {
+    Serial,
+    /// Set over MQTT without a remote identifier.
+    Mqtt,
+    /// Set over MQTT by a remote.
+    Remote,
+    Thermostat,
+    /// Set to the expiry duty after a remote failed to check in.
+    RemoteExpiry,
+    /// Zeroed by an operator's stop command.
+    Stop,
+    /// Restored from flash on boot.
+    Resume,
+    /// A brief pulse from the serial self-test.
+    SelfTest,
+}
+
+impl DutySource {
+    pub fn name(self) -> &'static str {
+        match self {
+            DutySource::Serial => "serial",
+            DutySource::Mqtt => "mqtt",
+            DutySource::Remote => "remote",
+            DutySource::Thermostat => "thermostat",
+            DutySource::RemoteExpiry => "remote-expiry",
+            DutySource::Stop => "stop",
+            DutySource::Resume => "resume",
+            DutySource::SelfTest => "selftest",
+        }
+    }
+}
+
+/// A duty cycle change, tagged with its source for auditing.
+#[derive(Clone, Copy, Debug)]
+pub struct DutyUpdate {
+    pub duty: Duty,
+    pub source: DutySource,
+    /// When the change was applied.
+    pub at: Instant,
 }
+
+impl DutyUpdate {
+    pub fn new(duty: Duty, source: DutySource) -> Self {
+        DutyUpdate {
+            duty,
+            source,
+            at: Instant::now(),
+        }
+    }
+}
+
 const COMMAND_CHANNEL_CAP: usize = 2;
-pub type SsrDutyWatch<const W: usize> = &'static watch::Watch<NoopRawMutex, u8, W>;
-pub type SsrDutyDynSender = watch::DynSender<'static, u8>;
-pub type SsrDutyDynReceiver = watch::DynReceiver<'static, u8>;
+// Operator commands held for the relay while the command channel is full.
+const COMMAND_RELAY_CAP: usize = 2;
+pub type SsrDutyWatch<const W: usize> = &'static watch::Watch<NoopRawMutex, DutyUpdate, W>;
+pub type SsrDutyDynSender = watch::DynSender<'static, DutyUpdate>;
+pub type SsrDutyDynReceiver = watch::DynReceiver<'static, DutyUpdate>;
 pub type SsrCommandPubSub<const S: usize, const P: usize> =
     &'static pubsub::PubSubChannel<NoopRawMutex, SsrCommand, COMMAND_CHANNEL_CAP, S, P>;
 pub type SsrCommandPublisher = pubsub::DynPublisher<'static, SsrCommand>;
 pub type SsrCommandSubscriber = pubsub::DynSubscriber<'static, SsrCommand>;
+pub type SsrCommandRelay = &'static channel::Channel<NoopRawMutex, SsrCommand, COMMAND_RELAY_CAP>;
+pub type SsrStatusWatch<const W: usize> = &'static watch::Watch<NoopRawMutex, SsrStatus, W>;
+pub type SsrStatusDynSender = watch::DynSender<'static, SsrStatus>;
+pub type SsrStatusDynReceiver = watch::DynReceiver<'static, SsrStatus>;
+pub type SsrDutyLimiter = &'static DutyLimiter;
+/// The on/off steps currently applied to the SSR, one per `PATTERN_STEP_DURATION`.
+pub type SsrPattern = Pattern;
+pub type SsrPatternWatch<const W: usize> = &'static watch::Watch<NoopRawMutex, SsrPattern, W>;
+pub type SsrPatternDynSender = watch::DynSender<'static, SsrPattern>;
+pub type SsrPatternDynReceiver = watch::DynReceiver<'static, SsrPattern>;
+pub type SsrAlertWatch<const W: usize> = &'static watch::Watch<NoopRawMutex, SsrAlert, W>;
+pub type SsrAlertDynSender = watch::DynSender<'static, SsrAlert>;
+pub type SsrAlertDynReceiver = watch::DynReceiver<'static, SsrAlert>;
+/// The commanded duty of each extra zone, see `board::SSR_EXTRA_ZONES`. Zone 1 comes first.
+pub type ZoneDuties = [Duty; board::SSR_EXTRA_ZONES];
+pub type SsrZoneDutyWatch<const W: usize> = &'static watch::Watch<NoopRawMutex, ZoneDuties, W>;
+pub type SsrZoneDutyDynSender = watch::DynSender<'static, ZoneDuties>;
+pub type SsrZoneDutyDynReceiver = watch::DynReceiver<'static, ZoneDuties>;
+
+// Frequency of the mains supply switched by the SSR, 50Hz or 60Hz depending on the region.
+pub const MAINS_FREQUENCY_HZ: u64 = 50;
+// One mains power cycle: 20ms at 50Hz, 16.7ms at 60Hz.
+const MAINS_CYCLE: Duration = Duration::from_hz(MAINS_FREQUENCY_HZ);
 
 // The duration of each duty step.
-// Smallest interval is one 50Hz mains power cycle (20ms).
+// Smallest useful interval is one mains power cycle.
 // Note: SSR operate time is max. 1/2 cycle of voltage sine wave +1 ms.
-// 200ms: 100 steps over 20 seconds (1000 cycles), 10 cycles per step.
-const PATTERN_STEP_DURATION: Duration = Duration::from_millis(200);
+// 200ms: 100 steps over 20 seconds, 10 cycles per step at 50Hz (12 at 60Hz).
+pub const PATTERN_STEP_DURATION: Duration = Duration::from_millis(200);
+// Whole mains cycles in each duty step.
+pub const MAINS_CYCLES_PER_STEP: u64 = PATTERN_STEP_DURATION.as_ticks() / MAINS_CYCLE.as_ticks();
+
+// A step shorter than half a mains cycle can end before the SSR has even switched.
+const _: () = assert!(
+    PATTERN_STEP_DURATION.as_ticks() >= MAINS_CYCLE.as_ticks() / 2,
+    "duty steps must last at least half a mains cycle"
+);
+
+// Thermal derating: as the temperature rises from the first value to the second, in Celsius, the
+// applied duty is capped on a sliding scale from full duty down to zero. A gentler alternative to
+// the over-temperature lock, for loads that tolerate reduced power. E.g. `Some((50.0, 70.0))`.
+const DUTY_DERATING: Option<(f32, f32)> = None;
+
+// Alerts for the same reason are sent at most once per interval, so a derating cap that moves
+// with every temperature reading doesn't flood the log.
+const ALERT_DEBOUNCE: Duration = Duration::from_secs(300);
+
+// Duty writes from the control interfaces faster than this are coalesced.
+const DUTY_WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Latch the SSR off once its feedback line disagrees with the pattern for this many steps in a
+// row. Only applies to boards with a feedback line, see `BoardPins::ssr_feedback`.
+const FEEDBACK_MISMATCH_STEPS: u32 = 3;
 
 /// Takes a const that sets the maximum number of watchers.
-pub fn init<const DUTY_WATCHERS: usize, const CMD_SUBS: usize, const CMD_PUBS: usize>() -> (
+pub fn init<
+    const DUTY_WATCHERS: usize,
+    const CMD_SUBS: usize,
+    const CMD_PUBS: usize,
+    const STATUS_WATCHERS: usize,
+    const PATTERN_WATCHERS: usize,
+    const ALERT_WATCHERS: usize,
+    const ZONE_WATCHERS: usize,
+>(
+    memlog: SharedLogger,
+) -> (
     SsrDutyWatch<DUTY_WATCHERS>,
     SsrCommandPubSub<CMD_SUBS, CMD_PUBS>,
+    SsrCommandRelay,
+    SsrDutyLimiter,
+    SsrStatusWatch<STATUS_WATCHERS>,
+    SsrPatternWatch<PATTERN_WATCHERS>,
+    SsrAlertWatch<ALERT_WATCHERS>,
+    SsrZoneDutyWatch<ZONE_WATCHERS>,
 ) {
     (
         Box::leak(Box::new(watch::Watch::new())),
         Box::leak(Box::new(pubsub::PubSubChannel::new())),
+        Box::leak(Box::new(channel::Channel::new())),
+        Box::leak(Box::new(DutyLimiter {
+            last_write: Cell::new(Instant::MIN),
+            pending: Signal::new(),
+            memlog,
+        })),
+        Box::leak(Box::new(watch::Watch::new())),
+        Box::leak(Box::new(watch::Watch::new())),
+        Box::leak(Box::new(watch::Watch::new())),
+        Box::leak(Box::new(watch::Watch::new())),
     )
 }
 
+#[derive(Clone, Copy, Debug, Error)]
+pub enum ZoneError {
+    #[error("no such zone")]
+    OutOfRange,
+    #[error("zone must be a whole number")]
+    Invalid,
+}
+
+/// Parses a zone number, between 0 (the main heater) and `board::SSR_EXTRA_ZONES`.
+pub fn parse_zone(s: &str) -> Result<usize, ZoneError> {
+    let zone = s.trim().parse::<usize>().map_err(|_| ZoneError::Invalid)?;
+    if zone > board::SSR_EXTRA_ZONES {
+        return Err(ZoneError::OutOfRange);
+    }
+    Ok(zone)
+}
+
+/// Sets the duty of an extra zone, numbered from 1. Other zones keep their duty.
+///
+/// Extra zones are driven manually: they are not limited, resumed, or run by the thermostat.
+pub fn set_zone_duty(
+    ssrcontrol_zone_sender: &SsrZoneDutyDynSender,
+    zone: usize,
+    duty: Duty,
+) -> Result<(), ZoneError> {
+    if zone == 0 || zone > board::SSR_EXTRA_ZONES {
+        return Err(ZoneError::OutOfRange);
+    }
+
+    ssrcontrol_zone_sender.send_modify(|duties| {
+        let mut updated = duties.unwrap_or([Duty::OFF; board::SSR_EXTRA_ZONES]);
+        updated[zone - 1] = duty;
+        *duties = Some(updated);
+    });
+    Ok(())
+}
+
+/// The highest duty that can be applied at a temperature, following `DUTY_DERATING`.
+pub fn derating_cap(temperature: f32) -> Duty {
+    let Some((full_below, zero_at)) = DUTY_DERATING else {
+        return Duty::FULL;
+    };
+
+    let fraction = (zero_at - temperature) / (zero_at - full_below);
+    // Round down, to never exceed the cap. Also maps NaN to zero.
+    Duty::saturating((fraction.clamp(0.0, 1.0) * 100.0) as u8)
+}
+
+/// The duty applied to the SSR for a commanded duty, after the duty curve and any derating at the
+/// given temperature reading.
+pub fn applied_duty(duty: Duty, reading: Option<TempSensorReading>) -> Duty {
+    match reading {
+        Some(Ok(data)) => duty.mapped().min(derating_cap(data.temperature)),
+        _ => duty.mapped(),
+    }
+}
+
+/// Rate-limits duty writes from the control interfaces (serial, MQTT).
+///
+/// Writes are applied by the `duty_limiter` task at most once per `DUTY_WRITE_INTERVAL`.
+/// Writes arriving faster than that are coalesced, and only the latest one is applied. A held-back
+/// write is dropped if another source sets the duty directly in the meantime.
+pub struct DutyLimiter {
+    last_write: Cell<Instant>,
+    /// The latest write, and when it was requested.
+    pending: Signal<NoopRawMutex, (Duty, DutySource, Instant)>,
+    memlog: SharedLogger,
+}
+
+impl DutyLimiter {
+    /// Requests a new duty cycle.
+    pub fn write(&self, duty: Duty, source: DutySource) {
+        if Instant::now() < self.last_write.get() + DUTY_WRITE_INTERVAL {
+            self.memlog
+                .debug(format!("ssr: duty write throttled, {duty} deferred"));
+        }
+
+        self.pending.signal((duty, source, Instant::now()));
+    }
+
+    /// Drops any write that has not been applied yet.
+    pub fn cancel(&self) {
+        self.pending.reset();
+    }
+}
+
+/// Applies duty writes requested through the `DutyLimiter`.
+#[embassy_executor::task]
+pub async fn duty_limiter(limiter: SsrDutyLimiter, ssrcontrol_duty_sender: SsrDutyDynSender) {
+    loop {
+        // Leave the write pending while it is held back, so that it can still be cancelled.
+        let write = limiter.pending.wait().await;
+        limiter.pending.signal(write);
+
+        // Hold the write back until the interval has elapsed, then apply the latest one.
+        Timer::at(limiter.last_write.get() + DUTY_WRITE_INTERVAL).await;
+        let Some((duty, source, requested_at)) = limiter.pending.try_take() else {
+            continue;
+        };
+        // A direct update since, such as a stop or a remote expiring, is newer. Keep it.
+        if ssrcontrol_duty_sender
+            .try_get()
+            .is_some_and(|update| update.at > requested_at)
+        {
+            limiter.memlog.debug(format!(
+                "ssr: deferred duty write of {duty} superseded, dropped"
+            ));
+            continue;
+        }
+
+        limiter.last_write.set(Instant::now());
+        ssrcontrol_duty_sender.send(DutyUpdate::new(duty, source));
+    }
+}
+
+/// Publishes commands handed over by tasks that can't wait on the command channel, such as the
+/// MQTT client, which also drains a subscriber of it.
+///
+/// Waits for room rather than evicting a queued command, which could be a safety lock or fault.
+#[embassy_executor::task]
+pub async fn command_relay(
+    relay: SsrCommandRelay,
+    ssrcontrol_command_publisher: SsrCommandPublisher,
+) {
+    loop {
+        let command = relay.receive().await;
+        ssrcontrol_command_publisher.publish(command).await;
+    }
+}
+
+/// Steps the duty from `from` to `to` over `duration`, with one update per pattern step.
+///
+/// The final update sets `to`. Stops early if another source sets the duty in the meantime.
+pub async fn ramp_duty(
+    ssrcontrol_duty_sender: &SsrDutyDynSender,
+    from: Duty,
+    to: Duty,
+    duration: Duration,
+    source: DutySource,
+) {
+    let steps = (duration.as_ticks() / PATTERN_STEP_DURATION.as_ticks()).max(1) as i32;
+    let (from, to) = (from.percent() as i32, to.percent() as i32);
+
+    for step in 1..=steps {
+        let taken_over = ssrcontrol_duty_sender
+            .try_get()
+            .is_some_and(|update| update.source != source);
+        if step > 1 && taken_over {
+            return;
+        }
+
+        let percent = from + (to - from) * step / steps;
+        ssrcontrol_duty_sender.send(DutyUpdate::new(Duty::saturating(percent as u8), source));
+        if step < steps {
+            Timer::after(PATTERN_STEP_DURATION).await;
+        }
+    }
+}
+
 #[embassy_executor::task]
 pub async fn ssr_control(
-    mut ssrcontrol_pin: gpio::Output<'static>,
+    mut ssrcontrol_pin: board::SsrPin,
+    ssrcontrol_feedback: Option<gpio::Input<'static>>,
+    mut ssrcontrol_zone_pins: [board::SsrPin; board::SSR_EXTRA_ZONES],
     mut ssrcontrol_duty_receiver: SsrDutyDynReceiver,
+    mut ssrcontrol_zone_receiver: SsrZoneDutyDynReceiver,
     mut ssrcontrol_command_subscriber: SsrCommandSubscriber,
+    ssrcontrol_status_sender: SsrStatusDynSender,
+    ssrcontrol_pattern_sender: SsrPatternDynSender,
+    ssrcontrol_alert_sender: SsrAlertDynSender,
+    mut tempsensor_receiver: TempSensorDynReceiver,
+    memlog: SharedLogger,
+    state: SharedState,
+    stats: SharedStats,
 ) {
-    // Generate an initial pattern for 100% duty cycle.
-    let mut pattern = generate_evenly_distributed_steps(100);
+    // The status lives in the shared state, so a restart picks up a lock or fault in place.
+    // Otherwise, every output starts off until a duty arrives.
+    let mut controller = SsrController::new(state.lock().await.ssr_status());
+    ssrcontrol_status_sender.send(controller.status());
+    ssrcontrol_pattern_sender.send(controller.pattern());
+
+    // The last alert raised, and when, for debouncing.
+    let mut last_alert: Option<(AlertReason, Instant)> = None;
 
-    // Locking the SSR sets its duty to zero and ignores any commands until an unlock.
-    let mut is_locked = false;
+    // The level last set on the pin, and for how many steps in a row the feedback disagreed.
+    let mut level_set = board::ssr_level(false);
+    let mut feedback_mismatches: u32 = 0;
 
     loop {
-        for step in 0..100 {
+        for step in 0..TOTAL_STEPS {
             Timer::after(PATTERN_STEP_DURATION).await;
 
-            if pattern[step] {
-                ssrcontrol_pin.set_high();
-            } else {
-                ssrcontrol_pin.set_low();
+            // Check that the output followed the last step, now that it has had a step to settle.
+            let mut feedback_fault = None;
+            if let Some(feedback) = &ssrcontrol_feedback {
+                if feedback.level() == level_set {
+                    feedback_mismatches = 0;
+                } else {
+                    feedback_mismatches += 1;
+                }
+
+                // Keeps faulting while the mismatch lasts, should an operator clear it.
+                let faulted = matches!(controller.status(), SsrStatus::Fault(_));
+                if feedback_mismatches >= FEEDBACK_MISMATCH_STEPS && !faulted {
+                    memlog.error(format!(
+                        "ssr control: feedback disagreed with the output for {feedback_mismatches} steps, ssr latched off"
+                    ));
+                    feedback_fault = Some(SsrCommand::Fault(FaultReason::OutputMismatch));
+                }
             }
 
-            // See if we have a lock/unlock message.
-            if let Some(pubsub::WaitResult::Message(command)) =
-                ssrcontrol_command_subscriber.try_next_message()
-            {
-                match command {
-                    SsrCommand::Lock => {
-                        pattern = [false; 100];
-                        is_locked = true;
-                    }
-                    SsrCommand::Unlock => is_locked = false,
+            let on = controller.fire(step, &mut ssrcontrol_pin, &mut ssrcontrol_zone_pins);
+            level_set = board::ssr_level(on);
+
+            // See if we have a lock/unlock message, or a fault from the feedback check.
+            let command = match ssrcontrol_command_subscriber.try_next_message() {
+                Some(pubsub::WaitResult::Message(command)) => Some(command),
+                Some(pubsub::WaitResult::Lagged(missed)) => {
+                    stats.ssr_command_lagged.add(missed as u32);
+                    None
+                }
+                None => None,
+            };
+            // A command that arrives on the same step delays the fault to the next one.
+            if let Some(command) = command.or(feedback_fault) {
+                let outcome = controller.command(command);
+                if outcome.refused {
+                    memlog.warn("ssr control: unlock refused, the interlock is open");
+                }
+                if let Some(alert) = outcome.alert {
+                    raise_alert(alert, &mut last_alert, &ssrcontrol_alert_sender, memlog);
+                }
+                if outcome.cleared {
+                    ssrcontrol_pattern_sender.send(controller.pattern());
+                }
+                if outcome.status_changed {
+                    state.lock().await.set_ssr_status(controller.status());
+                    ssrcontrol_status_sender.send(controller.status());
                 }
             }
 
-            // See if we have a new duty cycle.
-            // We simply replace the pattern and continue from the same step position.
-            // Since the pattern is evenly distributed, this puts us right into the
-            // new duty cycle.
-            if !is_locked {
-                if let Some(new_duty_cycle) = ssrcontrol_duty_receiver.try_changed() {
-                    pattern = generate_evenly_distributed_steps(new_duty_cycle);
+            // See if the derating cap has changed.
+            let mut cap_changed = false;
+            if let Some(Ok(data)) = tempsensor_receiver.try_changed() {
+                cap_changed = controller.set_cap(derating_cap(data.temperature));
+            }
+
+            // See if we have a new duty cycle. While locked, a new duty is left in the watch,
+            // to be picked up after an unlock.
+            if controller.status() == SsrStatus::Unlocked {
+                let duty = ssrcontrol_duty_receiver
+                    .try_changed()
+                    .map(|update| update.duty);
+                if duty.is_some() || cap_changed {
+                    let alert = controller.update_duty(duty);
+                    ssrcontrol_pattern_sender.send(controller.pattern());
+                    if let Some(alert) = alert {
+                        raise_alert(alert, &mut last_alert, &ssrcontrol_alert_sender, memlog);
+                    }
+                }
+
+                // Likewise for the extra zones.
+                let zone_duties = ssrcontrol_zone_receiver.try_changed();
+                if zone_duties.is_some() || cap_changed {
+                    controller.update_zone_duties(zone_duties);
                 }
             }
         }
     }
 }
 
-/// Turns a duty cycle percentage into a pattern of on/off steps of equal duration.
-///
-/// These steps are evenly distributed, maximizing the number of transitions.
-///
-/// Example output:
-///   0%: ····································································································
-///   1%: ·················································o··················································
-///   2%: ························o·················································o·························
-///   3%: ················o································o·································o················
-///   4%: ············o························o························o························o············
-///   5%: ·········o···················o···················o···················o···················o··········
-///   ..
-///  50%: o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·o·
-///   ..
-///  96%: oooooooooooo·oooooooooooooooooooooooo·oooooooooooooooooooooooo·oooooooooooooooooooooooo·oooooooooooo
-///  97%: oooooooooooooooo·ooooooooooooooooooooooooooooooooo·oooooooooooooooooooooooooooooooo·oooooooooooooooo
-///  98%: ooooooooooooooooooooooooo·ooooooooooooooooooooooooooooooooooooooooooooooooo·oooooooooooooooooooooooo
-///  99%: oooooooooooooooooooooooooooooooooooooooooooooooooo·ooooooooooooooooooooooooooooooooooooooooooooooooo
-/// 100%: oooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooooo
-fn generate_evenly_distributed_steps(duty_percent: u8) -> [bool; 100] {
-    const TOTAL_STEPS: usize = 100;
-    const TOTAL_STEPS_I32: i32 = TOTAL_STEPS as i32;
-
-    if duty_percent > 100 {
-        panic!("duty cycle outside 0.100 range");
-    }
-
-    // The target number of ON steps.
-    let num_on_steps_target = duty_percent as i32;
-
-    // Initialize the output array with all steps OFF (false).
-    let mut steps_array: [bool; TOTAL_STEPS] = [false; TOTAL_STEPS];
-
-    // Initialize the accumulator.
-    // Starting at `TOTAL_STEPS/2` centers the distribution of ON pulses.
-    let mut accumulator: i32 = TOTAL_STEPS_I32 / 2;
-
-    // Loop through each of the 100 steps to decide if it's ON or OFF.
-    for step in steps_array.iter_mut() {
-        // Add the "target density" of ON states to the accumulator.
-        accumulator += num_on_steps_target;
-
-        // Check if the accumulator has reached the threshold.
-        if accumulator >= TOTAL_STEPS_I32 {
-            *step = true; // This step is ON.
-            // "Spend" the credit for one ON pulse by subtracting TOTAL_STEPS
-            // from the accumulator.
-            accumulator -= TOTAL_STEPS_I32;
-        }
-        // Else, the step remains OFF (false), which is its initialized state.
+/// Logs and sends an alert, unless one for the same reason went out within `ALERT_DEBOUNCE`.
+fn raise_alert(
+    alert: SsrAlert,
+    last_alert: &mut Option<(AlertReason, Instant)>,
+    ssrcontrol_alert_sender: &SsrAlertDynSender,
+    memlog: SharedLogger,
+) {
+    let repeated = last_alert
+        .is_some_and(|(reason, at)| reason == alert.reason && at.elapsed() < ALERT_DEBOUNCE);
+    if repeated {
+        return;
     }
+    *last_alert = Some((alert.reason, Instant::now()));
 
-    steps_array
+    memlog.error(format!(
+        "ssr: {}, duty held at {}% of {}% commanded",
+        alert.reason.description(),
+        alert.applied,
+        alert.commanded
+    ));
+    ssrcontrol_alert_sender.send(alert);
 }
@@ -1,7 +1,17 @@
-use crate::task::ssr_control::{SsrCommand, SsrCommandPublisher};
-use alloc::boxed::Box;
-use embassy_sync::{blocking_mutex::raw::NoopRawMutex, watch};
-use embassy_time::{Duration, Timer};
+use crate::{
+    dwell::Dwell,
+    memlog::SharedLogger,
+    settings::{SharedSettings, TempAlarms, TempUnit},
+    state::SharedState,
+    stats::SharedStats,
+    task::ssr_control::{
+        Duty, FaultReason, LockReason, SsrCommand, SsrCommandPublisher, SsrStatus,
+    },
+};
+use alloc::{boxed::Box, format, string::String};
+use embassy_futures::select;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex, signal::Signal, watch};
+use embassy_time::{Duration, Instant, Timer};
 use esp_ds18b20::{Ds18b20, Ds18b20Error, Resolution, SensorData};
 use esp_hal::gpio;
 use esp_onewire::OneWireBus;
@@ -11,61 +21,503 @@ pub type TempSensorWatch<const W: usize> =
 pub type TempSensorDynSender = watch::DynSender<'static, TempSensorReading>;
 pub type TempSensorDynReceiver = watch::DynReceiver<'static, TempSensorReading>;
 
-pub type TempSensorReading = Result<SensorData, Ds18b20Error>;
+pub type TempSensorReading = Result<TempData, Ds18b20Error>;
 
-pub fn init<const WATCHERS: usize>() -> TempSensorWatch<WATCHERS> {
-    Box::leak(Box::new(watch::Watch::new()))
+/// A temperature reading from the primary sensor, in Celsius.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TempData {
+    pub temperature: f32,
+    /// Set when the sensor failed to read, and this is the last good reading instead.
+    /// Holds the age of that reading.
+    pub stale: Option<Duration>,
+}
+
+impl TempData {
+    /// Whether this is a reading from the last measurement.
+    pub fn is_fresh(&self) -> bool {
+        self.stale.is_none()
+    }
+}
+
+pub type TempAlarmWatch<const W: usize> = &'static watch::Watch<NoopRawMutex, TempAlarm, W>;
+pub type TempAlarmDynSender = watch::DynSender<'static, TempAlarm>;
+pub type TempAlarmDynReceiver = watch::DynReceiver<'static, TempAlarm>;
+
+/// Requests a measurement right away, instead of waiting for the next interval.
+pub type TempMeasureSignal = &'static Signal<NoopRawMutex, ()>;
+
+/// Takes consts that set the maximum number of watchers for readings and for alarms.
+/// Also returns a signal to request an immediate measurement.
+pub fn init<const WATCHERS: usize, const ALARM_WATCHERS: usize>() -> (
+    TempSensorWatch<WATCHERS>,
+    TempAlarmWatch<ALARM_WATCHERS>,
+    TempMeasureSignal,
+) {
+    let watch = Box::leak(Box::new(watch::Watch::new()));
+    let alarm_watch = Box::leak(Box::new(watch::Watch::new()));
+    let measure_signal = Box::leak(Box::new(Signal::new()));
+    (watch, alarm_watch, measure_signal)
+}
+
+/// The 1-Wire bus shared by the primary and cutoff sensors, which are addressed by ROM.
+pub type TempBus = &'static Mutex<NoopRawMutex, OneWire>;
+
+/// The 1-Wire bus pin, and when the conversion in progress on it completes, if one was started
+/// but not yet read.
+pub struct OneWire {
+    pin: gpio::AnyPin<'static>,
+    converting_until: Option<Instant>,
+}
+
+pub fn init_bus(onewire_pin: gpio::AnyPin<'static>) -> TempBus {
+    Box::leak(Box::new(Mutex::new(OneWire {
+        pin: onewire_pin,
+        converting_until: None,
+    })))
+}
+
+/// Which of the soft alarm thresholds in `TempAlarms` was crossed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TempAlarmKind {
+    Low,
+    High,
+}
+
+impl TempAlarmKind {
+    const ALL: [TempAlarmKind; 2] = [TempAlarmKind::Low, TempAlarmKind::High];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TempAlarmKind::Low => "low",
+            TempAlarmKind::High => "high",
+        }
+    }
+
+    fn threshold(self, temp_alarms: &TempAlarms) -> Option<f32> {
+        match self {
+            TempAlarmKind::Low => temp_alarms.low(),
+            TempAlarmKind::High => temp_alarms.high(),
+        }
+    }
+
+    /// Whether the temperature is past the threshold. An active alarm only clears once the
+    /// temperature is back by `TEMP_ALARM_HYSTERESIS`.
+    fn is_tripped(self, threshold: f32, temperature: f32, active: bool) -> bool {
+        let margin = if active { TEMP_ALARM_HYSTERESIS } else { 0.0 };
+        match self {
+            TempAlarmKind::Low => temperature < threshold + margin,
+            TempAlarmKind::High => temperature > threshold - margin,
+        }
+    }
+}
+
+/// Raised when a soft alarm threshold is crossed, and again when it clears.
+#[derive(Clone, Copy, Debug)]
+pub struct TempAlarm {
+    pub kind: TempAlarmKind,
+    pub active: bool,
+    /// The reading that crossed the threshold, in Celsius.
+    pub temperature: f32,
+    /// In Celsius. None if the threshold was disabled while the alarm was active.
+    pub threshold: Option<f32>,
+}
+
+/// Formats a sensor reading for display, converting the temperature to the given unit.
+pub fn format_reading(reading: &TempSensorReading, unit: TempUnit) -> String {
+    match reading {
+        Ok(TempData {
+            temperature,
+            stale: None,
+        }) => format!("{}{}", unit.convert(*temperature), unit.symbol()),
+        Ok(TempData {
+            temperature,
+            stale: Some(age),
+        }) => format!(
+            "{}{} (stale, {}s)",
+            unit.convert(*temperature),
+            unit.symbol(),
+            age.as_secs()
+        ),
+        Err(error) => format!("{error:?}"),
+    }
 }
 
 const TEMP_SENSOR_ADDRESS: u64 = 0x545A7B480B646128;
 const TEMP_MEASUREMENT_INTERVAL: Duration = Duration::from_secs(10);
 
-// Hysteresis temperature ranges for locking and unlocking the SSR control.
-const TEMP_LIMIT_HIGH: f32 = 70.0;
-const TEMP_LIMIT_LOW: f32 = 30.0;
+// The primary sensor is measured faster while the heater is being driven, at the regular interval
+// while it holds a steady duty, and slower once it's off. The fast interval is never shorter than
+// the conversion time.
+const TEMP_MEASUREMENT_INTERVAL_FAST: Duration = Duration::from_secs(2);
+const TEMP_MEASUREMENT_INTERVAL_SLOW: Duration = Duration::from_secs(30);
+// Thermostat error, in Celsius, above which measurements are taken at the fast interval.
+const TEMP_MEASUREMENT_FAST_ERROR: f32 = 1.0;
+
+// ROM address of the cutoff DS18B20, on the same bus as the primary sensor. The cutoff is disabled
+// if unset.
+const TEMP_CUTOFF_SENSOR_ADDRESS: Option<u64> = None;
+// Absolute temperature limit on the cutoff sensor, in Celsius.
+const TEMP_CUTOFF_MAX: f32 = 85.0;
+
+// Once locked on over-temperature, the SSR stays locked at least this long, even if the
+// temperature drops below the low limit sooner. Protects the SSR and load from rapid cycling.
+const TEMP_LOCK_MIN_DWELL: Duration = Duration::from_secs(60);
+
+// Soft alarms clear only once the temperature is back past their threshold by this much, in Celsius.
+const TEMP_ALARM_HYSTERESIS: f32 = 1.0;
+
+// A sensor failing this many readings in a row is considered disconnected, which is a fault.
+const TEMP_SENSOR_MAX_FAILURES: u32 = 3;
+// Every this many failed readings in a row, the sensor driver is set up from scratch, in case the
+// sensor recovered after a failed setup. The fault stays latched regardless.
+const TEMP_BUS_RESET_FAILURES: u32 = 5;
+
+// After a failed read, the last good reading is sent instead, marked stale, for this long after
+// it was taken. Past that, the error itself is sent. Set to `None` to always send errors.
+// This only affects what consumers see: the fault still latches after `TEMP_SENSOR_MAX_FAILURES`.
+const TEMP_STALE_GRACE: Option<Duration> = Some(Duration::from_secs(30));
+
+/// A DS18B20 on the shared 1-Wire bus.
+///
+/// Every bus transaction goes through [`TempProbe::measure`], which holds the bus for the whole
+/// conversion, so two conversions can never overlap on the bus, nor a sensor be addressed while
+/// the other converts. An on-demand measurement goes through the same bus as the periodic ones,
+/// and so waits its turn.
+///
+/// Measuring is cancel-safe. If a measurement is dropped mid-conversion, the next one on the bus
+/// waits for that conversion to complete before addressing a sensor again.
+struct TempProbe {
+    address: u64,
+    bus: TempBus,
+}
+
+impl TempProbe {
+    /// Sets up the driver for the sensor at `address`, to check that it responds.
+    async fn new(address: u64, bus: TempBus) -> Result<Self, Ds18b20Error> {
+        let mut onewire = bus.lock().await;
+        Ds18b20::new(address, OneWireBus::new(onewire.pin.reborrow()))?;
+        Ok(TempProbe { address, bus })
+    }
+
+    /// Runs a conversion and reads back its result.
+    async fn measure(&mut self) -> Result<SensorData, Ds18b20Error> {
+        let mut onewire = self.bus.lock().await;
+        let OneWire {
+            pin,
+            converting_until,
+        } = &mut *onewire;
+
+        // A previous measurement was dropped before reading its conversion.
+        if let Some(until) = *converting_until {
+            Timer::at(until).await;
+            *converting_until = None;
+        }
+
+        let mut sensor = Ds18b20::new(self.address, OneWireBus::new(pin.reborrow()))?;
+        sensor.start_temp_measurement()?;
+        let until = Instant::now() + conversion_time();
+        *converting_until = Some(until);
+        Timer::at(until).await;
+        *converting_until = None;
+
+        sensor.read_sensor_data()
+    }
+}
+
+/// How long a conversion takes. 12bit resolution is the default, expects a 750ms wait time.
+fn conversion_time() -> Duration {
+    Duration::from_millis(Resolution::Bits12.measurement_time_ms() as u64)
+}
 
 #[embassy_executor::task]
 pub async fn temp_sensor(
-    onewire_pin: gpio::AnyPin<'static>,
+    onewire_bus: TempBus,
     tempsensor_sender: TempSensorDynSender,
+    tempalarm_sender: TempAlarmDynSender,
+    measure_signal: TempMeasureSignal,
     ssrcontrol_command_sender: SsrCommandPublisher,
+    memlog: SharedLogger,
+    state: SharedState,
+    settings: SharedSettings,
+    stats: SharedStats,
 ) {
-    let onewire_bus = OneWireBus::new(onewire_pin);
-    let mut sensor = Ds18b20::new(TEMP_SENSOR_ADDRESS, onewire_bus).unwrap();
+    // The probe can be set up again after a failure. A failed reset leaves the error in place of
+    // the probe, until the next reset.
+    let mut probe = TempProbe::new(TEMP_SENSOR_ADDRESS, onewire_bus).await;
+    if let Err(error) = &probe {
+        // Without readings the over-temperature lock can't work, so latch the SSR off. Carry on
+        // regardless: the loop counts the failures, asserts the fault again should an operator
+        // clear it, and resets the bus until the sensor responds.
+        memlog.error(format!(
+            "temp sensor: init failed, ssr latched off: {error:?}"
+        ));
+        ssrcontrol_command_sender
+            .publish(SsrCommand::Fault(FaultReason::SensorFailure))
+            .await;
+    }
+
+    // The SSR control task owns the lock, through the shared state. Pick up a lock already in
+    // place, so that it is released with hysteresis as usual.
+    let mut temperature_lock = Dwell::new(TEMP_LOCK_MIN_DWELL);
+    if state.lock().await.ssr_status() == SsrStatus::Locked(LockReason::OverTemperature) {
+        temperature_lock.engage();
+    }
+    let mut consecutive_failures: u32 = 0;
+    // Whether each soft alarm is active, in the order of `TempAlarmKind::ALL`.
+    let mut alarms_active = [false; TempAlarmKind::ALL.len()];
+    // The last good temperature, and when it was measured.
+    let mut last_good: Option<(f32, Instant)> = None;
 
-    let mut temperature_exceeded = false;
+    // Start fast, so the first reading arrives soon after boot.
+    let mut measurement_interval = TEMP_MEASUREMENT_INTERVAL_FAST;
+    let mut last_duty = state.lock().await.duty();
 
     loop {
-        Timer::after(TEMP_MEASUREMENT_INTERVAL).await;
+        // Measurements only happen here, through the probe, so conversions never overlap.
+        // Requests that arrive during a measurement are coalesced into the next one.
+        select::select(Timer::after(measurement_interval), measure_signal.wait()).await;
 
-        // Attempt to catch errors from 1Wire.
-        let sensor_reading: Result<SensorData, Ds18b20Error> = async {
-            // Begin a measurement and wait for it to complete.
-            sensor.start_temp_measurement()?;
+        // Calibrate the reading, keeping the raw value for diagnostics.
+        let temp_calibration = settings.lock().await.temp_calibration;
+        let measurement = match &mut probe {
+            Ok(probe) => probe.measure().await,
+            Err(error) => Err(error.clone()),
+        }
+        .map(|data| {
+            stats.temp_raw.set(Some(data.temperature));
+            temp_calibration.apply(data.temperature)
+        });
 
-            // 12bit resolution is the default, expects a 750ms wait time.
-            let wait_time_ms = Resolution::Bits12.measurement_time_ms();
-            let wait_time = Duration::from_millis(wait_time_ms as u64);
-            Timer::after(wait_time).await;
+        // Lock the SSR if the temperature reading exceeds a limit.
+        // Unlock with hysteresis, and not before the minimum dwell time.
+        // The limits can change at runtime.
+        // An operator unlock releases the lock without us, so follow it, and lock again if the
+        // temperature is still over the limit.
+        let temp_limits = settings.lock().await.temp_limits;
+        if temperature_lock.is_engaged() && state.lock().await.ssr_status() == SsrStatus::Unlocked {
+            temperature_lock.clear();
+        }
+        if let Ok(temperature) = &measurement {
+            if temperature_lock.is_engaged() && *temperature < temp_limits.low() {
+                if temperature_lock.release() {
+                    ssrcontrol_command_sender
+                        .publish(SsrCommand::Unlock(LockReason::OverTemperature))
+                        .await;
+                }
+            } else if *temperature >= temp_limits.high() && temperature_lock.engage() {
+                ssrcontrol_command_sender
+                    .publish(SsrCommand::Lock(LockReason::OverTemperature))
+                    .await;
+            }
+        }
 
-            let data = sensor.read_sensor_data()?;
+        // Notify soft alarm crossings. These never act on the SSR.
+        let temp_alarms = settings.lock().await.temp_alarms;
+        if let Ok(temperature) = &measurement {
+            for (kind, active) in TempAlarmKind::ALL.iter().zip(alarms_active.iter_mut()) {
+                let threshold = kind.threshold(&temp_alarms);
+                let tripped = threshold
+                    .is_some_and(|threshold| kind.is_tripped(threshold, *temperature, *active));
+                if tripped == *active {
+                    continue;
+                }
 
-            Ok(data)
+                *active = tripped;
+                if tripped {
+                    memlog.warn(format!(
+                        "temp alarm: {temperature}°C crossed the {} threshold",
+                        kind.name()
+                    ));
+                } else {
+                    memlog.info(format!("temp alarm: {} cleared", kind.name()));
+                }
+                tempalarm_sender.send(TempAlarm {
+                    kind: *kind,
+                    active: tripped,
+                    temperature: *temperature,
+                    threshold,
+                });
+            }
         }
-        .await;
 
-        // Lock the SSR if the temperature reading exceeds a limit.
-        // Unlock with hysteresis.
-        if let Ok(SensorData { temperature, .. }) = &sensor_reading {
-            if temperature_exceeded && *temperature < TEMP_LIMIT_LOW {
-                temperature_exceeded = false;
-                ssrcontrol_command_sender.publish(SsrCommand::Unlock).await;
-            } else if !temperature_exceeded && *temperature >= TEMP_LIMIT_HIGH {
-                temperature_exceeded = true;
-                ssrcontrol_command_sender.publish(SsrCommand::Lock).await;
+        // Without readings the over-temperature lock can't work, so latch the SSR off.
+        if measurement.is_ok() {
+            consecutive_failures = 0;
+        } else {
+            // Assert the fault as the sensor goes down, and again should an operator clear it
+            // while it is still down, as with the cutoff. Not on every sample, which would flood
+            // the command channel.
+            consecutive_failures = consecutive_failures.saturating_add(1);
+            if consecutive_failures >= TEMP_SENSOR_MAX_FAILURES {
+                if consecutive_failures == TEMP_SENSOR_MAX_FAILURES {
+                    memlog.error("temp sensor: not responding, ssr latched off");
+                }
+                if consecutive_failures == TEMP_SENSOR_MAX_FAILURES || !is_faulted(state).await {
+                    ssrcontrol_command_sender
+                        .publish(SsrCommand::Fault(FaultReason::SensorFailure))
+                        .await;
+                }
+            }
+
+            if consecutive_failures % TEMP_BUS_RESET_FAILURES == 0 {
+                memlog.warn(format!(
+                    "temp sensor: {consecutive_failures} failures in a row, resetting the 1-wire bus"
+                ));
+                probe = TempProbe::new(TEMP_SENSOR_ADDRESS, onewire_bus).await;
+                if let Err(error) = &probe {
+                    memlog.error(format!("temp sensor: bus reset failed: {error:?}"));
+                }
             }
         }
 
+        // Fall back to the last good reading for a while, so brief glitches don't blank it.
+        let sensor_reading = match measurement {
+            Ok(temperature) => {
+                last_good = Some((temperature, Instant::now()));
+                Ok(TempData {
+                    temperature,
+                    stale: None,
+                })
+            }
+            Err(error) => match (last_good, TEMP_STALE_GRACE) {
+                (Some((temperature, measured_at)), Some(grace))
+                    if measured_at.elapsed() <= grace =>
+                {
+                    Ok(TempData {
+                        temperature,
+                        stale: Some(measured_at.elapsed()),
+                    })
+                }
+                _ => Err(error),
+            },
+        };
+
+        // Sample faster while the duty is changing, or the thermostat is far from its setpoint.
+        {
+            let state = state.lock().await;
+            let duty_changed = state.duty() != last_duty;
+            let heating = state.duty() != Duty::OFF;
+            last_duty = state.duty();
+
+            let thermostat_error = match (&sensor_reading, state.setpoint()) {
+                (Ok(data), Some(setpoint)) if state.is_thermostat() => {
+                    Some(setpoint - data.temperature)
+                }
+                _ => None,
+            };
+            measurement_interval = adaptive_interval(duty_changed, heating, thermostat_error);
+        }
+
         tempsensor_sender.send(sensor_reading);
+        stats.temp_sent.increment();
+    }
+}
+
+/// Picks the interval until the next measurement from the heater activity.
+fn adaptive_interval(duty_changed: bool, heating: bool, thermostat_error: Option<f32>) -> Duration {
+    let error_large =
+        thermostat_error.is_some_and(|error| error.abs() > TEMP_MEASUREMENT_FAST_ERROR);
+
+    if duty_changed || error_large {
+        // Don't start a conversion before the previous one could have completed.
+        TEMP_MEASUREMENT_INTERVAL_FAST.max(conversion_time())
+    } else if heating {
+        TEMP_MEASUREMENT_INTERVAL
+    } else {
+        TEMP_MEASUREMENT_INTERVAL_SLOW
+    }
+}
+
+/// Watches a second sensor on the shared bus, addressed by ROM, and latches the SSR off past an
+/// absolute limit.
+///
+/// This is a safety backstop to the regular over-temperature lock, and does not depend on the
+/// primary sensor task or its hysteresis. Trips and sensor failures are faults, which only an
+/// operator can clear.
+#[embassy_executor::task]
+pub async fn temp_cutoff(
+    onewire_bus: TempBus,
+    ssrcontrol_command_sender: SsrCommandPublisher,
+    memlog: SharedLogger,
+    state: SharedState,
+) {
+    let Some(sensor_address) = TEMP_CUTOFF_SENSOR_ADDRESS else {
+        memlog.warn("temp cutoff: no sensor address set, cutoff disabled");
+        return;
+    };
+
+    // A probe that failed to set up is retried on every interval, and counts as a failed reading
+    // meanwhile.
+    let mut probe = TempProbe::new(sensor_address, onewire_bus).await;
+    let mut consecutive_failures: u32 = 0;
+    if let Err(error) = &probe {
+        memlog.error(format!(
+            "temp cutoff: sensor init failed, ssr latched off: {error:?}"
+        ));
+        ssrcontrol_command_sender
+            .publish(SsrCommand::Fault(FaultReason::CutoffSensorFailure))
+            .await;
+        // Only assert the fault again should an operator clear it.
+        consecutive_failures = TEMP_SENSOR_MAX_FAILURES;
     }
+
+    // Used to log only on changes.
+    let mut limit_exceeded = false;
+
+    loop {
+        Timer::after(TEMP_MEASUREMENT_INTERVAL).await;
+
+        if probe.is_err() {
+            probe = TempProbe::new(sensor_address, onewire_bus).await;
+        }
+        let measurement = match &mut probe {
+            Ok(probe) => probe.measure().await,
+            Err(error) => Err(error.clone()),
+        };
+
+        match measurement {
+            Ok(SensorData { temperature, .. }) => {
+                consecutive_failures = 0;
+
+                if temperature >= TEMP_CUTOFF_MAX {
+                    // Assert the cutoff again for as long as the limit is exceeded, so an
+                    // operator unlock doesn't release it while still hot.
+                    if !limit_exceeded || !is_faulted(state).await {
+                        ssrcontrol_command_sender
+                            .publish(SsrCommand::Fault(FaultReason::CutoffTemperature))
+                            .await;
+                    }
+                    if !limit_exceeded {
+                        memlog.error(format!(
+                            "temp cutoff: {temperature}°C exceeds {TEMP_CUTOFF_MAX}°C, ssr latched off"
+                        ));
+                    }
+                    limit_exceeded = true;
+                } else {
+                    limit_exceeded = false;
+                }
+            }
+            Err(error) => {
+                consecutive_failures = consecutive_failures.saturating_add(1);
+                if consecutive_failures >= TEMP_SENSOR_MAX_FAILURES {
+                    if consecutive_failures == TEMP_SENSOR_MAX_FAILURES {
+                        memlog.error(format!(
+                            "temp cutoff: sensor not responding, ssr latched off: {error:?}"
+                        ));
+                    }
+                    if consecutive_failures == TEMP_SENSOR_MAX_FAILURES || !is_faulted(state).await
+                    {
+                        ssrcontrol_command_sender
+                            .publish(SsrCommand::Fault(FaultReason::CutoffSensorFailure))
+                            .await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether the SSR is latched off by a fault, for any reason.
+async fn is_faulted(state: SharedState) -> bool {
+    matches!(state.lock().await.ssr_status(), SsrStatus::Fault(_))
 }
@@ -0,0 +1,66 @@
+use crate::{
+    memlog::SharedLogger,
+    state::SharedState,
+    stats::SharedStats,
+    task::{
+        ssr_control::{Duty, DutySource, DutyUpdate, SsrDutyDynSender},
+        temp_sensor::TempSensorDynReceiver,
+    },
+};
+use alloc::format;
+
+/// Range of accepted thermostat setpoints, in Celsius.
+pub const SETPOINT_MIN: f32 = 5.0;
+pub const SETPOINT_MAX: f32 = 50.0;
+
+// Proportional gain, in duty percent per degree Celsius of error.
+// At 20%/°C the heater runs at full duty when 5°C or more below the setpoint.
+const THERMOSTAT_GAIN: f32 = 20.0;
+
+/// Sets the heater duty from each new temperature reading while in Thermostat mode.
+#[embassy_executor::task]
+pub async fn thermostat(
+    mut tempsensor_receiver: TempSensorDynReceiver,
+    ssrcontrol_duty_sender: SsrDutyDynSender,
+    memlog: SharedLogger,
+    state: SharedState,
+    stats: SharedStats,
+) {
+    loop {
+        let sensor_reading = tempsensor_receiver.changed().await;
+        stats.temp_observed_thermostat.increment();
+
+        let mut state = state.lock().await;
+        if !state.is_thermostat() {
+            continue;
+        }
+        let Some(setpoint) = state.setpoint() else {
+            continue;
+        };
+
+        let duty = match sensor_reading {
+            Ok(data) if data.is_fresh() => thermostat_duty(setpoint, data.temperature),
+            // Hold the current duty through a brief sensor glitch. If the sensor stays down,
+            // errors follow once the stale reading expires.
+            Ok(_) => continue,
+            // Don't hold a duty without knowing the temperature.
+            Err(error) => {
+                memlog.warn(format!(
+                    "thermostat: sensor error, duty set to 0: {error:?}"
+                ));
+                Duty::OFF
+            }
+        };
+
+        if state.thermostat_update_duty(duty).is_ok() {
+            ssrcontrol_duty_sender.send(DutyUpdate::new(duty, DutySource::Thermostat));
+        }
+    }
+}
+
+/// Computes a duty cycle proportional to the error between the setpoint and the measured
+/// temperature.
+fn thermostat_duty(setpoint: f32, measured: f32) -> Duty {
+    let error = setpoint - measured;
+    Duty::saturating((error * THERMOSTAT_GAIN).clamp(0.0, 100.0) as u8)
+}
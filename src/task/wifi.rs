@@ -1,5 +1,7 @@
 use crate::memlog::SharedLogger;
 use alloc::{boxed::Box, format};
+use embassy_futures::select;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
 use embassy_time::{Duration, Timer};
 use esp_hal::{peripherals, rng::Rng};
 use esp_wifi::{
@@ -12,6 +14,16 @@ use crate::config::WIFI_PASS;
 use crate::config::WIFI_SSID;
 // How long to wait before attempting to reconnect to WiFi.
 const WIFI_RECONNECT_PAUSE: Duration = Duration::from_secs(5);
+// If set, the first connection waits a random time up to this long, so that devices powering up
+// together don't all hit DHCP, DNS and the MQTT broker at once. E.g. `Some(Duration::from_secs(30))`.
+const WIFI_STARTUP_DELAY_MAX: Option<Duration> = None;
+
+/// Asks the WiFi task to drop the connection and associate again.
+pub type WifiReconnectSignal = &'static Signal<NoopRawMutex, ()>;
+
+pub fn init_reconnect() -> WifiReconnectSignal {
+    Box::leak(Box::new(Signal::new()))
+}
 
 /// Initializes the WiFi in client mode.
 ///
@@ -48,17 +60,34 @@ pub async fn init(
 #[embassy_executor::task]
 pub async fn wifi_permanent_connection(
     mut controller: wifi::WifiController<'static>,
+    reconnect_signal: WifiReconnectSignal,
+    mut rng: Rng,
     memlog: SharedLogger,
 ) {
     memlog.debug(format!("wifi: state: {:?}", wifi::wifi_state()));
 
+    // Stagger the network bring-up. Everything else runs in the meantime.
+    if let Some(delay_max) = WIFI_STARTUP_DELAY_MAX {
+        let delay = Duration::from_millis(rng.random() as u64 % (delay_max.as_millis() + 1));
+        memlog.info(format!("wifi: startup delayed by {}ms", delay.as_millis()));
+        Timer::after(delay).await;
+    }
+
     loop {
-        // If we're still connected, wait until we disconnect.
+        // If we're still connected, wait until we disconnect or are asked to reconnect.
         if wifi::wifi_state() == WifiState::StaConnected {
-            controller
-                .wait_for_event(wifi::WifiEvent::StaDisconnected)
-                .await;
+            let disconnected = controller.wait_for_event(wifi::WifiEvent::StaDisconnected);
+            if let select::Either::Second(()) =
+                select::select(disconnected, reconnect_signal.wait()).await
+            {
+                memlog.info("wifi: reconnecting on request");
+                if let Err(error) = controller.disconnect_async().await {
+                    memlog.debug(format!("wifi: disconnect error: {:?}", error));
+                }
+            }
         }
+        // A request made while disconnected is served by the reconnect below.
+        reconnect_signal.reset();
 
         // Pause before attempting to reconnect.
         Timer::after(WIFI_RECONNECT_PAUSE).await;
@@ -67,7 +96,10 @@ pub async fn wifi_permanent_connection(
         if !matches!(controller.is_started(), Ok(true)) {
             // TODO: do we need to set_configuration and set_power_saving here in the loop?
             memlog.debug("wifi: starting controller");
-            controller.start_async().await.unwrap();
+            if let Err(error) = controller.start_async().await {
+                memlog.warn(format!("wifi: controller start error: {:?}", error));
+                continue;
+            }
         }
 
         match controller.connect_async().await {